@@ -1,8 +1,6 @@
 use candid::{CandidType, Deserialize, Nat, Principal};
 use std::cell::RefCell;
-use ic_cdk::api::time;
-use ic_cdk_timers::set_timer;
-use std::time::Duration;
+use ic_cdk::api::{canister_balance128, time};
 use ic_cdk::{
     caller,
     id,
@@ -15,8 +13,95 @@ use ic_cdk::{
 };
 
 use shared::{
+    evm::{normalize_eth_address, parse_eth_address},
+    http::{HttpRequest, HttpResponse},
     icrc1::ICRC1CanisterClient,
+    icrc3::{
+        encode_hash_tree,
+        ArchiveInfo,
+        BlockWithId,
+        DataCertificate,
+        GetArchivesArgs,
+        GetBlocksArgs,
+        GetBlocksResult,
+        Icrc3Value,
+        SupportedBlockType,
+        MAX_BLOCKS_PER_REQUEST,
+    },
+    satslinker::types::{
+        BeaconRound,
+        InvariantReport,
+        LogLevel,
+        NetworkProfile,
+        RedistributionConfig,
+        ReservesReport,
+        SwapDirection,
+        TimerJob,
+        TimerJobState,
+        POS_ROUND_CATCH_UP_BATCH,
+        RESERVES_REPORT_INTERVAL_NS,
+        BLOCK_TYPES,
+        BLOCK_TYPE_PAY,
+    },
+    satslinker::api::{
+        AcceptAccountTransferRequest,
+        AddLiquidityRequest,
+        AddLiquidityResponse,
+        AnnounceDevFeeWithdrawalRequest,
+        AnnounceDevFeeWithdrawalResponse,
+        AttestVipStatusRequest,
+        AttestVipStatusResponse,
+        BuyLotteryTicketsRequest,
+        BuyLotteryTicketsResponse,
+        ClaimLpPayoutResponse,
+        ConfirmDevFeeWithdrawalResponse,
+        GetAccountTransfersResponse,
+        GetAttestationSignerResponse,
+        GetCertifiedVipStatusRequest,
+        GetCertifiedVipStatusResponse,
+        GetDevFeeAuditLogResponse,
+        GetDevFeeWithdrawalsResponse,
+        GetHealthResponse,
+        GetLogsRequest,
+        GetLogsResponse,
+        GetPosRoundScheduleResponse,
+        GetLotteryBetsResponse,
+        GetLotteryDrawsResponse,
+        GetPledgeLotsResponse,
+        GetRedistributionsResponse,
+        GetSwapPoolResponse,
+        GetTopUpsResponse,
+        GetTotalsResponse,
+        LotteryRequest,
+        LotteryResponse,
+        MigrateAccountRequest,
+        MigrateAccountResponse,
+        PledgeRequest,
+        PledgeResponse,
+        PreviewEarlyUnpledgeRequest,
+        PreviewEarlyUnpledgeResponse,
+        QuoteSwapRequest,
+        QuoteSwapResponse,
+        RedeemRequest,
+        RedeemResponse,
+        RemoveLiquidityRequest,
+        RemoveLiquidityResponse,
+        SatslinkerInitArgs,
+        SetCyclesMonitorConfigRequest,
+        SetEarlyUnpledgeConfigRequest,
+        SetPledgeAutoCompoundRequest,
+        SwapRequest,
+        SwapResponse,
+        TopUpCanisterRequest,
+        TopUpCanisterResponse,
+        UnpledgeEarlyRequest,
+        UnpledgeEarlyResponse,
+        VerifyLotteryDrawResponse,
+        WithdrawRequest,
+        WithdrawResponse,
+    },
     ICP_FEE,
+    ONE_HOUR_NS,
 };
 
 use icrc_ledger_types::{
@@ -26,11 +111,56 @@ use icrc_ledger_types::{
     icrc2::allowance::AllowanceArgs,
 };
 
-use std::collections::HashSet;
+use ic_e8s::c::E8s;
+
+use std::collections::{BTreeMap, HashSet};
 const ICP_CANISTER_ID: &str = "ryjl3-tyaaa-aaaaa-aaaba-cai";
 
+mod metrics;
+mod supervisor;
+mod utils;
+
+use utils::{
+    add_callers_liquidity,
+    assert_caller_is_auditor,
+    assert_caller_is_dev,
+    assert_caller_is_dev_fee_owner,
+    assert_running,
+    announce_callers_dev_fee_withdrawal,
+    attest_vip_status,
+    buy_callers_lottery_tickets,
+    check_and_log_invariants,
+    claim_callers_lp_payout,
+    compile_reserves_report,
+    execute_dev_fee_withdrawal,
+    get_attestation_signer,
+    is_stopped,
+    log,
+    place_callers_lottery_bet,
+    pledge_callers_satslink,
+    remove_callers_liquidity,
+    resume_pending_top_ups,
+    resume_unsettled_lottery_bets,
+    set_cycles_icp_exchange_rate_timer,
+    set_cycles_monitor_timer,
+    set_icp_redistribution_timer,
+    set_init_seed_one_timer,
+    set_invariant_check_timer,
+    set_lottery_and_pos_and_pledge_timer,
+    set_lottery_draw_timer,
+    set_randomness_beacon_timer,
+    set_top_up_sweep_timer,
+    swap_callers_tokens,
+    top_up_canister_with_callers_icp,
+    unpledge_callers_lot_early,
+    withdraw_callers_pledge,
+    MEMORY_MANAGER,
+    STATE,
+    STOPPED_FOR_UPDATE,
+};
+
 // 引入持久化存储
-use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::memory_manager::{MemoryId, VirtualMemory};
 use ic_stable_structures::{DefaultMemoryImpl, StableVec, Storable};
 
 // 定义 Memory 类型
@@ -49,14 +179,11 @@ impl Storable for PaymentRecord {
 }
 
 thread_local! {
-    static ICP_PRICE: RefCell<Option<f64>> = RefCell::new(None); // 存储 ICP 价格
-    static ADMIN: RefCell<Principal> = RefCell::new(Principal::anonymous());
+    static ICP_PRICE: RefCell<Option<f64>> = const { RefCell::new(None) }; // 存储 ICP 价格
+    static ADMIN: RefCell<Principal> = const { RefCell::new(Principal::anonymous()) };
     // 白名单 (存储允许的 canister ID)
     static WHITELISTED_TOKENS: RefCell<HashSet<Principal>> = RefCell::new(HashSet::new());
-    // 初始化 MemoryManager
-    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
-        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
-    // 使用 StableVec 存储支付记录
+    // 使用 StableVec 存储支付记录 (MemoryManager 与 SatslinkerState 共用, 见 utils.rs)
     static PAYMENTS: RefCell<StableVec<PaymentRecord, Memory>> =
         RefCell::new(StableVec::init(
             MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))),
@@ -84,14 +211,6 @@ pub struct PaymentStats {
     pub user_total_amount: Nat, // 新增：用户金额
 }
 
-thread_local! {
-    pub static STOPPED_FOR_UPDATE: RefCell<(Principal, bool)> = RefCell::new((Principal::anonymous(), false));
-}
-
-pub fn is_stopped() -> bool {
-    STOPPED_FOR_UPDATE.with_borrow(|(_, is_stopped)| *is_stopped)
-}
-
 // 获取 ICP 价格（从存储中读取）
 #[query]
 pub fn get_icp_price() -> Result<f64, String> {
@@ -213,7 +332,7 @@ pub async fn pay(
         Ok((v,)) => { // 解构单元组，获取内部的 Result
             match v { // 再次 match 内部的 Result
                 Ok(nat_val) => { // nat_val 的类型是 Nat
-                    if nat_val > Nat::from(0u64) {
+                    if nat_val > 0u64 {
                         let icp_price = get_icp_price()?;
                         let icp_canister_id = Principal::from_text(ICP_CANISTER_ID).unwrap();
                         let amount_f64 = amount.0.to_string().parse::<f64>().unwrap() / 100_000_000.0;
@@ -234,13 +353,30 @@ pub async fn pay(
                             canister_id: canister_id.clone(),
                             payment_create: time(),
                         };
-                        PAYMENTS.with(|payments| {
+                        let stored = PAYMENTS.with(|payments| {
                             let payments_mut = payments.borrow_mut();
                             let len = payments_mut.len();
-                            if (len as u64) < 1000 {
+                            if len < 1000 {
                                 payments_mut.push(&payment_record).expect("Failed to push payment record");
+                                true
+                            } else {
+                                false
                             }
                         });
+                        if !stored {
+                            log(LogLevel::Error, "payments", format!("Payment of {} for {} not stored, the payments store is full", amount, payment_record.eth_address));
+                        }
+                        recertify_vip_status();
+                        metrics::note_payment(token_id, &amount);
+                        STATE.with_borrow_mut(|s| {
+                            s.append_block(BLOCK_TYPE_PAY, vec![
+                                (String::from("from"), Icrc3Value::account(&caller())),
+                                (String::from("amt"), Icrc3Value::nat(amount.clone())),
+                                (String::from("token"), Icrc3Value::Blob(token_id.as_slice().to_vec())),
+                                (String::from("eth_address"), Icrc3Value::Text(payment_record.eth_address.clone())),
+                                (String::from("expiry"), Icrc3Value::nat(expiry_time)),
+                            ], time())
+                        });
                         println!(
                             "Payment record updated/created successfully. Transfer result: value = {:?}, amount = {:?}, canister = {:?}, caller = {:?}",
                             amount,
@@ -278,7 +414,6 @@ pub fn get_payment_stats() -> Result<PaymentStats, String> {
         let mut total_usd_value_user = 0.0;
         let mut all_payments = Vec::new();
         let mut user_payments = Vec::new();
-        let mut user_vip_expiry = 0u64; // 用户VIP截止时间
         let mut user_total_amount = Nat::from(0u64); // 用户金额累加
 
         let mut earliest_start_time = u64::MAX; // 最早的起始时间，初始值为 u64 的最大值
@@ -308,12 +443,12 @@ pub fn get_payment_stats() -> Result<PaymentStats, String> {
         }
 
         // 计算总时长截止时间
-        if earliest_start_time == u64::MAX {
+        let user_vip_expiry = if earliest_start_time == u64::MAX {
             // 如果没有支付记录，则设置为 0
-            user_vip_expiry = 0;
+            0
         } else {
-            user_vip_expiry = earliest_start_time + total_duration;
-        }
+            earliest_start_time + total_duration
+        }; // 用户VIP截止时间
 
         // 返回所有账单数据和用户的VIP截止时间、金额累加
         Ok(PaymentStats {
@@ -355,6 +490,169 @@ pub fn get_payments_by_eth_address(eth_address: String) -> u64 {
     })
 }
 
+/// 查询经认证的 VIP 截止时间（按 ETH 地址或 principal），附带证书与 witness，可用 IC 根公钥离线验证
+#[query]
+fn get_certified_vip_status(req: GetCertifiedVipStatusRequest) -> Option<GetCertifiedVipStatusResponse> {
+    let certificate = ic_cdk::api::data_certificate()?;
+    let eth_address = req.eth_address.as_deref().map(normalize_eth_address);
+    let eth_address = eth_address.as_deref();
+    let principal = req.principal.as_ref();
+
+    STATE.with_borrow(|s| {
+        Some(GetCertifiedVipStatusResponse {
+            expiry: s.get_certified_vip_expiry(eth_address, principal),
+            certificate,
+            witness: encode_hash_tree(&s.vip_status_witness(eth_address, principal)),
+        })
+    })
+}
+
+/// 罐的以太坊签名地址（threshold ECDSA secp256k1 公钥派生），合约用它校验 VIP 证明
+#[update]
+async fn get_vip_attestation_signer() -> GetAttestationSignerResponse {
+    GetAttestationSignerResponse {
+        result: get_attestation_signer().await,
+    }
+}
+
+/// 用 threshold ECDSA 签发 EIP-712 VIP 证明，合约可通过 ecrecover 验证；仅限该地址 VIP 支付记录中的 principal，同一截止时间重复请求返回已签发的证明
+#[update]
+async fn attest_vip(req: AttestVipStatusRequest) -> AttestVipStatusResponse {
+    assert_running();
+
+    let address = match parse_eth_address(&req.eth_address) {
+        Ok(address) => address,
+        Err(e) => return AttestVipStatusResponse { result: Err(e) },
+    };
+
+    let caller = caller();
+    let is_owner = PAYMENTS.with(|payments| {
+        payments
+            .borrow()
+            .iter()
+            .any(|p| p.principal == caller && parse_eth_address(&p.eth_address) == Ok(address))
+    });
+    if !is_owner {
+        return AttestVipStatusResponse {
+            result: Err(String::from("Only the principal the VIP was paid for can request attestations")),
+        };
+    }
+
+    AttestVipStatusResponse {
+        result: attest_vip_status(address, req.chain_id).await,
+    }
+}
+
+/// HTTP 网关：以 JSON 提供 VIP 状态与统计数据，/metrics 以 Prometheus 文本格式提供指标，无需 agent 即可访问
+#[query]
+fn http_request(req: HttpRequest) -> HttpResponse {
+    if req.method != "GET" {
+        return HttpResponse::error(405, "Method not allowed");
+    }
+
+    let path = req.path();
+    let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+
+    match segments.as_slice() {
+        ["vip", "principal", principal] => match Principal::from_text(principal) {
+            Ok(principal) => http_vip_status(|p| p.principal == principal, "principal", &principal.to_text()),
+            Err(_) => HttpResponse::error(400, "Invalid principal"),
+        },
+        ["vip", eth_address] => {
+            let eth_address = normalize_eth_address(eth_address);
+            http_vip_status(|p| normalize_eth_address(&p.eth_address) == eth_address, "eth_address", &eth_address)
+        }
+        ["stats"] => http_stats(),
+        ["metrics"] => http_metrics(),
+        _ => HttpResponse::error(404, "Not found"),
+    }
+}
+
+// 按条件合并支付记录得到 VIP 截止时间，与 get_payments_by_eth_address 的合并规则相同
+fn http_vip_status(filter: impl Fn(&PaymentRecord) -> bool, key: &str, value: &str) -> HttpResponse {
+    let payments: Vec<PaymentRecord> = PAYMENTS.with(|payments| payments.borrow().iter().filter(|p| filter(p)).collect());
+    let vip_expires_at = merged_vip_expiries(&payments, |_| ())
+        .first()
+        .map(|(_, expiry)| *expiry)
+        .unwrap_or_default();
+
+    HttpResponse::json(200, &serde_json::json!({
+        key: value,
+        "vip_expires_at": vip_expires_at,
+        "is_vip": vip_expires_at > time(),
+        "payments": payments.len(),
+    }))
+}
+
+// 全局统计，E8s 数值以字符串输出以免 JSON 精度丢失
+fn http_stats() -> HttpResponse {
+    let totals = STATE.with_borrow(|s| s.get_totals(&Principal::anonymous()));
+    let (payments, payment_users) = PAYMENTS.with(|payments| {
+        let payments = payments.borrow();
+        let users: HashSet<Principal> = payments.iter().map(|p| p.principal).collect();
+
+        (payments.len(), users.len())
+    });
+
+    HttpResponse::json(200, &serde_json::json!({
+        "total_pledge_token_supply": totals.total_pledge_token_supply.val.to_string(),
+        "total_token_lottery": totals.total_token_lottery.val.to_string(),
+        "total_token_dev": totals.total_token_dev.val.to_string(),
+        "total_token_minted": totals.total_token_minted.val.to_string(),
+        "current_token_reward": totals.current_token_reward.val.to_string(),
+        "is_satslink_enabled": totals.is_satslink_enabled,
+        "current_pos_round": totals.current_pos_round,
+        "pos_round_delay_ns": totals.pos_round_delay_ns,
+        "total_pledge_participants": totals.total_pledge_participants,
+        "total_vip_participants": totals.total_vip_participants,
+        "icp_to_cycles_exchange_rate": totals.icp_to_cycles_exchange_rate.val.to_string(),
+        "payments": payments,
+        "payment_users": payment_users,
+    }))
+}
+
+// Prometheus 文本格式，供抓取
+fn http_metrics() -> HttpResponse {
+    let now = time();
+    let mut active_payments: BTreeMap<Principal, u64> = BTreeMap::new();
+    PAYMENTS.with(|payments| {
+        for payment in payments.borrow().iter().filter(|p| p.expiry_time > now) {
+            if let Ok(token) = Principal::from_text(&payment.canister_id) {
+                *active_payments.entry(token).or_default() += 1;
+            }
+        }
+    });
+
+    let body = metrics::render(active_payments.into_iter().collect());
+
+    HttpResponse::new(200, "text/plain; version=0.0.4", body.into_bytes())
+}
+
+// 与 get_payments_by_eth_address 相同的合并规则：从最早的支付开始累加各笔时长
+fn merged_vip_expiries<K: Ord>(payments: &[PaymentRecord], key: impl Fn(&PaymentRecord) -> K) -> Vec<(K, u64)> {
+    let mut merged: BTreeMap<K, (u64, u64)> = BTreeMap::new();
+
+    for payment in payments {
+        let (earliest_start_time, total_duration) = merged.entry(key(payment)).or_insert((u64::MAX, 0));
+        *earliest_start_time = (*earliest_start_time).min(payment.payment_create);
+        *total_duration += payment.expiry_time - payment.payment_create;
+    }
+
+    merged
+        .into_iter()
+        .map(|(k, (earliest_start_time, total_duration))| (k, earliest_start_time + total_duration))
+        .collect()
+}
+
+// 支付记录变化后重建认证树
+fn recertify_vip_status() {
+    let payments: Vec<PaymentRecord> = PAYMENTS.with(|payments| payments.borrow().iter().collect());
+    let by_eth = merged_vip_expiries(&payments, |p| normalize_eth_address(&p.eth_address));
+    let by_principal = merged_vip_expiries(&payments, |p| p.principal);
+
+    STATE.with_borrow_mut(|s| s.reset_certified_vip_expiry(by_eth, by_principal));
+}
+
 /// 根据 ETH 地址查询支付记录，接口为 query 方法
 #[query]
 pub fn get_payments_by_principal(principal: String) -> Vec<PaymentRecord> {
@@ -363,7 +661,6 @@ pub fn get_payments_by_principal(principal: String) -> Vec<PaymentRecord> {
         payments_vec
             .iter()
             .filter(|p| p.principal.to_text() == principal)
-            .map(|p| p.clone())
             .collect()
     })
 }
@@ -375,40 +672,792 @@ pub fn count_payment_users() -> usize {
         let payments_vec = payments.borrow();
         let unique_users: HashSet<Principal> = payments_vec
             .iter()
-            .map(|payment| payment.principal.clone())
+            .map(|payment| payment.principal)
             .collect();
         unique_users.len()
     })
 }
 
+/// 质押 SATSLINK：代币通过 icrc2_transfer_from 转入质押子账户后才记录份额
+#[update]
+async fn pledge(req: PledgeRequest) -> PledgeResponse {
+    assert_running();
+
+    PledgeResponse {
+        result: pledge_callers_satslink(
+            req.qty_e8s_u64,
+            req.lock_period.unwrap_or_default(),
+            req.auto_compound.unwrap_or_default(),
+        )
+        .await,
+    }
+}
+
+/// 开启或关闭质押批次的自动复投 (每轮奖励直接计入质押本金)
+#[update]
+fn set_pledge_auto_compound(req: SetPledgeAutoCompoundRequest) -> Result<(), String> {
+    assert_running();
+
+    STATE.with_borrow_mut(|s| s.set_pledge_auto_compound(caller(), req.lot_id, req.enabled))
+}
+
+/// 查询调用者的所有质押批次 (每次质押单独锁定，按锁定期计算奖励权重)
+#[query]
+fn get_pledge_lots() -> GetPledgeLotsResponse {
+    GetPledgeLotsResponse {
+        lots: STATE.with_borrow(|s| s.get_pledge_lots(&caller())),
+    }
+}
+
+/// 取回已解锁批次中质押的 SATSLINK (从最早的批次开始)
+#[update]
+async fn withdraw(req: WithdrawRequest) -> WithdrawResponse {
+    assert_running();
+
+    WithdrawResponse {
+        result: withdraw_callers_pledge(req.qty_e8s, req.to).await,
+    }
+}
+
+/// 预览提前解除质押的罚金 (罚金随锁定期线性递减)
+#[query]
+fn preview_early_unpledge(req: PreviewEarlyUnpledgeRequest) -> PreviewEarlyUnpledgeResponse {
+    PreviewEarlyUnpledgeResponse {
+        result: STATE.with_borrow(|s| s.quote_early_unpledge(&caller(), req.lot_id, time())),
+    }
+}
+
+/// 提前解除锁定中的质押批次，罚金分给其他质押者和金库
+#[update]
+async fn unpledge_early(req: UnpledgeEarlyRequest) -> UnpledgeEarlyResponse {
+    assert_running();
+
+    UnpledgeEarlyResponse {
+        result: unpledge_callers_lot_early(req.lot_id, req.to).await,
+    }
+}
+
+/// 设置提前解除质押的最高罚金和金库分成 (仅开发者)
+#[update]
+fn set_early_unpledge_config(req: SetEarlyUnpledgeConfigRequest) -> Result<(), String> {
+    assert_caller_is_dev();
+
+    STATE.with_borrow_mut(|s| {
+        let mut info = s.get_info();
+        info.set_early_unpledge_config(req.max_penalty_bp, req.treasury_share_bp)?;
+        s.set_info(info);
+
+        Ok(())
+    })
+}
+
+/// SATSLINK/ICP 兑换池状态 (储备、LP 总量、手续费和调用者的 LP 份额)
+#[query]
+fn get_swap_pool() -> GetSwapPoolResponse {
+    STATE.with_borrow(|s| {
+        let pool = s.get_swap_pool();
+
+        GetSwapPoolResponse {
+            swap_fee_bp: pool.get_swap_fee_bp(),
+            reserve_icp: pool.reserve_icp,
+            reserve_satslink: pool.reserve_satslink,
+            lp_total_supply: pool.lp_total_supply,
+            your_lp_shares: s.get_lp_shares(&caller()),
+            your_lp_payout: s.get_lp_payout(&caller()),
+        }
+    })
+}
+
+/// 兑换报价 (恒定乘积，手续费留在池中)
+#[query]
+fn quote_swap(req: QuoteSwapRequest) -> QuoteSwapResponse {
+    QuoteSwapResponse {
+        result: STATE.with_borrow(|s| s.get_swap_pool().quote(req.direction, &E8s::from(req.amount_in_e8s))),
+    }
+}
+
+/// 兑换，输出低于 min_amount_out_e8s 时失败并退回输入
+#[update]
+async fn swap(req: SwapRequest) -> SwapResponse {
+    assert_running();
+
+    SwapResponse {
+        result: swap_callers_tokens(
+            req.direction,
+            req.amount_in_e8s,
+            E8s::from(req.min_amount_out_e8s),
+            caller(),
+        )
+        .await,
+    }
+}
+
+/// 通过兑换池将 SATSLINK 赎回为 ICP
+#[update]
+async fn redeem(req: RedeemRequest) -> RedeemResponse {
+    assert_running();
+
+    RedeemResponse {
+        result: swap_callers_tokens(
+            SwapDirection::SatslinkToIcp,
+            req.qty_e8s_u64,
+            E8s::from(req.min_icp_out_e8s_u64),
+            req.to,
+        )
+        .await,
+    }
+}
+
+/// 提供流动性，获得 LP 份额
+#[update]
+async fn add_liquidity(req: AddLiquidityRequest) -> AddLiquidityResponse {
+    assert_running();
+
+    AddLiquidityResponse {
+        result: add_callers_liquidity(req.icp_e8s, req.satslink_e8s).await,
+    }
+}
+
+/// 销毁 LP 份额，取回 ICP 和 SATSLINK
+#[update]
+async fn remove_liquidity(req: RemoveLiquidityRequest) -> RemoveLiquidityResponse {
+    assert_running();
+
+    RemoveLiquidityResponse {
+        result: remove_callers_liquidity(
+            req.shares,
+            E8s::from(req.min_icp_e8s),
+            E8s::from(req.min_satslink_e8s),
+        )
+        .await,
+    }
+}
+
+/// 领取撤出流动性时未能发出的 SATSLINK
+#[update]
+async fn claim_lp_payout() -> ClaimLpPayoutResponse {
+    assert_running();
+
+    ClaimLpPayoutResponse {
+        result: claim_callers_lp_payout().await,
+    }
+}
+
+/// 设置兑换手续费 (仅开发者)
+#[update]
+fn set_swap_fee(swap_fee_bp: u64) -> Result<(), String> {
+    assert_caller_is_dev();
+
+    STATE.with_borrow_mut(|s| {
+        let mut pool = s.get_swap_pool();
+        pool.set_swap_fee_bp(swap_fee_bp)?;
+        s.set_swap_pool(pool);
+
+        Ok(())
+    })
+}
+
+/// 用 ICP 为指定罐充值 cycles，完成后按汇率奖励 SATSLINK
+#[update]
+async fn top_up_canister(req: TopUpCanisterRequest) -> TopUpCanisterResponse {
+    assert_running();
+
+    TopUpCanisterResponse {
+        result: top_up_canister_with_callers_icp(req.canister_id, req.qty_e8s_u64).await,
+    }
+}
+
+/// 查询调用者的充值记录
+#[query]
+fn get_top_ups() -> GetTopUpsResponse {
+    let entries = STATE.with_borrow(|s| s.get_top_ups_of(&caller()));
+
+    GetTopUpsResponse { entries }
+}
+
+/// 查询全局统计与调用者的状态，包含罐的 cycles 余额与低余额告警
+#[query]
+fn get_totals() -> GetTotalsResponse {
+    STATE.with_borrow(|s| s.get_totals(&caller()))
+}
+
+/// 查询罐为自身补充 cycles 的记录 (仅开发者)
+#[query]
+fn get_cycles_top_ups() -> GetTopUpsResponse {
+    assert_caller_is_dev();
+
+    let entries = STATE.with_borrow(|s| s.get_top_ups_of(&id()));
+
+    GetTopUpsResponse { entries }
+}
+
+/// 设置 cycles 补充阈值与每次消耗的 ICP 数量 (仅开发者)
+#[update]
+fn set_cycles_monitor_config(req: SetCyclesMonitorConfigRequest) -> Result<(), String> {
+    assert_caller_is_dev();
+
+    STATE.with_borrow_mut(|s| {
+        let mut info = s.get_info();
+        info.set_cycles_monitor_config(req.threshold, req.top_up_e8s)?;
+        s.set_info(info);
+
+        Ok(())
+    })
+}
+
+/// 押注 SATSLINK 参与抽奖，结果由后续的随机数决定
+#[update]
+async fn lottery(req: LotteryRequest) -> LotteryResponse {
+    assert_running();
+
+    LotteryResponse {
+        result: place_callers_lottery_bet(req.qty_e8s_u64).await,
+    }
+}
+
+/// 查询调用者的抽奖记录
+#[query]
+fn get_lottery_bets() -> GetLotteryBetsResponse {
+    let entries = STATE.with_borrow(|s| s.get_lottery_bets_of(&caller()));
+
+    GetLotteryBetsResponse { entries }
+}
+
+/// 购买当前开奖期的彩票
+#[update]
+async fn buy_lottery_tickets(req: BuyLotteryTicketsRequest) -> BuyLotteryTicketsResponse {
+    assert_running();
+
+    BuyLotteryTicketsResponse {
+        result: buy_callers_lottery_tickets(req.count).await,
+    }
+}
+
+/// 查询当前开奖期、奖池与往期中奖记录
+#[query]
+fn get_lottery_draws() -> GetLotteryDrawsResponse {
+    let caller = caller();
+
+    STATE.with_borrow(|s| {
+        let info = s.get_info();
+        let current = s.get_current_lottery_draw();
+        let your_tickets = current
+            .as_ref()
+            .map(|(draw_id, _)| s.count_lottery_tickets_of(*draw_id, &caller))
+            .unwrap_or_default();
+
+        GetLotteryDrawsResponse {
+            current,
+            pot: info.get_lottery_draw_pot(),
+            ticket_price: info.get_lottery_ticket_price(),
+            your_tickets,
+            past: s.get_past_lottery_draws(),
+        }
+    })
+}
+
+/// 查询随机数信标轮次，揭示前不公开秘密值
+#[query]
+fn get_beacon_round(round_id: u64) -> Option<BeaconRound> {
+    STATE.with_borrow(|s| s.get_public_beacon_round(round_id))
+}
+
+/// 根据公开的承诺与揭示值重新验证某期开奖结果
+#[query]
+fn verify_lottery_draw(draw_id: u64) -> Option<VerifyLotteryDrawResponse> {
+    STATE.with_borrow(|s| s.verify_lottery_draw(draw_id))
+}
+
+/// 设置彩票价格 (仅开发者)
+#[update]
+fn set_lottery_ticket_price(price_e8s: u64) -> Result<(), String> {
+    assert_caller_is_dev();
+
+    STATE.with_borrow_mut(|s| {
+        let mut info = s.get_info();
+        info.set_lottery_ticket_price(price_e8s)?;
+        s.set_info(info);
+
+        Ok(())
+    })
+}
+
+/// 设置抽奖的庄家优势 (仅开发者)
+#[update]
+fn set_lottery_house_edge(house_edge_bp: u64) -> Result<(), String> {
+    assert_caller_is_dev();
+
+    STATE.with_borrow_mut(|s| {
+        let mut info = s.get_info();
+        info.set_lottery_house_edge_bp(house_edge_bp)?;
+        s.set_info(info);
+
+        Ok(())
+    })
+}
+
+/// 申请从开发者费用子账户提取 ICP 或 SATSLINK，需等待时间锁后由所有者确认 (仅开发者)
+#[update]
+async fn announce_dev_fee_withdrawal(req: AnnounceDevFeeWithdrawalRequest) -> AnnounceDevFeeWithdrawalResponse {
+    assert_caller_is_dev();
+
+    AnnounceDevFeeWithdrawalResponse {
+        result: announce_callers_dev_fee_withdrawal(req.token, E8s::from(req.qty_e8s_u64), req.to).await,
+    }
+}
+
+/// 时间锁到期后确认并执行提取 (仅所有者)
+#[update]
+async fn confirm_dev_fee_withdrawal(withdrawal_id: u64) -> ConfirmDevFeeWithdrawalResponse {
+    assert_caller_is_dev_fee_owner();
+
+    ConfirmDevFeeWithdrawalResponse {
+        result: execute_dev_fee_withdrawal(withdrawal_id).await,
+    }
+}
+
+/// 取消尚未执行的提取申请 (开发者或所有者)
+#[update]
+fn cancel_dev_fee_withdrawal(withdrawal_id: u64) -> Result<(), String> {
+    let caller = caller();
+    let dev = STOPPED_FOR_UPDATE.with_borrow(|(dev, _)| *dev);
+    let owner = STATE.with_borrow(|s| s.get_info().get_dev_fee_owner());
+    if caller != dev && owner != Some(caller) {
+        return Err(String::from("Access denied"));
+    }
+
+    STATE.with_borrow_mut(|s| s.cancel_dev_fee_withdrawal(withdrawal_id, caller, time()))
+}
+
+/// 设置提取时间锁 (仅所有者)，不得低于最小值，且需等待当前时间锁到期后生效，返回生效时间
+#[update]
+fn set_dev_fee_timelock(timelock_ns: u64) -> Result<u64, String> {
+    assert_caller_is_dev_fee_owner();
+
+    STATE.with_borrow_mut(|s| s.set_dev_fee_timelock(timelock_ns, caller(), time()))
+}
+
+/// 提议移交开发者费用所有权 (仅所有者)，新所有者不能是开发者本人，需等待当前时间锁到期后由新所有者接受，返回可接受的时间
+#[update]
+fn propose_dev_fee_owner(owner: Principal) -> Result<u64, String> {
+    let dev = STOPPED_FOR_UPDATE.with_borrow(|(dev, _)| *dev);
+
+    STATE.with_borrow_mut(|s| s.propose_dev_fee_owner(owner, dev, caller(), time()))
+}
+
+/// 新所有者在时间锁到期后接受所有权
+#[update]
+fn accept_dev_fee_owner() -> Result<(), String> {
+    STATE.with_borrow_mut(|s| s.accept_dev_fee_owner(caller(), time()))
+}
+
+/// 查询提取申请
+#[query]
+fn get_dev_fee_withdrawals() -> GetDevFeeWithdrawalsResponse {
+    STATE.with_borrow(|s| {
+        let info = s.get_info();
+
+        GetDevFeeWithdrawalsResponse {
+            owner: info.get_dev_fee_owner(),
+            pending_owner: info.pending_dev_fee_owner,
+            timelock_ns: info.get_dev_fee_timelock_ns(time()),
+            pending_timelock: info.pending_dev_fee_timelock,
+            withdrawals: s.get_dev_fee_withdrawals(),
+        }
+    })
+}
+
+/// 查询开发者费用审计日志
+#[query]
+fn get_dev_fee_audit_log() -> GetDevFeeAuditLogResponse {
+    let entries = STATE.with_borrow(|s| s.get_dev_fee_audit_log());
+
+    GetDevFeeAuditLogResponse { entries }
+}
+
+/// 查询 ICP 分配比例、结转金额与最近的分配记录
+#[query]
+fn get_redistributions() -> GetRedistributionsResponse {
+    STATE.with_borrow(|s| {
+        let info = s.get_info();
+
+        GetRedistributionsResponse {
+            config: info.get_redistribution_config(),
+            carry: info.get_redistribution_carry(),
+            records: s.get_redistributions(),
+        }
+    })
+}
+
+/// 设置 ICP 分配比例 (仅开发者)
+#[update]
+fn set_redistribution_config(config: RedistributionConfig) -> Result<(), String> {
+    assert_caller_is_dev();
+
+    STATE.with_borrow_mut(|s| {
+        let mut info = s.get_info();
+        info.set_redistribution_config(config)?;
+        s.set_info(info);
+
+        Ok(())
+    })
+}
+
+/// 当前网络的时间参数（轮次间隔、VIP 时间单位、价格更新与分配间隔、质押周期等）
+#[query]
+fn get_network_profile() -> NetworkProfile {
+    STATE.with_borrow(|s| s.get_info().get_network_profile())
+}
+
+/// 设置网络时间参数 (仅开发者)，已有 VIP 持仓时不能修改 VIP 时间单位
+#[update]
+fn set_network_profile(profile: NetworkProfile) -> Result<(), String> {
+    assert_caller_is_dev();
+
+    STATE.with_borrow_mut(|s| s.set_network_profile(profile, time()))
+}
+
+/// 刷新储备证明（仅审计员或开发者）：比对各子账户的账本余额与内部负债，并保存报告
+#[update]
+async fn refresh_reserves_report() -> ReservesReport {
+    assert_caller_is_auditor();
+
+    compile_reserves_report(icp_paid_in()).await
+}
+
+/// 查询最近一次储备证明报告，由定时任务每小时刷新
+#[query]
+fn get_reserves_report() -> ReservesReport {
+    STATE.with_borrow(|s| s.get_reserves_report())
+}
+
+// 支付记录中以 ICP 支付的总额
+fn icp_paid_in() -> E8s {
+    let icp_canister_id = Principal::from_text(ICP_CANISTER_ID).unwrap();
+    let mut icp_paid_in = E8s::zero();
+
+    PAYMENTS.with(|payments| {
+        for payment in payments.borrow().iter() {
+            if Principal::from_text(&payment.canister_id).ok() == Some(icp_canister_id) {
+                icp_paid_in += E8s::new(payment.amount.0.clone());
+            }
+        }
+    });
+
+    icp_paid_in
+}
+
+// 储备证明报告定时任务，安装和升级后立即执行一次
+pub fn set_reserves_report_timer() {
+    supervisor::arm(TimerJob::ReservesReport, 0);
+}
+
+// 定期查询各子账户余额并重新生成储备证明报告
+fn refresh_reserves_report_job() {
+    ic_cdk::spawn(async {
+        let Some(run) = supervisor::start(TimerJob::ReservesReport) else {
+            return;
+        };
+        compile_reserves_report(icp_paid_in()).await;
+        supervisor::finish(run, RESERVES_REPORT_INTERVAL_NS);
+    });
+}
+
+/// 检查账目不变量（仅审计员或开发者），开启自动暂停时发现问题会暂停奖励轮次
+#[update]
+fn check_invariants() -> InvariantReport {
+    assert_caller_is_auditor();
+
+    check_and_log_invariants()
+}
+
+/// 查询最近一次不变量检查报告（仅审计员或开发者）
+#[query]
+fn get_last_invariant_report() -> InvariantReport {
+    assert_caller_is_auditor();
+
+    STATE.with_borrow(|s| s.get_invariant_report())
+}
+
+/// 健康状态：运行/暂停状态、cycles 告警以及各定时任务的最近开始、结束、错误与下次执行时间
+#[query]
+fn get_health() -> GetHealthResponse {
+    let now = time();
+    let info = STATE.with_borrow(|s| s.get_info());
+    let jobs = supervisor::get_jobs_health(now);
+    let stopped = is_stopped();
+
+    let healthy = !stopped
+        && !info.are_reward_rounds_paused()
+        && !info.is_low_cycles_alert()
+        && jobs.iter().all(|job| matches!(job.state, TimerJobState::Scheduled | TimerJobState::Running));
+
+    GetHealthResponse {
+        healthy,
+        now,
+        is_stopped: stopped,
+        reward_rounds_paused: info.are_reward_rounds_paused(),
+        low_cycles_alert: info.is_low_cycles_alert(),
+        cycles_balance: canister_balance128(),
+        jobs,
+    }
+}
+
+/// PoS 轮次进度：按创世时间应到的轮数与已处理轮数，落后的轮次会分批补算
+#[query]
+fn get_pos_round_schedule() -> GetPosRoundScheduleResponse {
+    let now = time();
+    let info = STATE.with_borrow(|s| s.get_info());
+
+    GetPosRoundScheduleResponse {
+        genesis_at: info.pos_genesis_at,
+        round_delay_ns: info.pos_round_delay_ns,
+        expected_rounds: info.get_expected_pos_rounds(now),
+        processed_rounds: info.current_pos_round,
+        missed_rounds: info.get_missed_pos_rounds(now),
+        next_round_at: info.get_next_pos_round_at(),
+        catch_up_batch: POS_ROUND_CATCH_UP_BATCH,
+    }
+}
+
+/// 分页查询持久化日志（仅审计员或开发者），按级别、子系统与时间过滤，最新的在前
+#[query]
+fn get_logs(req: GetLogsRequest) -> GetLogsResponse {
+    assert_caller_is_auditor();
+
+    STATE.with_borrow(|s| s.get_logs(req))
+}
+
+/// 设置审计员 (仅开发者)
+#[update]
+fn set_auditor(auditor: Principal) {
+    assert_caller_is_dev();
+
+    STATE.with_borrow_mut(|s| {
+        let mut info = s.get_info();
+        info.auditor = Some(auditor);
+        s.set_info(info);
+    });
+}
+
+/// 设置发现不变量问题时是否自动暂停奖励轮次 (仅开发者)
+#[update]
+fn set_invariant_auto_pause(enabled: bool) {
+    assert_caller_is_dev();
+
+    STATE.with_borrow_mut(|s| {
+        let mut info = s.get_info();
+        info.invariant_auto_pause = Some(enabled);
+        s.set_info(info);
+    });
+}
+
+/// 恢复被暂停的奖励轮次 (仅开发者)
+#[update]
+fn resume_reward_rounds() {
+    assert_caller_is_dev();
+
+    STATE.with_borrow_mut(|s| {
+        let mut info = s.get_info();
+        info.set_reward_rounds_paused(false);
+        s.set_info(info);
+    });
+}
+
+/// ICRC-3：按区间查询活动日志区块，单次最多返回 MAX_BLOCKS_PER_REQUEST 个
+#[query]
+fn icrc3_get_blocks(args: Vec<GetBlocksArgs>) -> GetBlocksResult {
+    STATE.with_borrow(|s| {
+        let mut blocks = Vec::new();
+
+        for arg in args {
+            let remaining = MAX_BLOCKS_PER_REQUEST.saturating_sub(blocks.len() as u64);
+            let start = u64::try_from(&arg.start.0).unwrap_or(u64::MAX);
+            let length = u64::try_from(&arg.length.0).unwrap_or(u64::MAX).min(remaining);
+
+            for (id, block) in s.get_blocks(start, length) {
+                blocks.push(BlockWithId { id: Nat::from(id), block });
+            }
+        }
+
+        GetBlocksResult {
+            log_length: Nat::from(s.blocks.len()),
+            blocks,
+            archived_blocks: Vec::new(),
+        }
+    })
+}
+
+/// ICRC-3：最新区块的索引与哈希证书
+#[query]
+fn icrc3_get_tip_certificate() -> Option<DataCertificate> {
+    let certificate = ic_cdk::api::data_certificate()?;
+    let hash_tree = STATE.with_borrow(|s| {
+        s.get_block_tip()?;
+        Some(encode_hash_tree(&s.block_tip_witness()))
+    })?;
+
+    Some(DataCertificate {
+        certificate,
+        hash_tree,
+    })
+}
+
+/// ICRC-3：日志不做归档，始终为空
+#[query]
+fn icrc3_get_archives(_args: GetArchivesArgs) -> Vec<ArchiveInfo> {
+    Vec::new()
+}
+
+/// ICRC-3：日志中出现的区块类型
+#[query]
+fn icrc3_supported_block_types() -> Vec<SupportedBlockType> {
+    BLOCK_TYPES
+        .iter()
+        .map(|block_type| SupportedBlockType {
+            block_type: block_type.to_string(),
+            url: String::from("https://github.com/Sama-X/satslink"),
+        })
+        .collect()
+}
+
+/// 发起账户迁移：目标账户需在期限内接受，VIP 时长、质押仓位、未领取奖励、流动性份额与本期彩票才会转移，投注、充值或奖金未结清时不能迁移
+#[update]
+fn migrate_satslinker_account(req: MigrateAccountRequest) -> MigrateAccountResponse {
+    assert_running();
+
+    let result = STATE
+        .with_borrow_mut(|s| s.initiate_account_transfer(caller(), req.to, time()))
+        .map(|_| ());
+
+    MigrateAccountResponse { result }
+}
+
+/// 目标账户接受迁移
+#[update]
+fn accept_account_transfer(req: AcceptAccountTransferRequest) -> Result<(), String> {
+    assert_running();
+
+    STATE.with_borrow_mut(|s| s.accept_account_transfer(caller(), req.from, time()))
+}
+
+/// 发起方取消尚未接受的迁移
+#[update]
+fn cancel_account_transfer() -> Result<(), String> {
+    STATE.with_borrow_mut(|s| s.cancel_account_transfer(&caller()))
+}
+
+/// 查询与调用者相关的待处理迁移
+#[query]
+fn get_account_transfers() -> GetAccountTransfersResponse {
+    let entries = STATE.with_borrow(|s| s.get_account_transfers_of(&caller()));
+
+    GetAccountTransfersResponse { entries }
+}
+
 #[query]
 fn subaccount_of(id: Principal) -> Subaccount {
     Account::from(id).subaccount.unwrap_or([0u8; 32])
 }
 
 #[init]
-fn init_hook() {
+fn init_hook(args: Option<SatslinkerInitArgs>) {
     STOPPED_FOR_UPDATE.with_borrow_mut(|(dev, _)| *dev = caller());
     ADMIN.with_borrow_mut(|admin| *admin = caller());
+    let dev_fee_owner = args.as_ref().and_then(|it| it.dev_fee_owner);
+    apply_network_profile(args.and_then(|it| it.network_profile));
+    apply_dev_fee_owner(dev_fee_owner);
     // 初始化白名单（可选）
     // WHITELISTED_TOKENS.with(|whitelist| {
     //     let mut whitelist = whitelist.borrow_mut();
     //     whitelist.insert(Principal::from_text("your_icp_canister_id").unwrap());
     // });
+    set_init_seed_one_timer();
+    set_cycles_icp_exchange_rate_timer();
+    set_icp_redistribution_timer();
+    set_lottery_and_pos_and_pledge_timer();
     set_clean_expired_payments_timer();
+    set_cycles_monitor_timer();
+    set_randomness_beacon_timer();
+    set_lottery_draw_timer();
+    set_invariant_check_timer();
+    set_reserves_report_timer();
+    set_top_up_sweep_timer();
+    supervisor::set_timer_supervisor();
     println!("Finished set_clean_expired_payments_timer function");
 }
 
+// 仅在安装时写入网络时间参数，未提供时按 CanisterMode 选择；校验失败时记录错误并沿用默认值
+fn apply_network_profile(profile: Option<NetworkProfile>) {
+    let Some(profile) = profile else {
+        return;
+    };
+
+    if let Err(e) = STATE.with_borrow_mut(|s| s.set_network_profile(profile, time())) {
+        log(LogLevel::Error, "timers", format!("Invalid network profile, the default one is used: {}", e));
+    }
+}
+
+// 开发者费用所有者只能通过安装参数首次设置，之后只能由所有者移交
+fn apply_dev_fee_owner(owner: Option<Principal>) {
+    let Some(owner) = owner else {
+        return;
+    };
+
+    let dev = STOPPED_FOR_UPDATE.with_borrow(|(dev, _)| *dev);
+    if owner == dev {
+        log(LogLevel::Error, "dev_fee", String::from("The dev fee owner can't be the dev, it is left unset"));
+        return;
+    }
+
+    STATE.with_borrow_mut(|s| {
+        if s.get_info().get_dev_fee_owner().is_none() {
+            s.set_dev_fee_owner(owner, dev, time());
+        }
+    });
+}
+
 #[post_upgrade]
-fn post_upgrade_hook() {
+fn post_upgrade_hook(args: Option<SatslinkerInitArgs>) {
     STOPPED_FOR_UPDATE.with_borrow_mut(|(dev, _)| *dev = caller());
     ADMIN.with_borrow_mut(|admin| *admin = caller());
+    let dev_fee_owner = args.as_ref().and_then(|it| it.dev_fee_owner);
+    // 升级时不改网络时间参数，否则会重排轮次时间表，修改请调用 set_network_profile
+    if args.is_some_and(|it| it.network_profile.is_some()) {
+        log(LogLevel::Warn, "timers", String::from("The network profile is only applied at install, use set_network_profile to change it"));
+    }
+    apply_dev_fee_owner(dev_fee_owner);
     // 重新初始化白名单（可选）
     // WHITELISTED_TOKENS.with(|whitelist| {
     //     let mut whitelist = whitelist.borrow_mut();
     //     whitelist.insert(Principal::from_text("your_icp_canister_id").unwrap());
     // });
+    // SatslinkerState 在旧版本中尚未初始化时，补充初始化随机种子
+    if STATE.with_borrow(|s| s.get_info().seed.is_empty()) {
+        set_init_seed_one_timer();
+    }
+    STATE.with_borrow_mut(|s| s.migrate_legacy_pledge_shares());
+    STATE.with_borrow_mut(|s| s.rebuild_vip_address_index(caller(), time()));
+    recertify_vip_status();
+    STATE.with_borrow_mut(|s| {
+        let mut info = s.get_info();
+        info.init_minted_breakdown();
+        s.set_info(info);
+    });
+    set_cycles_icp_exchange_rate_timer();
+    set_icp_redistribution_timer();
+    set_lottery_and_pos_and_pledge_timer();
     set_clean_expired_payments_timer();
+    set_cycles_monitor_timer();
+    set_randomness_beacon_timer();
+    set_lottery_draw_timer();
+    set_invariant_check_timer();
+    set_reserves_report_timer();
+    set_top_up_sweep_timer();
+    supervisor::set_timer_supervisor();
+    // 升级会清除重试定时器，未完成的充值和未结算的投注在这里继续
+    resume_pending_top_ups();
+    resume_unsettled_lottery_bets();
     println!("Finished set_clean_expired_payments_timer function");
 }
 
@@ -431,23 +1480,26 @@ fn resume() {
 }
 
 pub fn set_clean_expired_payments_timer() {
-    set_timer(Duration::from_nanos(0), clean_expired_payments); // 初始立即执行一次
+    supervisor::arm(TimerJob::CleanExpiredPayments, 0); // 初始立即执行一次
 }
 
 fn clean_expired_payments() {
+    let Some(run) = supervisor::start(TimerJob::CleanExpiredPayments) else {
+        return;
+    };
     let current_time: u64 = ic_cdk::api::time();
     PAYMENTS.with(|payments| {
         let mut payments_mut = payments.borrow_mut();
         let mut payments_to_remove = Vec::new();
         let payments_vec = payments_mut.iter().collect::<Vec<_>>();
         for i in 0..payments_mut.len() {
-            if payments_vec.get(i as usize).map_or(false, |record| record.expiry_time <= current_time) {
+            if payments_vec.get(i as usize).is_some_and(|record| record.expiry_time <= current_time) {
                 payments_to_remove.push(i);
             }
         }
 
         // 从后往前删除，避免索引错乱
-        let mut payments_vec: Vec<PaymentRecord> = payments_mut.iter().map(|r| r.clone()).collect();
+        let mut payments_vec: Vec<PaymentRecord> = payments_mut.iter().collect();
         for &index in payments_to_remove.iter().rev() {
             payments_vec.remove(index as usize);
         }
@@ -460,7 +1512,8 @@ fn clean_expired_payments() {
         }
         *payments_mut = new_payments; // 更新 payments_mut
     });
-    set_timer(Duration::from_secs(3600), clean_expired_payments);
+    recertify_vip_status();
+    supervisor::finish(run, ONE_HOUR_NS);
 }
 
 // 辅助函数：计算美元价值
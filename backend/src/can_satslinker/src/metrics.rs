@@ -0,0 +1,186 @@
+use std::{cell::RefCell, collections::BTreeMap, fmt::Write};
+
+use candid::{Nat, Principal};
+use ic_cdk::api::{canister_balance128, performance_counter, stable::stable_size, time};
+use num_bigint::BigUint;
+
+use crate::utils::STATE;
+
+// Counters live on the heap and restart from zero after an upgrade, Prometheus treats that as a reset.
+// Gauges are read from the state on every scrape.
+#[derive(Default)]
+struct Metrics {
+    payments: BTreeMap<Principal, (u64, BigUint)>,
+    timer_runs: BTreeMap<&'static str, TimerRun>,
+}
+
+#[derive(Default, Clone, Copy)]
+struct TimerRun {
+    count: u64,
+    last_run_at: u64,
+    last_instructions: u64,
+}
+
+thread_local! {
+    static METRICS: RefCell<Metrics> = RefCell::new(Metrics::default());
+}
+
+pub fn note_payment(token: Principal, amount: &Nat) {
+    METRICS.with_borrow_mut(|m| {
+        let (count, total) = m.payments.entry(token).or_default();
+        *count += 1;
+        *total += &amount.0;
+    });
+}
+
+// Called right before a job re-arms itself. The call context counter covers the awaits of the job,
+// since every timer runs in its own call context.
+pub fn note_timer_run(job: &'static str, started_at: u64) {
+    let instructions = performance_counter(1);
+
+    METRICS.with_borrow_mut(|m| {
+        let run = m.timer_runs.entry(job).or_default();
+        run.count += 1;
+        run.last_run_at = started_at;
+        run.last_instructions = instructions;
+    });
+}
+
+struct Exposition(String);
+
+impl Exposition {
+    fn family(&mut self, name: &str, kind: &str, help: &str, samples: &[(String, String)]) {
+        let _ = writeln!(self.0, "# HELP {} {}", name, help);
+        let _ = writeln!(self.0, "# TYPE {} {}", name, kind);
+
+        for (labels, value) in samples {
+            if labels.is_empty() {
+                let _ = writeln!(self.0, "{} {}", name, value);
+            } else {
+                let _ = writeln!(self.0, "{}{{{}}} {}", name, labels, value);
+            }
+        }
+    }
+
+    fn single(&mut self, name: &str, kind: &str, help: &str, value: String) {
+        self.family(name, kind, help, &[(String::new(), value)]);
+    }
+}
+
+// Prometheus text exposition format 0.0.4
+pub fn render(active_payments: Vec<(Principal, u64)>) -> String {
+    let mut out = Exposition(String::new());
+
+    let (payments, timer_runs) = METRICS.with_borrow(|m| (m.payments.clone(), m.timer_runs.clone()));
+
+    out.family(
+        "satslinker_payments_total",
+        "counter",
+        "Payments received since the last upgrade, by token",
+        &payments.iter().map(|(token, (count, _))| (format!("token=\"{}\"", token), count.to_string())).collect::<Vec<_>>(),
+    );
+    out.family(
+        "satslinker_payments_amount_total",
+        "counter",
+        "Amount paid since the last upgrade in the token's base units, by token",
+        &payments.iter().map(|(token, (_, total))| (format!("token=\"{}\"", token), total.to_string())).collect::<Vec<_>>(),
+    );
+    out.family(
+        "satslinker_active_payments",
+        "gauge",
+        "Stored payments that haven't expired yet, by token",
+        &active_payments.iter().map(|(token, count)| (format!("token=\"{}\"", token), count.to_string())).collect::<Vec<_>>(),
+    );
+
+    STATE.with_borrow(|s| {
+        let info = s.get_info();
+
+        out.single("satslinker_vip_participants", "gauge", "Accounts holding a VIP position", s.vip_shares.len().to_string());
+        out.single("satslinker_pledge_lots", "gauge", "Open pledge lots", s.pledge_lots.len().to_string());
+        out.single("satslinker_current_pos_round", "gauge", "Current PoS round", info.current_pos_round.to_string());
+        out.single("satslinker_pos_missed_rounds", "gauge", "PoS rounds due by time but not processed yet", info.get_missed_pos_rounds(time()).to_string());
+        out.single("satslinker_current_token_reward_e8s", "gauge", "SATSLINK reward of the current round", info.current_token_reward.val.to_string());
+        out.single("satslinker_total_token_minted_e8s", "gauge", "SATSLINK minted so far", info.total_token_minted.val.to_string());
+        out.single("satslinker_reward_rounds_paused", "gauge", "1 if the reward rounds are paused", (info.are_reward_rounds_paused() as u8).to_string());
+
+        let pending_top_ups = s.get_pending_top_up_ids().len();
+        let pending_bets = s.get_unsettled_lottery_bet_ids().len();
+        let pending_prizes = s.get_unpaid_lottery_draws().len();
+
+        out.family(
+            "satslinker_pending_transfers",
+            "gauge",
+            "Outgoing transfers that are not completed yet, by kind",
+            &[
+                (String::from("kind=\"top_up\""), pending_top_ups.to_string()),
+                (String::from("kind=\"lottery_bet\""), pending_bets.to_string()),
+                (String::from("kind=\"lottery_prize\""), pending_prizes.to_string()),
+            ],
+        );
+    });
+
+    out.family(
+        "satslinker_timer_runs_total",
+        "counter",
+        "Completed timer job runs since the last upgrade",
+        &timer_runs.iter().map(|(job, run)| (format!("job=\"{}\"", job), run.count.to_string())).collect::<Vec<_>>(),
+    );
+    out.family(
+        "satslinker_timer_last_run_timestamp_seconds",
+        "gauge",
+        "Start time of the last completed run",
+        &timer_runs.iter().map(|(job, run)| (format!("job=\"{}\"", job), (run.last_run_at / 1_000_000_000).to_string())).collect::<Vec<_>>(),
+    );
+    out.family(
+        "satslinker_timer_last_run_instructions",
+        "gauge",
+        "Instructions used by the last completed run",
+        &timer_runs.iter().map(|(job, run)| (format!("job=\"{}\"", job), run.last_instructions.to_string())).collect::<Vec<_>>(),
+    );
+
+    out.single("satslinker_cycles_balance", "gauge", "Canister cycles balance", canister_balance128().to_string());
+    out.single("satslinker_stable_memory_bytes", "gauge", "Stable memory size", (stable_size() * 65536).to_string());
+    out.single("satslinker_scrape_timestamp_seconds", "gauge", "Canister time of this scrape", (time() / 1_000_000_000).to_string());
+
+    out.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payments_are_counted_and_summed_per_token() {
+        let (icp, ckbtc) = (Principal::from_slice(&[1]), Principal::from_slice(&[2]));
+
+        note_payment(icp, &Nat::from(100u64));
+        note_payment(icp, &Nat::from(50u64));
+        note_payment(ckbtc, &Nat::from(7u64));
+
+        let payments = METRICS.with_borrow(|m| m.payments.clone());
+        assert_eq!(payments.get(&icp), Some(&(2, BigUint::from(150u64))));
+        assert_eq!(payments.get(&ckbtc), Some(&(1, BigUint::from(7u64))));
+    }
+
+    #[test]
+    fn families_are_rendered_in_the_text_exposition_format() {
+        let mut out = Exposition(String::new());
+        out.family(
+            "satslinker_pending_transfers",
+            "gauge",
+            "Outgoing transfers that are not completed yet, by kind",
+            &[(String::from("kind=\"top_up\""), String::from("2"))],
+        );
+        out.single("satslinker_pledge_lots", "gauge", "Open pledge lots", String::from("3"));
+
+        assert_eq!(
+            out.0,
+            "# HELP satslinker_pending_transfers Outgoing transfers that are not completed yet, by kind\n\
+             # TYPE satslinker_pending_transfers gauge\n\
+             satslinker_pending_transfers{kind=\"top_up\"} 2\n\
+             # HELP satslinker_pledge_lots Open pledge lots\n\
+             # TYPE satslinker_pledge_lots gauge\n\
+             satslinker_pledge_lots 3\n"
+        );
+    }
+}
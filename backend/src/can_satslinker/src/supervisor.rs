@@ -0,0 +1,275 @@
+use std::{cell::RefCell, collections::BTreeMap, time::Duration};
+
+use ic_cdk::api::time;
+use ic_cdk_timers::{set_timer, set_timer_interval};
+use shared::satslinker::types::{LogLevel, TimerJob, TimerJobHealth, TimerJobState, TIMER_JOB_GRACE_NS, TIMER_SUPERVISOR_INTERVAL_NS};
+
+use crate::{
+    clean_expired_payments,
+    metrics::note_timer_run,
+    refresh_reserves_report_job,
+    utils::{
+        check_invariants,
+        fetch_cycles_icp_exchange_rate,
+        log,
+        lottery_and_pos_and_pledge,
+        monitor_cycles,
+        redistribute_icps,
+        rotate_randomness_beacon,
+        run_lottery_draw,
+        sweep_top_ups,
+    },
+};
+
+// Every job re-arms itself at the end of its run, a trap in between ends the chain silently.
+// The supervisor keeps the last start/finish of each job and re-arms the ones that missed their deadline.
+// A re-arm bumps the job's epoch, so a run that was only slow won't re-arm a second chain when it finishes.
+// A job runs once at a time, the run holds it in flight until its guard drops, on finish or on a trap in a callback.
+// Kept on the heap, the timers don't survive an upgrade either and are re-armed in post_upgrade.
+#[derive(Default, Clone)]
+struct JobRecord {
+    epoch: u64,
+    last_started_at: Option<u64>,
+    last_finished_at: Option<u64>,
+    last_error: Option<(u64, String)>,
+    next_run_at: Option<u64>,
+    runs: u64,
+    rearms: u64,
+    in_flight: Option<u64>,
+}
+
+impl JobRecord {
+    // the state the supervisor re-arms the job from, never while a run is in flight
+    fn needs_rearm(&self, now: u64) -> Option<TimerJobState> {
+        Some(self.state(now)).filter(|state| matches!(state, TimerJobState::Stalled | TimerJobState::Overdue))
+    }
+
+    fn state(&self, now: u64) -> TimerJobState {
+        // still awaiting, however long it takes
+        if self.in_flight.is_some() {
+            return TimerJobState::Running;
+        }
+
+        // the guard dropped without a finish, the run trapped
+        if let Some(started_at) = self.last_started_at {
+            let unfinished = self.last_finished_at.is_none_or(|finished_at| finished_at < started_at);
+            let rearmed_since = self.next_run_at.is_some_and(|next_run_at| next_run_at > started_at);
+
+            if unfinished && !rearmed_since {
+                return TimerJobState::Stalled;
+            }
+        }
+
+        match self.next_run_at {
+            Some(next_run_at) if now > next_run_at + TIMER_JOB_GRACE_NS => TimerJobState::Overdue,
+            Some(_) => TimerJobState::Scheduled,
+            None => TimerJobState::NotArmed,
+        }
+    }
+}
+
+thread_local! {
+    static JOBS: RefCell<BTreeMap<TimerJob, JobRecord>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+fn entry(job: TimerJob) -> fn() {
+    match job {
+        TimerJob::PosRound => lottery_and_pos_and_pledge,
+        TimerJob::CyclesExchangeRate => fetch_cycles_icp_exchange_rate,
+        TimerJob::IcpRedistribution => redistribute_icps,
+        TimerJob::CleanExpiredPayments => clean_expired_payments,
+        TimerJob::CyclesMonitor => monitor_cycles,
+        TimerJob::RandomnessBeacon => rotate_randomness_beacon,
+        TimerJob::LotteryDraw => run_lottery_draw,
+        TimerJob::InvariantCheck => check_invariants,
+        TimerJob::ReservesReport => refresh_reserves_report_job,
+        TimerJob::TopUpSweep => sweep_top_ups,
+    }
+}
+
+fn schedule(job: TimerJob, record: &mut JobRecord, delay_ns: u64) {
+    record.next_run_at = Some(time() + delay_ns);
+    set_timer(Duration::from_nanos(delay_ns), entry(job));
+}
+
+// starts a new chain for the job, used by the set_*_timer functions
+pub fn arm(job: TimerJob, delay_ns: u64) {
+    JOBS.with_borrow_mut(|jobs| {
+        let record = jobs.entry(job).or_default();
+        record.epoch += 1;
+        schedule(job, record, delay_ns);
+    });
+}
+
+// Held by a run from `start` to `finish`. Dropping it releases the job, a trap in a callback
+// drops the future and with it the guard, so the job is left for the supervisor to re-arm.
+pub struct JobRun {
+    job: TimerJob,
+    epoch: u64,
+}
+
+impl Drop for JobRun {
+    fn drop(&mut self) {
+        JOBS.with_borrow_mut(|jobs| {
+            if let Some(record) = jobs.get_mut(&self.job) {
+                if record.in_flight == Some(self.epoch) {
+                    record.in_flight = None;
+                }
+            }
+        });
+    }
+}
+
+// None while another run of the job is in flight, the caller skips this run then
+pub fn start(job: TimerJob) -> Option<JobRun> {
+    JOBS.with_borrow_mut(|jobs| {
+        let record = jobs.entry(job).or_default();
+
+        if let Some(running_epoch) = record.in_flight {
+            // the run in flight belongs to an older chain and won't re-arm, retry this one after it
+            if running_epoch != record.epoch {
+                schedule(job, record, TIMER_SUPERVISOR_INTERVAL_NS);
+            }
+
+            return None;
+        }
+
+        record.last_started_at = Some(time());
+        record.in_flight = Some(record.epoch);
+
+        Some(JobRun { job, epoch: record.epoch })
+    })
+}
+
+// errors the job recovers from on its own, kept for get_health
+pub fn fail(job: TimerJob, error: String) {
+    log(LogLevel::Warn, "timers", format!("{}: {}", job.name(), error));
+
+    JOBS.with_borrow_mut(|jobs| {
+        jobs.entry(job).or_default().last_error = Some((time(), error));
+    });
+}
+
+// ends the run and re-arms the job, unless the supervisor already started a new chain for it
+pub fn finish(run: JobRun, next_in_ns: u64) {
+    let job = run.job;
+    let started_at = JOBS.with_borrow_mut(|jobs| {
+        let record = jobs.entry(job).or_default();
+        if record.epoch != run.epoch {
+            return None;
+        }
+
+        record.last_finished_at = Some(time());
+        record.runs += 1;
+        schedule(job, record, next_in_ns);
+
+        record.last_started_at
+    });
+    drop(run);
+
+    if let Some(started_at) = started_at {
+        note_timer_run(job.name(), started_at);
+    }
+}
+
+fn supervise() {
+    let now = time();
+
+    let rearmed = JOBS.with_borrow_mut(|jobs| {
+        let mut rearmed = Vec::new();
+
+        for (job, record) in jobs.iter_mut() {
+            let Some(state) = record.needs_rearm(now) else {
+                continue;
+            };
+
+            record.epoch += 1;
+            record.rearms += 1;
+            record.last_error = Some((now, format!("{:?}, re-armed by the supervisor", state)));
+            schedule(*job, record, 0);
+
+            rearmed.push((*job, state));
+        }
+
+        rearmed
+    });
+
+    for (job, state) in rearmed {
+        log(LogLevel::Error, "timers", format!("{} was {:?}, re-armed", job.name(), state));
+    }
+}
+
+// an interval timer isn't re-armed by a callback, so a trap can't stop it
+pub fn set_timer_supervisor() {
+    set_timer_interval(Duration::from_nanos(TIMER_SUPERVISOR_INTERVAL_NS), supervise);
+}
+
+pub fn get_jobs_health(now: u64) -> Vec<TimerJobHealth> {
+    JOBS.with_borrow(|jobs| {
+        TimerJob::ALL
+            .iter()
+            .map(|job| {
+                let record = jobs.get(job).cloned().unwrap_or_default();
+
+                TimerJobHealth {
+                    job: *job,
+                    state: record.state(now),
+                    last_started_at: record.last_started_at,
+                    last_finished_at: record.last_finished_at,
+                    last_error: record.last_error,
+                    next_run_at: record.next_run_at,
+                    runs: record.runs,
+                    rearms: record.rearms,
+                }
+            })
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = TIMER_JOB_GRACE_NS * 10;
+
+    fn in_flight_since(started_at: u64) -> JobRecord {
+        JobRecord {
+            epoch: 1,
+            last_started_at: Some(started_at),
+            next_run_at: Some(started_at),
+            in_flight: Some(1),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn a_run_still_awaiting_is_never_re_armed() {
+        let record = in_flight_since(NOW - TIMER_JOB_GRACE_NS * 5);
+
+        assert_eq!(record.state(NOW), TimerJobState::Running);
+        assert_eq!(record.needs_rearm(NOW), None);
+    }
+
+    #[test]
+    fn a_run_released_without_finishing_is_re_armed() {
+        let record = JobRecord { in_flight: None, ..in_flight_since(NOW - 1) };
+
+        assert_eq!(record.needs_rearm(NOW), Some(TimerJobState::Stalled));
+
+        let finished = JobRecord { last_finished_at: Some(NOW), next_run_at: Some(NOW + 1), ..record };
+        assert_eq!(finished.needs_rearm(NOW), None);
+        assert_eq!(finished.needs_rearm(NOW + 1 + TIMER_JOB_GRACE_NS + 1), Some(TimerJobState::Overdue));
+    }
+
+    #[test]
+    fn dropping_the_run_releases_only_its_own_epoch() {
+        JOBS.with_borrow_mut(|jobs| jobs.insert(TimerJob::TopUpSweep, in_flight_since(NOW)));
+
+        drop(JobRun { job: TimerJob::TopUpSweep, epoch: 0 });
+        assert_eq!(JOBS.with_borrow(|jobs| jobs[&TimerJob::TopUpSweep].in_flight), Some(1));
+
+        drop(JobRun { job: TimerJob::TopUpSweep, epoch: 1 });
+        assert_eq!(JOBS.with_borrow(|jobs| jobs[&TimerJob::TopUpSweep].in_flight), None);
+        assert_eq!(JOBS.with_borrow(|jobs| jobs[&TimerJob::TopUpSweep].state(NOW)), TimerJobState::Stalled);
+    }
+}
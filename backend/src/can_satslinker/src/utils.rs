@@ -1,30 +1,47 @@
-use std::{cell::RefCell, time::Duration};
+use std::{cell::RefCell, collections::BTreeMap, time::Duration};
 
 use candid::{Nat, Principal};
 use ic_cdk::{
-    api::management_canister::main::raw_rand, 
+    api::{
+        canister_balance128,
+        management_canister::ecdsa::{
+            ecdsa_public_key,
+            sign_with_ecdsa,
+            EcdsaCurve,
+            EcdsaKeyId,
+            EcdsaPublicKeyArgument,
+            SignWithEcdsaArgument,
+        },
+        management_canister::main::raw_rand,
+        time,
+    },
     caller, 
     id, 
     spawn, 
     println,
 };
-use ic_e8s::c::{E8s, ECs};
+use ic_e8s::c::E8s;
+use num_bigint::BigUint;
 use ic_cdk_timers::set_timer;
 
 use icrc_ledger_types::{
     icrc1::{
         account::Account, 
-        transfer::TransferArg,
+        transfer::{Memo as IcrcMemo, TransferArg, TransferError as IcrcTransferError},
     },
     icrc2::transfer_from::TransferFromArgs,
-    icrc2::allowance::AllowanceArgs,
 };
 
 use ic_ledger_types::{
     AccountBalanceArgs, 
     AccountIdentifier, 
+    Memo,
     Subaccount,
+    Tokens,
+    TransferArgs,
+    TransferError as LedgerTransferError,
 };
+use ic_certified_map::RbTree;
 use ic_stable_structures::{
     memory_manager::{
         MemoryId, 
@@ -39,25 +56,68 @@ use shared::{
         state::SatslinkerState,
         types::{
             SatslinkerStateInfo, 
-            ICP_REDISTRIBUTION_INTERVAL_NS,
+            PledgeLockPeriod,
+            SwapDirection,
+            SwapPoolInfo,
+            TopUpStatus,
+            CMC_TOP_UP_MEMO,
+            TOP_UP_REWARD_MEMO,
+            SATSLINKER_TOPUP_SUBACCOUNT,
+            TOP_UP_MAX_ATTEMPTS,
+            TOP_UP_RETRY_DELAY_NS,
+            TOP_UP_REFUND_MEMO,
+            TOP_UP_SWEEP_INTERVAL_NS,
+            CYCLES_MONITOR_INTERVAL_NS,
+            LotteryBet,
+            LotteryBetStatus,
+            BASIS_POINTS_BASE,
+            LOTTERY_MAX_ATTEMPTS,
+            LOTTERY_RETRY_DELAY_NS,
+            LOTTERY_BET_MEMO,
+            LOTTERY_PRIZE_MEMO,
+            DevFeeToken,
             SATSLINKER_DEV_FEE_SUBACCOUNT, 
             SATSLINKER_REDISTRIBUTION_SUBACCOUNT,
             SATSLINKER_LOTTERY_SUBACCOUNT,
             SATSLINKER_SWAPPOOL_SUBACCOUNT,
-            ICPSWAP_PRICE_UPDATE_INTERVAL_NS,
-            REDISTRIBUTION_DEV_SHARE_E8S,
-            REDISTRIBUTION_SWAPPOOL_SHARE_E8S, 
-            REDISTRIBUTION_LOTTERY_SHARE_E8S, 
-            POS_ROUND_DELAY_NS,
+            SATSLINKER_PLEDGE_SUBACCOUNT,
+            RedistributionOutcome,
+            RedistributionTarget,
+            RedistributionTransfer,
+            ReserveToken,
+            ReservesReport,
+            RESERVES_REPORT_MIN_INTERVAL_NS,
+            InvariantReport,
+            INVARIANT_CHECK_INTERVAL_NS,
+            ECDSA_KEY_NAME_DEV,
+            ECDSA_KEY_NAME_IC,
+            POS_ROUND_CATCH_UP_BATCH,
             POS_ROUND_START_REWARD_E8S,
+            TimerJob,
+            NetworkProfile,
+            LogLevel,
         },
     },
-    cmc::CMCClient, 
+    cmc::{CMCClient, NotifyTopUpError, NotifyTopUpRequest},
+    evm::{
+        eth_address_from_public_key,
+        EthAddress,
+        format_eth_address,
+        parse_eth_address,
+        to_recoverable_signature,
+        vip_attestation_digest,
+    },
     icrc1::ICRC1CanisterClient,
+    satslinker::api::{AttestationSigner, VipAttestation},
+    CanisterMode,
     ENV_VARS,
     ICP_FEE, 
+    SATSLINK_FEE,
+    MIN_ICP_STAKE_E8S_U64,
+    MIN_STL_LOTTERY_E8S_U64,
 };
 
+use crate::supervisor;
 // use crate::subaccount_of;
 
 thread_local! {
@@ -66,19 +126,86 @@ thread_local! {
 
     pub static STATE: RefCell<SatslinkerState> = RefCell::new(
         SatslinkerState {
+            // memory region 0 is taken by the payment records
             vip_shares: StableBTreeMap::init(
-                MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(0))), // VIP Shares uses memory region 0
+                MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(1))), // VIP Shares uses memory region 1
             ),
             pledge_shares: StableBTreeMap::init(
-                MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(1))), // Pledge Shares uses memory region 1
+                MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(2))), // Pledge Shares uses memory region 2
             ),
             info: Cell::init(
-                MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(2))), // Info uses memory region 2
+                MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(3))), // Info uses memory region 3
                 SatslinkerStateInfo::default()
             )
             .expect("Unable to create total supply cell"),
             vip_participants: StableBTreeMap::init(
-                MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(3))) // VIP Participants uses memory region 3
+                MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(4))) // VIP Participants uses memory region 4
+            ),
+            pledge_lots: StableBTreeMap::init(
+                MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(5))) // Pledge Lots uses memory region 5
+            ),
+            swap_pool: Cell::init(
+                MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(6))), // Swap Pool uses memory region 6
+                SwapPoolInfo::default()
+            )
+            .expect("Unable to create swap pool cell"),
+            swap_lp_shares: StableBTreeMap::init(
+                MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(7))) // Swap LP Shares uses memory region 7
+            ),
+            top_ups: StableBTreeMap::init(
+                MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(8))) // Top Ups uses memory region 8
+            ),
+            lottery_bets: StableBTreeMap::init(
+                MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(9))) // Lottery Bets uses memory region 9
+            ),
+            lottery_draws: StableBTreeMap::init(
+                MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(10))) // Lottery Draws uses memory region 10
+            ),
+            lottery_tickets: StableBTreeMap::init(
+                MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(11))) // Lottery Tickets uses memory region 11
+            ),
+            beacon_rounds: StableBTreeMap::init(
+                MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(12))) // Beacon Rounds uses memory region 12
+            ),
+            dev_fee_withdrawals: StableBTreeMap::init(
+                MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(13))) // Dev Fee Withdrawals uses memory region 13
+            ),
+            dev_fee_audit_log: StableBTreeMap::init(
+                MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(14))) // Dev Fee Audit Log uses memory region 14
+            ),
+            redistributions: StableBTreeMap::init(
+                MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(15))) // Redistributions uses memory region 15
+            ),
+            reserves_report: Cell::init(
+                MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(16))), // Reserves Report uses memory region 16
+                ReservesReport::default()
+            )
+            .expect("Unable to create reserves report cell"),
+            account_transfers: StableBTreeMap::init(
+                MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(17))) // Account Transfers uses memory region 17
+            ),
+            vip_address_index: StableBTreeMap::init(
+                MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(18))) // VIP Address Index uses memory region 18
+            ),
+            invariant_report: Cell::init(
+                MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(19))), // Invariant Report uses memory region 19
+                InvariantReport::default()
+            )
+            .expect("Unable to create invariant report cell"),
+            blocks: StableBTreeMap::init(
+                MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(20))) // Blocks uses memory region 20
+            ),
+            certified_vip_by_eth: RbTree::new(),
+            certified_vip_by_principal: RbTree::new(),
+            vip_attestations: BTreeMap::new(),
+            logs: StableBTreeMap::init(
+                MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(21))) // Logs uses memory region 21
+            ),
+            vip_started_at: StableBTreeMap::init(
+                MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(22))) // VIP Started At uses memory region 22
+            ),
+            swap_lp_payouts: StableBTreeMap::init(
+                MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(23))) // Swap LP Payouts uses memory region 23
             ),
         }
     )
@@ -96,148 +223,202 @@ fn init_seed() {
     });
 }
 
+// timing parameters of the network the canister runs on
+pub fn network_profile() -> NetworkProfile {
+    STATE.with_borrow(|s| s.get_info().get_network_profile())
+}
+
 pub fn set_lottery_and_pos_and_pledge_timer(){
-    print!("Executing set_lottery_and_pos_and_pledge_timer function");
-    set_timer(Duration::from_nanos(0), lottery_and_pos_and_pledge);
+    log(LogLevel::Debug, "timers", String::from("PoS round timer armed"));
+    supervisor::arm(TimerJob::PosRound, 0);
 }
 
 pub fn lottery_and_pos_and_pledge() {
     spawn(async {
+        let Some(run) = supervisor::start(TimerJob::PosRound) else {
+            return;
+        };
+
         // if the canister is stopped for an upgrade - don't run any rounds and reschedule the next block in case the canister resumes.
-        if is_stopped() {
-            set_timer(Duration::from_nanos(POS_ROUND_DELAY_NS), lottery_and_pos_and_pledge);
+        // the same goes for rounds paused after an invariant violation, the missed rounds are caught up afterwards
+        if is_stopped() || STATE.with_borrow(|s| s.get_info().are_reward_rounds_paused()) {
+            supervisor::finish(run, network_profile().pos_round_delay_ns);
             return;
         }
 
         let this_canister_id = id();
         let mut temp_satslink_token_lottery = E8s::zero();
         let mut temp_satslink_token_dev = E8s::zero();
+        let mut temp_compounded_pledge = E8s::zero();
         let satslink_token_can = ICRC1CanisterClient::new(ENV_VARS.satslink_token_canister_id);
 
-        STATE.with_borrow_mut(|s| {
+        // rounds are due by time since genesis, the ones missed while stopped, paused or late are caught up in batches
+        let (next_round_in_ns, rounds, current_round) = STATE.with_borrow_mut(|s| {
+            let now = time();
             let mut info = s.get_info();
-            let satslink_enabled = info.is_satslink_enabled();
-            ic_cdk::println!("Total dev tokens before lottery_and_pos_and_pledge: {:?}", info.total_token_dev.clone());
-            ic_cdk::println!("Total lottery tokens before lottery_and_pos_and_pledge: {:?}", info.total_token_lottery.clone());
-            if satslink_enabled {
-                // First calculate lottery reward (10%)
-                let mut cur_lottery_reward = info.current_token_reward.clone();
-                cur_lottery_reward *= ECs::<8>::from(100u64);
-                cur_lottery_reward /= ECs::<8>::from(1000u64);  // 10% = 100/1000
-                info.total_token_lottery += cur_lottery_reward.clone();
-                println!("Lottery reward increased: {:?}", cur_lottery_reward);
-                println!("Current total lottery reward: {:?}", info.total_token_lottery);
-
-                // Then calculate developer reward (2.5%)
-                let mut cur_dev_reward = info.current_token_reward.clone();
-                cur_dev_reward *= ECs::<8>::from(25u64);
-                cur_dev_reward /= ECs::<8>::from(1000u64);  // 2.5% = 25/1000
-                info.total_token_dev += cur_dev_reward.clone();
-                println!("Developer reward increased: {:?}", cur_dev_reward);
-                println!("Current total developer reward: {:?}", info.total_token_dev);
-
-                // Finally perform other distributions (POS and pledge)
-                s.distribute_vip_pos_rewards();
-                s.distribute_pledge_rewards();
-
-                // Process lottery reward transfer
-                if info.total_token_lottery > E8s::from(POS_ROUND_START_REWARD_E8S) {
-                    println!("Lottery reward reached threshold, preparing transfer: {:?}", info.total_token_lottery);
-                    temp_satslink_token_lottery = info.total_token_lottery.clone();
-                    info.total_token_minted = info.total_token_minted.clone() + &temp_satslink_token_lottery;
-                }
-
-                // Process developer reward transfer
-                if info.total_token_dev > E8s::from(POS_ROUND_START_REWARD_E8S) {
-                    println!("Developer reward reached threshold, preparing transfer: {:?}", info.total_token_dev);
-                    temp_satslink_token_dev = info.total_token_dev.clone();
-                    info.total_token_minted = info.total_token_minted.clone() + &temp_satslink_token_dev;
-                }
+            info.init_pos_genesis(now);
 
-                info.complete_round();
+            if !info.is_satslink_enabled() {
+                info.skip_missed_pos_rounds(now);
             }
             s.set_info(info);
+
+            let rounds = s.catch_up_pos_rounds(now, POS_ROUND_CATCH_UP_BATCH);
+
+            s.grant_matured_pledge_tickets(now);
+
+            let info = s.get_info();
+
+            // Process lottery reward transfer, counted as minted once the transfer succeeds
+            if info.total_token_lottery > E8s::from(POS_ROUND_START_REWARD_E8S) {
+                temp_satslink_token_lottery = info.total_token_lottery.clone();
+            }
+
+            // Process developer reward transfer
+            if info.total_token_dev > E8s::from(POS_ROUND_START_REWARD_E8S) {
+                temp_satslink_token_dev = info.total_token_dev.clone();
+            }
+
+            // compounded pledge rewards are minted into custody in one transfer per run
+            temp_compounded_pledge = info.get_pending_compounded_pledge();
+
+            // still behind - continue right away, otherwise wait for the next round
+            let next_round_in_ns = if info.get_missed_pos_rounds(now) > 0 {
+                0
+            } else {
+                info.get_next_pos_round_at().unwrap_or(now).saturating_sub(now)
+            };
+            let current_round = info.current_pos_round;
+
+            (next_round_in_ns, rounds, current_round)
         });
 
+        if rounds > 0 {
+            log(LogLevel::Debug, "pos", format!("Processed {} PoS rounds, current round: {}", rounds, current_round));
+        }
+
         //transfer to lottery pool and dev pool
         if temp_satslink_token_lottery > E8s::zero() {
+            log(LogLevel::Debug, "pos", format!("Lottery reward reached threshold, transferring: {:?}", temp_satslink_token_lottery));
             let transfer_result = satslink_token_can.icrc1_transfer(TransferArg {
                 to: Account {
                     owner: this_canister_id,
                     subaccount: Some(SATSLINKER_LOTTERY_SUBACCOUNT)
                 },
-                amount: Nat(temp_satslink_token_lottery.val),
+                amount: Nat(temp_satslink_token_lottery.val.clone()),
                 from_subaccount: None,
                 fee: None,
                 created_at_time: None,
                 memo: None,
             }).await;
             
-            // Only reset to zero after successful transfer
-            if transfer_result.is_ok() {
+            // Only deduct after successful transfer, the minted SATSLINK funds the current draw
+            if matches!(transfer_result, Ok((Ok(_),))) {
                 STATE.with_borrow_mut(|s| {
                     let mut info = s.get_info();
-                    info.total_token_lottery = E8s::zero();
+                    info.total_token_lottery -= &temp_satslink_token_lottery;
+                    info.note_minted_to_pool(&temp_satslink_token_lottery);
+                    info.note_lottery_draw_funded(&temp_satslink_token_lottery);
                     s.set_info(info);
                 });
+            } else {
+                log(LogLevel::Error, "pos", format!("Lottery pool transfer failed: {:?}", transfer_result));
             }
         }
 
         if temp_satslink_token_dev > E8s::zero() {
+            log(LogLevel::Debug, "pos", format!("Developer reward reached threshold, transferring: {:?}", temp_satslink_token_dev));
             let transfer_result = satslink_token_can.icrc1_transfer(TransferArg {
                 to: Account {
                     owner: this_canister_id,
                     subaccount: Some(SATSLINKER_DEV_FEE_SUBACCOUNT)
                 },
-                amount: Nat(temp_satslink_token_dev.val),
+                amount: Nat(temp_satslink_token_dev.val.clone()),
+                from_subaccount: None,
+                fee: None,
+                created_at_time: None,
+                memo: None,
+            }).await;
+
+            // Only deduct after successful transfer, a ledger error leaves it for the next run
+            if matches!(transfer_result, Ok((Ok(_),))) {
+                STATE.with_borrow_mut(|s| {
+                    let mut info = s.get_info();
+                    info.total_token_dev -= &temp_satslink_token_dev;
+                    info.note_minted_to_pool(&temp_satslink_token_dev);
+                    s.set_info(info);
+                });
+            } else {
+                log(LogLevel::Error, "pos", format!("Dev pool transfer failed: {:?}", transfer_result));
+            }
+        }
+
+        if temp_compounded_pledge > E8s::zero() {
+            let transfer_result = satslink_token_can.icrc1_transfer(TransferArg {
+                to: Account {
+                    owner: this_canister_id,
+                    subaccount: Some(SATSLINKER_PLEDGE_SUBACCOUNT)
+                },
+                amount: Nat(temp_compounded_pledge.val.clone()),
                 from_subaccount: None,
                 fee: None,
                 created_at_time: None,
                 memo: None,
             }).await;
 
-            // Only reset to zero after successful transfer
-            if transfer_result.is_ok() {
+            // Only note as minted after successful transfer, otherwise retried next round
+            if let Ok((Ok(_),)) = transfer_result {
                 STATE.with_borrow_mut(|s| {
                     let mut info = s.get_info();
-                    info.total_token_dev = E8s::zero();
+                    info.note_compounded_pledge_minted(&temp_compounded_pledge);
                     s.set_info(info);
                 });
+            } else {
+                log(LogLevel::Error, "pledge", format!("Compounded pledge rewards transfer failed: {:?}", transfer_result));
             }
         }
 
-        set_timer(Duration::from_nanos(POS_ROUND_DELAY_NS), lottery_and_pos_and_pledge);
+        flush_early_unpledge_treasury_carry().await;
+
+        supervisor::finish(run, next_round_in_ns);
     });
 }
 
 pub fn set_cycles_icp_exchange_rate_timer() {
-    set_timer(Duration::from_nanos(0), fetch_cycles_icp_exchange_rate);
+    supervisor::arm(TimerJob::CyclesExchangeRate, 0);
 }
 
-fn fetch_cycles_icp_exchange_rate() {
+pub fn fetch_cycles_icp_exchange_rate() {
     spawn(async {
+        let Some(run) = supervisor::start(TimerJob::CyclesExchangeRate) else {
+            return;
+        };
         let cmc = CMCClient(ENV_VARS.cycles_minting_canister_id);
         let call_result = cmc.get_icp_xdr_conversion_rate().await;
 
-        if let Ok(response) = call_result {
-            STATE.with_borrow_mut(|s| {
+        match call_result {
+            Ok(response) => STATE.with_borrow_mut(|s| {
                 let mut info = s.get_info();
                 info.update_icp_to_cycles_exchange_rate(response.0.data);
 
                 s.set_info(info);
-            });
+            }),
+            Err(e) => supervisor::fail(TimerJob::CyclesExchangeRate, format!("{:?}", e)),
         }
 
-        set_timer(Duration::from_nanos(ICPSWAP_PRICE_UPDATE_INTERVAL_NS), fetch_cycles_icp_exchange_rate,);
+        supervisor::finish(run, network_profile().price_update_interval_ns);
     });
 }
 
 pub fn set_icp_redistribution_timer() {
-    set_timer(Duration::from_nanos(0), redistribute_icps);
+    supervisor::arm(TimerJob::IcpRedistribution, 0);
 }
 
-fn redistribute_icps() {
+pub fn redistribute_icps() {
     spawn(async {
+        let Some(run) = supervisor::start(TimerJob::IcpRedistribution) else {
+            return;
+        };
         let this_canister_id = id();
         let redistribution_subaccount = Subaccount(SATSLINKER_REDISTRIBUTION_SUBACCOUNT);
         let redistribution_account_id = AccountIdentifier::new(&this_canister_id, &redistribution_subaccount);
@@ -253,167 +434,1276 @@ fn redistribute_icps() {
 
         if let Ok(balance) = balance_call_result {
             let balance_e8s = balance.e8s();
-            let one_e8s = 1_0000_0000;
-
-            // if more than 1 ICP is collected
-            if balance_e8s > one_e8s {
-                let qty_to_swappool = balance_e8s * REDISTRIBUTION_SWAPPOOL_SHARE_E8S / one_e8s; //60%
-                let qty_to_lottery = balance_e8s * REDISTRIBUTION_LOTTERY_SHARE_E8S / one_e8s;     //10%
-                let qty_to_dev = balance_e8s * REDISTRIBUTION_DEV_SHARE_E8S / one_e8s;         //30%
 
-                // send half to the swap (pool) canister            
+            if let Some((plan, carry_reset)) = STATE.with_borrow(|s| s.plan_redistribution(balance_e8s)) {
                 let satslink_icp_can = ICRC1CanisterClient::new(ENV_VARS.icp_token_canister_id);
-                let _ = satslink_icp_can.icrc1_transfer(TransferArg {
-                    to: Account {
-                        owner: this_canister_id,
-                        subaccount: Some(SATSLINKER_SWAPPOOL_SUBACCOUNT),
-                    },
-                    amount: Nat::from(qty_to_swappool - ICP_FEE),
-                    from_subaccount: Some(redistribution_subaccount.0),
-                    fee: Some(Nat::from(ICP_FEE)),
-                    created_at_time: None,
-                    memo: None,
+                let mut transfers = Vec::new();
+
+                for (target, qty_e8s) in plan {
+                    // shares that can't cover the fee wait for the next run
+                    if qty_e8s <= ICP_FEE {
+                        transfers.push(RedistributionTransfer {
+                            target,
+                            qty_e8s,
+                            outcome: RedistributionOutcome::Carried,
+                        });
+                        continue;
+                    }
+
+                    let transfer_result = satslink_icp_can
+                        .icrc1_transfer(TransferArg {
+                            to: Account {
+                                owner: this_canister_id,
+                                subaccount: Some(target.subaccount()),
+                            },
+                            amount: Nat::from(qty_e8s - ICP_FEE),
+                            from_subaccount: Some(redistribution_subaccount.0),
+                            fee: Some(Nat::from(ICP_FEE)),
+                            created_at_time: None,
+                            memo: None,
+                        })
+                        .await
+                        .map_err(|e| format!("{:?}", e))
+                        .and_then(|(r,)| r.map_err(|e| format!("{:?}", e)));
+
+                    let outcome = match transfer_result {
+                        Ok(block_index) => {
+                            // the swap pool share backs the SATSLINK/ICP liquidity
+                            if target == RedistributionTarget::SwapPool {
+                                STATE.with_borrow_mut(|s| s.note_swap_pool_icp_donation(E8s::from(qty_e8s - ICP_FEE)));
+                            }
+
+                            RedistributionOutcome::Sent { block_index }
+                        }
+                        Err(reason) => {
+                            log(LogLevel::Warn, "redistribution", format!("Transfer to {:?} failed: {}", target, reason));
+                            RedistributionOutcome::Failed { reason }
+                        }
+                    };
+
+                    transfers.push(RedistributionTransfer { target, qty_e8s, outcome });
+                }
+
+                STATE.with_borrow_mut(|s| s.record_redistribution(balance_e8s, carry_reset, transfers, time()));
+            }
+        } else if let Err(e) = balance_call_result {
+            supervisor::fail(TimerJob::IcpRedistribution, format!("{:?}", e));
+        }
+
+        supervisor::finish(run, network_profile().redistribution_interval_ns);
+    });
+}
+
+// Pulls the caller's SATSLINK into the pledge subaccount and only then records the pledge lot
+pub async fn pledge_callers_satslink(qty_e8s_u64: u64, lock_period: PledgeLockPeriod, auto_compound: bool) -> Result<Nat, String> {
+    let caller = caller();
+    let qty = E8s::from(qty_e8s_u64);
+
+    if qty < SatslinkerStateInfo::get_min_pledge_share() {
+        return Err(format!(
+            "The pledge should be at least {:?}",
+            SatslinkerStateInfo::get_min_pledge_share()
+        ));
+    }
+
+    let satslink_token_can = ICRC1CanisterClient::new(ENV_VARS.satslink_token_canister_id);
+    let block_idx = satslink_token_can
+        .icrc2_transfer_from(TransferFromArgs {
+            spender_subaccount: None,
+            from: Account {
+                owner: caller,
+                subaccount: None,
+            },
+            to: Account {
+                owner: id(),
+                subaccount: Some(SATSLINKER_PLEDGE_SUBACCOUNT),
+            },
+            amount: Nat::from(qty_e8s_u64),
+            fee: None,
+            memo: None,
+            created_at_time: None,
+        })
+        .await
+        .map_err(|e| format!("{:?}", e))?
+        .0
+        .map_err(|e| format!("{:?}", e))?;
+
+    // the draw tickets for the lot are granted once its lock ends
+    STATE.with_borrow_mut(|s| s.mint_pledge_lot(qty.clone(), lock_period, auto_compound, time(), caller));
+
+    Ok(block_idx)
+}
+
+// Returns the caller's unlocked pledge from the pledge subaccount, the ledger fee is paid out of `qty`
+pub async fn withdraw_callers_pledge(qty: E8s, to: Principal) -> Result<Nat, String> {
+    let caller = caller();
+
+    if qty <= E8s::from(SATSLINK_FEE) {
+        return Err(String::from("The amount is too small to cover the transfer fee"));
+    }
+
+    let touched_lots = STATE.with_borrow_mut(|s| s.withdraw_pledge_lots(caller, qty.clone(), time()))?;
+
+    let transfer_result = transfer_from_pledge_subaccount(Account::from(to), qty.clone()).await;
+
+    if transfer_result.is_err() {
+        STATE.with_borrow_mut(|s| s.revert_withdraw_pledge_lots(caller, qty, touched_lots));
+    }
+
+    transfer_result
+}
+
+// Closes one of the caller's locked lots, paying out what is left after the early exit penalty.
+// The treasury part of the penalty is moved to the dev fee subaccount.
+pub async fn unpledge_callers_lot_early(lot_id: u64, to: Principal) -> Result<Nat, String> {
+    let caller = caller();
+
+    let quote = STATE.with_borrow(|s| s.quote_early_unpledge(&caller, lot_id, time()))?;
+    if quote.payout <= E8s::from(SATSLINK_FEE) {
+        return Err(String::from("The payout is too small to cover the transfer fee"));
+    }
+
+    let receipt = STATE.with_borrow_mut(|s| s.unpledge_early(caller, lot_id, time()))?;
+    let to_treasury = receipt.quote.to_treasury.clone();
+
+    let transfer_result = transfer_from_pledge_subaccount(Account::from(to), receipt.quote.payout.clone()).await;
+
+    if transfer_result.is_err() {
+        STATE.with_borrow_mut(|s| s.revert_unpledge_early(caller, receipt));
+        return transfer_result;
+    }
+
+    STATE.with_borrow_mut(|s| {
+        let mut info = s.get_info();
+        info.add_early_unpledge_treasury_carry(&to_treasury);
+        s.set_info(info);
+    });
+    flush_early_unpledge_treasury_carry().await;
+
+    transfer_result
+}
+
+// The treasury part of the penalties waits in the pledge subaccount as a carry. It is moved to the dev fee
+// subaccount once it covers the fee, a failed transfer keeps it for the next attempt of the round timer.
+pub async fn flush_early_unpledge_treasury_carry() {
+    let carry = STATE.with_borrow_mut(|s| {
+        let mut info = s.get_info();
+        if info.get_early_unpledge_treasury_carry() <= E8s::from(SATSLINK_FEE) {
+            return E8s::zero();
+        }
+
+        let carry = info.take_early_unpledge_treasury_carry();
+        s.set_info(info);
+
+        carry
+    });
+
+    if carry == E8s::zero() {
+        return;
+    }
+
+    let treasury_account = Account {
+        owner: id(),
+        subaccount: Some(SATSLINKER_DEV_FEE_SUBACCOUNT),
+    };
+
+    if let Err(e) = transfer_from_pledge_subaccount(treasury_account, carry.clone()).await {
+        log(LogLevel::Warn, "pledge", format!("Unable to move {:?} of early unpledge penalties to the treasury, kept for the next attempt: {}", carry, e));
+
+        STATE.with_borrow_mut(|s| {
+            let mut info = s.get_info();
+            info.add_early_unpledge_treasury_carry(&carry);
+            s.set_info(info);
+        });
+    }
+}
+
+async fn transfer_from_pledge_subaccount(to: Account, qty: E8s) -> Result<Nat, String> {
+    let satslink_token_can = ICRC1CanisterClient::new(ENV_VARS.satslink_token_canister_id);
+
+    satslink_token_can
+        .icrc1_transfer(TransferArg {
+            to,
+            amount: Nat(qty.val) - Nat::from(SATSLINK_FEE),
+            from_subaccount: Some(SATSLINKER_PLEDGE_SUBACCOUNT),
+            fee: Some(Nat::from(SATSLINK_FEE)),
+            created_at_time: None,
+            memo: None,
+        })
+        .await
+        .map_err(|e| format!("{:?}", e))
+        .and_then(|(r,)| r.map_err(|e| format!("{:?}", e)))
+}
+
+fn swap_pool_ledger(icp: bool) -> (Principal, u64) {
+    if icp {
+        (ENV_VARS.icp_token_canister_id, ICP_FEE)
+    } else {
+        (ENV_VARS.satslink_token_canister_id, SATSLINK_FEE)
+    }
+}
+
+async fn pull_into_swap_pool(icp: bool, from: Principal, qty_e8s_u64: u64) -> Result<Nat, String> {
+    let (ledger, _) = swap_pool_ledger(icp);
+
+    ICRC1CanisterClient::new(ledger)
+        .icrc2_transfer_from(TransferFromArgs {
+            spender_subaccount: None,
+            from: Account {
+                owner: from,
+                subaccount: None,
+            },
+            to: Account {
+                owner: id(),
+                subaccount: Some(SATSLINKER_SWAPPOOL_SUBACCOUNT),
+            },
+            amount: Nat::from(qty_e8s_u64),
+            fee: None,
+            memo: None,
+            created_at_time: None,
+        })
+        .await
+        .map_err(|e| format!("{:?}", e))
+        .and_then(|(r,)| r.map_err(|e| format!("{:?}", e)))
+}
+
+// the ledger fee is paid out of `qty`, so the pool subaccount always loses exactly `qty`
+async fn send_from_swap_pool(icp: bool, to: Principal, qty: E8s) -> Result<Nat, String> {
+    let (ledger, fee) = swap_pool_ledger(icp);
+
+    if qty <= E8s::from(fee) {
+        return Err(String::from("The amount is too small to cover the transfer fee"));
+    }
+
+    ICRC1CanisterClient::new(ledger)
+        .icrc1_transfer(TransferArg {
+            to: Account {
+                owner: to,
+                subaccount: None,
+            },
+            amount: Nat(qty.val) - Nat::from(fee),
+            from_subaccount: Some(SATSLINKER_SWAPPOOL_SUBACCOUNT),
+            fee: Some(Nat::from(fee)),
+            created_at_time: None,
+            memo: None,
+        })
+        .await
+        .map_err(|e| format!("{:?}", e))
+        .and_then(|(r,)| r.map_err(|e| format!("{:?}", e)))
+}
+
+async fn refund_swap_pool_input(icp: bool, to: Principal, qty: E8s) {
+    if let Err(e) = send_from_swap_pool(icp, to, qty).await {
+        log(LogLevel::Error, "swap", format!("Unable to refund the swap pool input to {}: {}", to, e));
+    }
+}
+
+// Swaps the caller's tokens through the pool, the output is sent to `to`
+pub async fn swap_callers_tokens(direction: SwapDirection, amount_in_e8s_u64: u64, min_amount_out: E8s, to: Principal) -> Result<Nat, String> {
+    let caller = caller();
+    let amount_in = E8s::from(amount_in_e8s_u64);
+    let icp_in = direction == SwapDirection::IcpToSatslink;
+
+    // fail fast, before anything is transferred
+    let quote = STATE.with_borrow(|s| s.get_swap_pool().quote(direction, &amount_in))?;
+    if quote.amount_out < min_amount_out {
+        return Err(format!("Slippage limit exceeded: {:?} would be received", quote.amount_out));
+    }
+
+    pull_into_swap_pool(icp_in, caller, amount_in_e8s_u64).await?;
+
+    let quote = match STATE.with_borrow_mut(|s| s.apply_swap(direction, amount_in.clone(), min_amount_out)) {
+        Ok(quote) => quote,
+        Err(e) => {
+            refund_swap_pool_input(icp_in, caller, amount_in).await;
+            return Err(e);
+        }
+    };
+
+    let transfer_result = send_from_swap_pool(!icp_in, to, quote.amount_out.clone()).await;
+
+    if transfer_result.is_err() {
+        STATE.with_borrow_mut(|s| s.revert_swap(&quote));
+        refund_swap_pool_input(icp_in, caller, amount_in).await;
+    }
+
+    transfer_result
+}
+
+// Deposits both tokens into the pool for LP shares, the part that doesn't match the pool ratio is refunded
+pub async fn add_callers_liquidity(icp_e8s_u64: u64, satslink_e8s_u64: u64) -> Result<E8s, String> {
+    let caller = caller();
+    let dev = STOPPED_FOR_UPDATE.with_borrow(|(dev, _)| *dev);
+
+    // fail fast, the state checks it again once both tokens have arrived
+    if caller != dev && STATE.with_borrow(|s| s.get_swap_pool().lp_total_supply == E8s::zero()) {
+        return Err(String::from("Only the dev can seed the empty swap pool"));
+    }
+
+    pull_into_swap_pool(true, caller, icp_e8s_u64).await?;
+
+    if let Err(e) = pull_into_swap_pool(false, caller, satslink_e8s_u64).await {
+        refund_swap_pool_input(true, caller, E8s::from(icp_e8s_u64)).await;
+        return Err(e);
+    }
+
+    let icp = E8s::from(icp_e8s_u64);
+    let satslink = E8s::from(satslink_e8s_u64);
+
+    let (shares, icp_used, satslink_used) =
+        match STATE.with_borrow_mut(|s| s.add_liquidity(caller, dev, icp.clone(), satslink.clone())) {
+            Ok(it) => it,
+            Err(e) => {
+                refund_swap_pool_input(true, caller, icp).await;
+                refund_swap_pool_input(false, caller, satslink).await;
+                return Err(e);
+            }
+        };
+
+    let mut icp_excess = icp;
+    icp_excess -= &icp_used;
+    if icp_excess > E8s::from(ICP_FEE) {
+        refund_swap_pool_input(true, caller, icp_excess).await;
+    }
+
+    let mut satslink_excess = satslink;
+    satslink_excess -= &satslink_used;
+    if satslink_excess > E8s::from(SATSLINK_FEE) {
+        refund_swap_pool_input(false, caller, satslink_excess).await;
+    }
+
+    Ok(shares)
+}
+
+// Burns the caller's LP shares and sends both tokens back
+pub async fn remove_callers_liquidity(shares: E8s, min_icp: E8s, min_satslink: E8s) -> Result<(E8s, E8s), String> {
+    let caller = caller();
+
+    let (icp_out, satslink_out) =
+        STATE.with_borrow_mut(|s| s.remove_liquidity(caller, shares.clone(), min_icp, min_satslink))?;
+
+    if let Err(e) = send_from_swap_pool(true, caller, icp_out.clone()).await {
+        STATE.with_borrow_mut(|s| s.revert_remove_liquidity(caller, shares, icp_out, satslink_out));
+        return Err(e);
+    }
+
+    if let Err(e) = send_from_swap_pool(false, caller, satslink_out.clone()).await {
+        // the ICP side is already sent, the SATSLINK side is kept for the provider as it is
+        log(LogLevel::Warn, "swap", format!("SATSLINK side of the liquidity removal of {} is owed: {}", caller, e));
+        STATE.with_borrow_mut(|s| s.owe_lp_payout(caller, satslink_out));
+        return Err(format!("{}, the SATSLINK side is kept for you, claim it with claim_lp_payout", e));
+    }
+
+    Ok((icp_out, satslink_out))
+}
+
+// Retries the SATSLINK side of a liquidity removal that couldn't be sent
+pub async fn claim_callers_lp_payout() -> Result<Nat, String> {
+    let caller = caller();
+
+    let owed = STATE.with_borrow_mut(|s| s.take_lp_payout(&caller))
+        .ok_or(String::from("Nothing is owed"))?;
+
+    let transfer_result = send_from_swap_pool(false, caller, owed.clone()).await;
+
+    if transfer_result.is_err() {
+        STATE.with_borrow_mut(|s| s.owe_lp_payout(caller, owed));
+    }
+
+    transfer_result
+}
+
+// CMC credits cycles to the canister whose principal is encoded in the subaccount
+fn cmc_top_up_subaccount(canister_id: &Principal) -> Subaccount {
+    let principal_bytes = canister_id.as_slice();
+    let mut subaccount = [0u8; 32];
+    subaccount[0] = principal_bytes.len() as u8;
+    subaccount[1..1 + principal_bytes.len()].copy_from_slice(principal_bytes);
+
+    Subaccount(subaccount)
+}
+
+// Pulls the caller's ICP and starts burning it into cycles for `canister_id`
+pub async fn top_up_canister_with_callers_icp(canister_id: Principal, qty_e8s_u64: u64) -> Result<u64, String> {
+    let caller = caller();
+
+    if qty_e8s_u64 < MIN_ICP_STAKE_E8S_U64 {
+        return Err(format!("The top up should be at least {} e8s", MIN_ICP_STAKE_E8S_U64));
+    }
+
+    let satslink_icp_can = ICRC1CanisterClient::new(ENV_VARS.icp_token_canister_id);
+    satslink_icp_can
+        .icrc2_transfer_from(TransferFromArgs {
+            spender_subaccount: None,
+            from: Account {
+                owner: caller,
+                subaccount: None,
+            },
+            to: Account {
+                owner: id(),
+                subaccount: Some(SATSLINKER_TOPUP_SUBACCOUNT),
+            },
+            amount: Nat::from(qty_e8s_u64),
+            fee: Some(Nat::from(ICP_FEE)),
+            memo: None,
+            created_at_time: None,
+        })
+        .await
+        .map_err(|e| format!("{:?}", e))?
+        .0
+        .map_err(|e| format!("{:?}", e))?;
+
+    let top_up_id = STATE.with_borrow_mut(|s| s.create_top_up(caller, canister_id, qty_e8s_u64, time()));
+    process_top_up(top_up_id).await;
+
+    Ok(top_up_id)
+}
+
+// retry timers don't survive an upgrade, so post_upgrade schedules every top up with a step left
+pub fn resume_pending_top_ups() {
+    for top_up_id in STATE.with_borrow(|s| s.get_pending_top_up_ids()) {
+        schedule_top_up_retry(top_up_id);
+    }
+}
+
+pub fn set_top_up_sweep_timer() {
+    supervisor::arm(TimerJob::TopUpSweep, TOP_UP_SWEEP_INTERVAL_NS);
+}
+
+// Gives the top ups that ran out of attempts a fresh set of retries
+pub fn sweep_top_ups() {
+    let Some(run) = supervisor::start(TimerJob::TopUpSweep) else {
+        return;
+    };
+
+    for top_up_id in STATE.with_borrow(|s| s.get_given_up_top_up_ids()) {
+        STATE.with_borrow_mut(|s| s.reset_top_up_attempts(top_up_id));
+        spawn(process_top_up(top_up_id));
+    }
+
+    supervisor::finish(run, TOP_UP_SWEEP_INTERVAL_NS);
+}
+
+fn schedule_top_up_retry(top_up_id: u64) {
+    set_timer(Duration::from_nanos(TOP_UP_RETRY_DELAY_NS), move || {
+        spawn(process_top_up(top_up_id))
+    });
+}
+
+// Moves a top up one step further: ICP -> CMC -> notify -> SATSLINK reward, or the ICP back to the user.
+// Every step is safe to repeat, so a failed step is simply retried later.
+pub async fn process_top_up(top_up_id: u64) {
+    let Some(record) = STATE.with_borrow(|s| s.get_top_up(top_up_id)) else {
+        return;
+    };
+
+    if record.is_final() {
+        return;
+    }
+
+    let attempt = STATE.with_borrow_mut(|s| s.note_top_up_attempt(top_up_id));
+    let mut should_retry = false;
+
+    match record.status {
+        TopUpStatus::Received => {
+            let transfer_result = ic_ledger_types::transfer(
+                ENV_VARS.icp_token_canister_id,
+                TransferArgs {
+                    memo: Memo(CMC_TOP_UP_MEMO),
+                    amount: Tokens::from_e8s(record.qty_e8s - ICP_FEE),
+                    fee: Tokens::from_e8s(ICP_FEE),
+                    from_subaccount: Some(Subaccount(
+                        record.source_subaccount.unwrap_or(SATSLINKER_TOPUP_SUBACCOUNT),
+                    )),
+                    to: AccountIdentifier::new(
+                        &ENV_VARS.cycles_minting_canister_id,
+                        &cmc_top_up_subaccount(&record.canister_id),
+                    ),
+                    // the same creation time on every attempt lets the ledger deduplicate retries
+                    created_at_time: Some(ic_ledger_types::Timestamp {
+                        timestamp_nanos: record.created_at,
+                    }),
+                },
+            )
+            .await;
+
+            match transfer_result {
+                Ok(Ok(block_index)) | Ok(Err(LedgerTransferError::TxDuplicate { duplicate_of: block_index })) => {
+                    STATE.with_borrow_mut(|s| s.set_top_up_status(top_up_id, TopUpStatus::SentToCmc { block_index }));
+                    // notify right away
+                    spawn(process_top_up(top_up_id));
+                }
+                Ok(Err(e)) => {
+                    log(LogLevel::Error, "top_up", format!("Top up {} transfer to the CMC failed: {:?}", top_up_id, e));
+                    STATE.with_borrow_mut(|s| s.fail_top_up(top_up_id, format!("{:?}", e)));
+                    // refund right away
+                    spawn(process_top_up(top_up_id));
+                }
+                Err(e) => {
+                    log(LogLevel::Warn, "top_up", format!("Top up {} transfer to the CMC, attempt {}: {:?}", top_up_id, attempt, e));
+                    should_retry = true;
+                }
+            }
+        }
+        TopUpStatus::SentToCmc { block_index } => {
+            let cmc = CMCClient(ENV_VARS.cycles_minting_canister_id);
+            let notify_result = cmc
+                .notify_top_up(NotifyTopUpRequest {
+                    block_index,
+                    canister_id: record.canister_id,
                 })
                 .await;
 
-                // send another half to a special subaccount of this canister, that will eventually satslink them
-                let _ = satslink_icp_can.icrc1_transfer(TransferArg {
+            match notify_result {
+                Ok((Ok(cycles),)) => {
+                    STATE.with_borrow_mut(|s| s.set_top_up_status(top_up_id, TopUpStatus::Completed { cycles }));
+                    spawn(process_top_up(top_up_id));
+                }
+                Ok((Err(NotifyTopUpError::Processing),)) => should_retry = true,
+                Err(e) => {
+                    log(LogLevel::Warn, "top_up", format!("Top up {} notify, attempt {}: {:?}", top_up_id, attempt, e));
+                    should_retry = true;
+                }
+                // CMC refunds to the source account, only user ICP has to be forwarded back
+                Ok((Err(NotifyTopUpError::Refunded { reason, .. }),)) => {
+                    STATE.with_borrow_mut(|s| s.note_top_up_refunded_by_cmc(top_up_id, reason));
+                    spawn(process_top_up(top_up_id));
+                }
+                Ok((Err(NotifyTopUpError::TransactionTooOld(_)),)) => {
+                    log(LogLevel::Error, "top_up", format!("Top up {} notify failed: the transaction is too old", top_up_id));
+                    STATE.with_borrow_mut(|s| s.fail_top_up(top_up_id, String::from("Transaction too old")));
+                    spawn(process_top_up(top_up_id));
+                }
+                Ok((Err(e),)) => {
+                    log(LogLevel::Error, "top_up", format!("Top up {} notify failed: {:?}", top_up_id, e));
+                    STATE.with_borrow_mut(|s| s.fail_top_up(top_up_id, format!("{:?}", e)));
+                    spawn(process_top_up(top_up_id));
+                }
+            }
+        }
+        TopUpStatus::Completed { .. } => {
+            if let Some((to, reward, created_at)) = STATE.with_borrow_mut(|s| s.prepare_top_up_reward(top_up_id, time())) {
+                let satslink_token_can = ICRC1CanisterClient::new(ENV_VARS.satslink_token_canister_id);
+                let transfer_result = satslink_token_can
+                    .icrc1_transfer(TransferArg {
                         to: Account {
-                            owner: this_canister_id,
-                            subaccount: Some(SATSLINKER_LOTTERY_SUBACCOUNT),
+                            owner: to,
+                            subaccount: None,
                         },
-                        amount: Nat::from(qty_to_lottery - ICP_FEE),
-                        from_subaccount: Some(redistribution_subaccount.0),
-                        fee: Some(Nat::from(ICP_FEE)),
-                        created_at_time: None,
-                        memo: None,
+                        amount: Nat(reward.val),
+                        from_subaccount: None,
+                        fee: None,
+                        // the same creation time and memo on every attempt let the ledger deduplicate retries
+                        created_at_time: Some(created_at),
+                        memo: Some(record_memo(TOP_UP_REWARD_MEMO, top_up_id)),
                     })
-                    .await
-                    .map_err(|e| format!("{:?}", e));
+                    .await;
 
-                // send a little bit to the subaccount, where the devs can withdraw them
-                let _ = satslink_icp_can.icrc1_transfer(TransferArg {
+                match transfer_result {
+                    Ok((Ok(_),)) | Ok((Err(IcrcTransferError::Duplicate { .. }),)) => {
+                        STATE.with_borrow_mut(|s| s.note_top_up_reward_paid(top_up_id));
+                    }
+                    // rejected by the ledger, nothing was minted
+                    Ok((Err(e),)) => {
+                        log(LogLevel::Warn, "top_up", format!("Top up {} reward, attempt {}: {:?}", top_up_id, attempt, e));
+                        STATE.with_borrow_mut(|s| s.revert_top_up_reward(top_up_id));
+                        should_retry = true;
+                    }
+                    // the outcome is unknown, the retry sends the same transfer
+                    Err(e) => {
+                        log(LogLevel::Warn, "top_up", format!("Top up {} reward, attempt {}: {:?}", top_up_id, attempt, e));
+                        should_retry = true;
+                    }
+                }
+            }
+        }
+        TopUpStatus::Refunded { .. } | TopUpStatus::Failed { .. } => {
+            if let Some(refund) = record.refund {
+                let satslink_icp_can = ICRC1CanisterClient::new(ENV_VARS.icp_token_canister_id);
+                let transfer_result = satslink_icp_can
+                    .icrc1_transfer(TransferArg {
                         to: Account {
-                            owner: this_canister_id,
-                            subaccount: Some(SATSLINKER_DEV_FEE_SUBACCOUNT),
+                            owner: record.from,
+                            subaccount: None,
                         },
-                        amount: Nat::from(qty_to_dev - ICP_FEE),
-                        from_subaccount: Some(redistribution_subaccount.0),
+                        amount: Nat::from(refund.qty_e8s - ICP_FEE),
+                        from_subaccount: Some(refund.from_subaccount),
                         fee: Some(Nat::from(ICP_FEE)),
-                        created_at_time: None,
-                        memo: None,
+                        // the same creation time and memo on every attempt let the ledger deduplicate retries
+                        created_at_time: Some(record.created_at),
+                        memo: Some(record_memo(TOP_UP_REFUND_MEMO, top_up_id)),
                     })
-                    .await
-                    .map_err(|e| format!("{:?}", e));
+                    .await;
+
+                match transfer_result {
+                    Ok((Ok(block_index),)) | Ok((Err(IcrcTransferError::Duplicate { duplicate_of: block_index }),)) => {
+                        STATE.with_borrow_mut(|s| s.note_top_up_refund_sent(top_up_id, block_index));
+                    }
+                    _ => {
+                        log(LogLevel::Warn, "top_up", format!("Top up {} refund, attempt {}: {:?}", top_up_id, attempt, transfer_result));
+                        should_retry = true;
+                    }
+                }
             }
         }
+    }
+
+    if should_retry {
+        if attempt < TOP_UP_MAX_ATTEMPTS {
+            schedule_top_up_retry(top_up_id);
+        } else {
+            log(LogLevel::Error, "top_up", format!("Top up {} ran out of attempts, left for the next sweep", top_up_id));
+        }
+    }
+}
+
+pub fn set_invariant_check_timer() {
+    supervisor::arm(TimerJob::InvariantCheck, INVARIANT_CHECK_INTERVAL_NS);
+}
+
+pub fn check_invariants() {
+    let Some(run) = supervisor::start(TimerJob::InvariantCheck) else {
+        return;
+    };
+    check_and_log_invariants();
+
+    supervisor::finish(run, INVARIANT_CHECK_INTERVAL_NS);
+}
+
+// Violations and the pause they trigger go to the log buffer, whether the check ran on the timer or on demand
+pub fn check_and_log_invariants() -> InvariantReport {
+    let was_paused = STATE.with_borrow(|s| s.get_info().are_reward_rounds_paused());
+    let report = STATE.with_borrow_mut(|s| s.check_invariants(time()));
+
+    if !report.violations.is_empty() {
+        log(LogLevel::Error, "invariants", format!("Invariant check found {} violations: {:?}", report.violations.len(), report.violations));
+    }
+    if report.rounds_paused && !was_paused {
+        log(LogLevel::Error, "invariants", String::from("Reward rounds are paused until the violations are resolved"));
+    }
+
+    report
+}
+
+pub fn set_cycles_monitor_timer() {
+    supervisor::arm(TimerJob::CyclesMonitor, 0);
+}
+
+// Keeps the canister alive: below the threshold the treasury ICP is burned into own cycles
+pub fn monitor_cycles() {
+    spawn(async {
+        let Some(run) = supervisor::start(TimerJob::CyclesMonitor) else {
+            return;
+        };
+        let balance = canister_balance128();
+        let (threshold, top_up_e8s) = STATE.with_borrow(|s| {
+            let info = s.get_info();
+            (info.get_cycles_threshold(), info.get_cycles_top_up_e8s())
+        });
+
+        if balance >= threshold {
+            STATE.with_borrow_mut(|s| {
+                let mut info = s.get_info();
+                info.set_low_cycles_alert(false);
+                s.set_info(info);
+            });
+        } else if !STATE.with_borrow(|s| s.has_pending_cycles_top_up()) {
+            let funded = top_up_self_from_treasury(top_up_e8s).await;
+
+            STATE.with_borrow_mut(|s| {
+                let mut info = s.get_info();
+                info.set_low_cycles_alert(!funded);
+                s.set_info(info);
+            });
+        }
 
-        set_timer(Duration::from_nanos(ICP_REDISTRIBUTION_INTERVAL_NS), redistribute_icps,); //3 hours
+        supervisor::finish(run, CYCLES_MONITOR_INTERVAL_NS);
     });
 }
 
-pub async fn stake_callers_icp_for_redistribution(qty_e8s_u64: u64) -> Result<String, String> {
-    // let caller_subaccount = subaccount_of(caller());
-    let canister_id = id();
+async fn top_up_self_from_treasury(qty_e8s: u64) -> bool {
+    let this_canister_id = id();
     let satslink_icp_can = ICRC1CanisterClient::new(ENV_VARS.icp_token_canister_id);
+    let balance_call_result = satslink_icp_can
+        .icrc1_balance_of(Account {
+            owner: this_canister_id,
+            subaccount: Some(SATSLINKER_DEV_FEE_SUBACCOUNT),
+        })
+        .await;
 
-    let token_allowance = satslink_icp_can.icrc2_allowance(
-        AllowanceArgs {
-                account: Account::from(caller()),
-                spender: Account::from(canister_id),
-            }).await.unwrap().0;
-    ic_cdk::println!("token allowance: {:?}", token_allowance);
-    
-    let transferfrom_result = satslink_icp_can.icrc2_transfer_from(
-        TransferFromArgs{
-                spender_subaccount : None,
-                from : Account { 
-                    owner: caller(), 
-                    subaccount: None 
-                },
-                to : Account{ 
-                    owner: canister_id,
-                    subaccount: Some(SATSLINKER_REDISTRIBUTION_SUBACCOUNT),
-                },
-                amount :  Nat::from(qty_e8s_u64),
-                fee : Some(Nat::from(ICP_FEE)),
-                memo : None,
-                created_at_time : None,
-            }).await
-            .map_err(|e| format!("{:?}", e));
+    // ICP of announced and executing dev fee withdrawals isn't the treasury's to burn
+    let committed = STATE.with_borrow(|s| s.get_committed_dev_fee_withdrawals(DevFeeToken::ICP));
 
-    match transferfrom_result {
-        Ok((value,)) => Ok(format!("{}|{}|{:?}", caller(), canister_id, value)),
-        Err(err) => Err(format!("{:?}", err)),
+    match balance_call_result {
+        Ok((balance,)) if balance.0 >= committed.val.clone() + BigUint::from(qty_e8s) => {}
+        Ok((balance,)) => {
+            log(LogLevel::Warn, "top_up", format!(
+                "Not enough treasury ICP to top up own cycles: {} on the balance, {:?} committed to dev fee withdrawals",
+                balance, committed,
+            ));
+            return false;
+        }
+        Err(e) => {
+            log(LogLevel::Error, "top_up", format!("Unable to fetch the treasury ICP balance: {:?}", e));
+            return false;
+        }
     }
+
+    let top_up_id = STATE.with_borrow_mut(|s| {
+        s.create_cycles_top_up(this_canister_id, SATSLINKER_DEV_FEE_SUBACCOUNT, qty_e8s, time())
+    });
+    process_top_up(top_up_id).await;
+
+    STATE.with_borrow(|s| s.get_top_up(top_up_id))
+        .map(|record| !matches!(record.status, TopUpStatus::Failed { .. } | TopUpStatus::Refunded { .. }))
+        .unwrap_or_default()
 }
 
-pub fn lottery_running(qty: u64, to: Principal) {
-    spawn(async move { // Use move keyword to take ownership of qty and to
-        let this_canister_id = id();
-        // Implement lottery game with 10% of block reward as prize pool
-        let satslink_token_can = ICRC1CanisterClient::new(ENV_VARS.satslink_token_canister_id);
+// Pulls the caller's stake into the lottery subaccount, the outcome is rolled afterwards
+pub async fn place_callers_lottery_bet(qty_e8s_u64: u64) -> Result<u64, String> {
+    let caller = caller();
 
-        // Transfer tokens to target account
-        let _ = satslink_token_can.icrc1_transfer(TransferArg {
-            to: Account {
-                owner: this_canister_id,
-                subaccount: Some(SATSLINKER_LOTTERY_SUBACCOUNT),
+    if qty_e8s_u64 < MIN_STL_LOTTERY_E8S_U64 {
+        return Err(format!("The bet should be at least {} e8s", MIN_STL_LOTTERY_E8S_U64));
+    }
+
+    let qty = E8s::from(qty_e8s_u64);
+    let lottery_account = Account {
+        owner: id(),
+        subaccount: Some(SATSLINKER_LOTTERY_SUBACCOUNT),
+    };
+
+    let satslink_token_can = ICRC1CanisterClient::new(ENV_VARS.satslink_token_canister_id);
+    let (lottery_balance,) = satslink_token_can
+        .icrc1_balance_of(lottery_account)
+        .await
+        .map_err(|e| format!("{:?}", e))?;
+
+    let (max_payout, house_edge_bp) =
+        STATE.with_borrow_mut(|s| s.reserve_lottery_bet(&qty, &E8s::new(lottery_balance.0)))?;
+
+    let transfer_result = satslink_token_can
+        .icrc2_transfer_from(TransferFromArgs {
+            spender_subaccount: None,
+            from: Account {
+                owner: caller,
+                subaccount: None,
             },
-            amount: Nat::from(qty),
-            from_subaccount: None,
-            fee: None,
-            created_at_time: None,
+            to: lottery_account,
+            amount: Nat::from(qty_e8s_u64),
+            fee: Some(Nat::from(SATSLINK_FEE)),
             memo: None,
+            created_at_time: None,
         })
         .await
         .map_err(|e| format!("{:?}", e))
-        .map(|(r,)| r.map_err(|e| format!("{:?}", e)));
+        .and_then(|(r,)| r.map_err(|e| format!("{:?}", e)));
 
-        // Get current transaction hash
-        let (rand,) = raw_rand().await.expect("Unable to fetch rand");
-        let last_digit_qty = qty % 10; 
-
-        // Find digit characters from the end of transaction hash
-        let mut last_digit_hash = None;
-        for c in rand.to_vec().iter().rev() { // Use to_vec() and iterate bytes
-            if c.is_ascii_digit() { // Use is_ascii_digit() to check character
-                last_digit_hash = Some(c - b'0'); // Convert byte to digit
-                break; // Exit loop after finding digit
+    if let Err(e) = transfer_result {
+        STATE.with_borrow_mut(|s| s.release_lottery_reservation(&max_payout));
+        return Err(e);
+    }
+
+    let bet_id = STATE.with_borrow_mut(|s| s.create_lottery_bet(caller, qty, house_edge_bp, time()));
+    settle_lottery_bet(bet_id).await;
+
+    Ok(bet_id)
+}
+
+// ICRC-1 memo of a transfer made for one record: a 4 byte tag and the record id.
+// The ledger deduplicates on the whole transfer, so records with otherwise equal transfers don't collide.
+fn record_memo(tag: u32, id: u64) -> IcrcMemo {
+    let mut bytes = tag.to_be_bytes().to_vec();
+    bytes.extend_from_slice(&id.to_be_bytes());
+
+    IcrcMemo::from(bytes)
+}
+
+// retry timers don't survive an upgrade, so post_upgrade schedules every unsettled bet with fresh attempts
+pub fn resume_unsettled_lottery_bets() {
+    for bet_id in STATE.with_borrow(|s| s.get_unsettled_lottery_bet_ids()) {
+        STATE.with_borrow_mut(|s| s.reset_lottery_bet_attempts(bet_id));
+        schedule_lottery_bet_retry(bet_id);
+    }
+}
+
+fn schedule_lottery_bet_retry(bet_id: u64) {
+    set_timer(Duration::from_nanos(LOTTERY_RETRY_DELAY_NS), move || {
+        spawn(settle_lottery_bet(bet_id))
+    });
+}
+
+// Rolls a pending bet with fresh randomness and pays a won bet out of the lottery subaccount
+pub async fn settle_lottery_bet(bet_id: u64) {
+    let Some(bet) = STATE.with_borrow(|s| s.get_lottery_bet(bet_id)) else {
+        return;
+    };
+
+    let attempt = STATE.with_borrow_mut(|s| s.note_lottery_bet_attempt(bet_id));
+    let mut should_retry = false;
+
+    match bet.status {
+        LotteryBetStatus::Pending => {
+            // raw_rand is produced in a later round than the one that accepted the stake
+            match raw_rand().await {
+                Ok((rand,)) => {
+                    let mut rng_buf = [0u8; 8];
+                    rng_buf.copy_from_slice(&rand[0..8]);
+                    let roll = u64::from_le_bytes(rng_buf) % BASIS_POINTS_BASE;
+
+                    let rolled = STATE.with_borrow_mut(|s| s.roll_lottery_bet(bet_id, roll, time()));
+                    if matches!(rolled, Some(LotteryBet { status: LotteryBetStatus::Won { .. }, .. })) {
+                        spawn(settle_lottery_bet(bet_id));
+                    }
+                }
+                Err(e) => {
+                    log(LogLevel::Warn, "lottery", format!("Bet {} roll, attempt {}: {:?}", bet_id, attempt, e));
+                    should_retry = true;
+                }
             }
         }
+        LotteryBetStatus::Won { payout } => {
+            let mut amount = payout.clone();
+            amount -= E8s::from(SATSLINK_FEE);
 
-        // Check if last digits are both odd or both even
-        if let Some(last_digit) = last_digit_hash {
-            // Convert last_digit to u64 for comparison with last_digit_qty
-            if last_digit_qty % 2 == last_digit as u64 % 2 { // Convert last_digit to u64
-                // Double the amount for caller
-                let double_amount = qty * 2;
-                // Send double amount to caller
-                let _ = satslink_token_can.icrc1_transfer(TransferArg {
+            let satslink_token_can = ICRC1CanisterClient::new(ENV_VARS.satslink_token_canister_id);
+            let transfer_result = satslink_token_can
+                .icrc1_transfer(TransferArg {
                     to: Account {
-                        owner: to,
+                        owner: bet.player,
                         subaccount: None,
                     },
-                    amount: Nat(double_amount.into()),
-                    from_subaccount: None,
-                    fee: None,
-                    created_at_time: None,
-                    memo: None,
+                    amount: Nat(amount.val),
+                    from_subaccount: Some(SATSLINKER_LOTTERY_SUBACCOUNT),
+                    fee: Some(Nat::from(SATSLINK_FEE)),
+                    // the same creation time and memo on every attempt let the ledger deduplicate retries,
+                    // the bet id in the memo keeps two bets with the same player, qty and time apart
+                    created_at_time: Some(bet.placed_at),
+                    memo: Some(record_memo(LOTTERY_BET_MEMO, bet_id)),
                 })
-                .await
-                .map_err(|e| format!("{:?}", e))
-                .map(|(r,)| r.map_err(|e| format!("{:?}", e)));
+                .await;
+
+            match transfer_result {
+                // a duplicate carries this bet's memo, so it is this bet's earlier payout
+                Ok((Ok(_),)) | Ok((Err(IcrcTransferError::Duplicate { .. }),)) => {
+                    STATE.with_borrow_mut(|s| s.note_lottery_bet_paid(bet_id));
+                }
+                _ => {
+                    log(LogLevel::Warn, "lottery", format!("Bet {} payout, attempt {}: {:?}", bet_id, attempt, transfer_result));
+                    should_retry = true;
+                }
             }
+        }
+        LotteryBetStatus::Paid { .. } | LotteryBetStatus::Lost => {}
+    }
+
+    if should_retry {
+        if attempt < LOTTERY_MAX_ATTEMPTS {
+            schedule_lottery_bet_retry(bet_id);
         } else {
-            // If no digit found, log or take other actions
-            // Example: log("No digit found in transaction hash");
-        }       
+            log(LogLevel::Error, "lottery", format!("Lottery bet {} is left unsettled after {} attempts", bet_id, attempt));
+        }
+    }
+}
+
+pub async fn buy_callers_lottery_tickets(count: u64) -> Result<u64, String> {
+    let caller = caller();
+
+    if count == 0 {
+        return Err(String::from("At least one ticket is required"));
+    }
+
+    let (draw_id, qty) = STATE.with_borrow(|s| {
+        let (draw_id, _) = s
+            .get_current_lottery_draw()
+            .filter(|(_, draw)| time() < draw.ends_at)
+            .ok_or(String::from("No lottery draw is open"))?;
+
+        let price = s.get_info().get_lottery_ticket_price();
+
+        Ok::<(u64, E8s), String>((draw_id, E8s::new(price.val * BigUint::from(count))))
+    })?;
+
+    let satslink_token_can = ICRC1CanisterClient::new(ENV_VARS.satslink_token_canister_id);
+    satslink_token_can
+        .icrc2_transfer_from(TransferFromArgs {
+            spender_subaccount: None,
+            from: Account {
+                owner: caller,
+                subaccount: None,
+            },
+            to: Account {
+                owner: id(),
+                subaccount: Some(SATSLINKER_LOTTERY_SUBACCOUNT),
+            },
+            amount: Nat(qty.val.clone()),
+            fee: Some(Nat::from(SATSLINK_FEE)),
+            memo: None,
+            created_at_time: None,
+        })
+        .await
+        .map_err(|e| format!("{:?}", e))?
+        .0
+        .map_err(|e| format!("{:?}", e))?;
+
+    // the draw may have closed while the payment was in flight, ticket sales only go to the pot of the draw they were bought for
+    let sell_result = STATE.with_borrow_mut(|s| s.sell_lottery_tickets(caller, draw_id, count, &qty, time()));
+    let Err(e) = sell_result else {
+        return sell_result;
+    };
+
+    let mut refund = qty;
+    refund -= E8s::from(SATSLINK_FEE);
+
+    let refund_result = satslink_token_can
+        .icrc1_transfer(TransferArg {
+            from_subaccount: Some(SATSLINKER_LOTTERY_SUBACCOUNT),
+            to: Account {
+                owner: caller,
+                subaccount: None,
+            },
+            amount: Nat(refund.val),
+            fee: Some(Nat::from(SATSLINK_FEE)),
+            memo: None,
+            created_at_time: None,
+        })
+        .await
+        .map_err(|e| format!("{:?}", e))
+        .and_then(|(r,)| r.map_err(|e| format!("{:?}", e)));
+
+    match refund_result {
+        Ok(_) => Err(format!("{}, the payment is refunded", e)),
+        Err(refund_err) => {
+            log(LogLevel::Error, "lottery", format!("Failed to refund the tickets of {} for draw {}: {}", caller, draw_id, refund_err));
+            Err(format!("{}, the refund failed: {}", e, refund_err))
+        }
+    }
+}
+
+pub fn set_randomness_beacon_timer() {
+    supervisor::arm(TimerJob::RandomnessBeacon, 0);
+}
+
+// Mixes fresh raw_rand into the seed through a previously published commitment, every round
+pub fn rotate_randomness_beacon() {
+    spawn(async {
+        let Some(run) = supervisor::start(TimerJob::RandomnessBeacon) else {
+            return;
+        };
+        match (raw_rand().await, raw_rand().await) {
+            (Ok((entropy,)), Ok((next_secret,))) => {
+                STATE.with_borrow_mut(|s| s.rotate_seed_beacon(entropy, next_secret, time()))
+            }
+            (Err(e), _) | (_, Err(e)) => supervisor::fail(TimerJob::RandomnessBeacon, format!("{:?}", e)),
+        }
+
+        supervisor::finish(run, network_profile().beacon_interval_ns);
+    });
+}
+
+pub fn set_lottery_draw_timer() {
+    supervisor::arm(TimerJob::LotteryDraw, 0);
+}
+
+// Closes the draw once its time is up, reveals its beacon round with fresh randomness to pick the winner
+// and opens the next draw with a new commitment
+pub fn run_lottery_draw() {
+    spawn(async {
+        let Some(run) = supervisor::start(TimerJob::LotteryDraw) else {
+            return;
+        };
+        let now = time();
+        let current = STATE.with_borrow(|s| s.get_current_lottery_draw());
+
+        let next_check_ns = match current {
+            None => match raw_rand().await {
+                Ok((secret,)) => {
+                    STATE.with_borrow_mut(|s| s.open_lottery_draw(secret, time()));
+                    network_profile().lottery_draw_interval_ns
+                }
+                Err(e) => {
+                    supervisor::fail(TimerJob::LotteryDraw, format!("{:?}", e));
+                    LOTTERY_RETRY_DELAY_NS
+                }
+            },
+            Some((_, draw)) if now < draw.ends_at => draw.ends_at - now,
+            Some(_) if is_stopped() => LOTTERY_RETRY_DELAY_NS,
+            Some((draw_id, _)) => match (raw_rand().await, raw_rand().await) {
+                (Ok((entropy,)), Ok((next_secret,))) => {
+                    STATE.with_borrow_mut(|s| {
+                        s.draw_lottery(draw_id, entropy, time());
+                        s.open_lottery_draw(next_secret, time());
+                    });
+
+                    network_profile().lottery_draw_interval_ns
+                }
+                (Err(e), _) | (_, Err(e)) => {
+                    supervisor::fail(TimerJob::LotteryDraw, format!("{:?}", e));
+                    LOTTERY_RETRY_DELAY_NS
+                }
+            },
+        };
+
+        for (draw_id, _) in STATE.with_borrow(|s| s.get_unpaid_lottery_draws()) {
+            pay_lottery_draw_prize(draw_id, 1).await;
+        }
+
+        supervisor::finish(run, next_check_ns);
+    });
+}
+
+async fn pay_lottery_draw_prize(draw_id: u64, attempt: u32) {
+    let Some(draw) = STATE.with_borrow(|s| s.lottery_draws.get(&draw_id)) else {
+        return;
+    };
+    let (Some(winner), Some(prize)) = (draw.winner, draw.prize.clone()) else {
+        return;
+    };
+    if draw.paid {
+        return;
+    }
+
+    // a prize that can't cover the fee stays in the lottery subaccount
+    if prize <= E8s::from(SATSLINK_FEE) {
+        STATE.with_borrow_mut(|s| s.note_lottery_draw_paid(draw_id));
+        return;
+    }
+
+    let mut amount = prize;
+    amount -= E8s::from(SATSLINK_FEE);
+
+    let satslink_token_can = ICRC1CanisterClient::new(ENV_VARS.satslink_token_canister_id);
+    let transfer_result = satslink_token_can
+        .icrc1_transfer(TransferArg {
+            to: Account {
+                owner: winner,
+                subaccount: None,
+            },
+            amount: Nat(amount.val),
+            from_subaccount: Some(SATSLINKER_LOTTERY_SUBACCOUNT),
+            fee: Some(Nat::from(SATSLINK_FEE)),
+            // the same creation time and memo on every attempt let the ledger deduplicate retries
+            created_at_time: draw.drawn_at,
+            memo: Some(record_memo(LOTTERY_PRIZE_MEMO, draw_id)),
+        })
+        .await;
+
+    match transfer_result {
+        // a duplicate carries this draw's memo, so it is this draw's earlier payout
+        Ok((Ok(_),)) | Ok((Err(IcrcTransferError::Duplicate { .. }),)) => {
+            STATE.with_borrow_mut(|s| s.note_lottery_draw_paid(draw_id));
+        }
+        _ if attempt < LOTTERY_MAX_ATTEMPTS => {
+            log(LogLevel::Warn, "lottery", format!("Draw {} prize, attempt {}: {:?}", draw_id, attempt, transfer_result));
+            set_timer(Duration::from_nanos(LOTTERY_RETRY_DELAY_NS), move || {
+                spawn(pay_lottery_draw_prize(draw_id, attempt + 1))
+            });
+        }
+        _ => log(LogLevel::Error, "lottery", format!("Lottery draw {} prize is left unpaid after {} attempts", draw_id, attempt)),
+    }
+}
+
+fn dev_fee_ledger(token: DevFeeToken) -> (Principal, u64) {
+    match token {
+        DevFeeToken::ICP => (ENV_VARS.icp_token_canister_id, ICP_FEE),
+        DevFeeToken::SATSLINK => (ENV_VARS.satslink_token_canister_id, SATSLINK_FEE),
+    }
+}
+
+// Announces a withdrawal only if the dev fee subaccount covers it together with the ones already pending
+pub async fn announce_callers_dev_fee_withdrawal(token: DevFeeToken, qty: E8s, to: Principal) -> Result<u64, String> {
+    let caller = caller();
+    let (ledger_id, _) = dev_fee_ledger(token);
+
+    let (balance,) = ICRC1CanisterClient::new(ledger_id)
+        .icrc1_balance_of(Account {
+            owner: id(),
+            subaccount: Some(SATSLINKER_DEV_FEE_SUBACCOUNT),
+        })
+        .await
+        .map_err(|e| format!("Unable to fetch the dev fee balance: {:?}", e))?;
+
+    STATE.with_borrow_mut(|s| s.announce_dev_fee_withdrawal(token, qty, to, E8s::new(balance.0), caller, time()))
+}
+
+// Sends a confirmed withdrawal out of the dev fee subaccount, the ledger fee is paid out of `qty`.
+// The owner has to be someone other than the dev, otherwise the dev alone could withdraw.
+pub async fn execute_dev_fee_withdrawal(withdrawal_id: u64) -> Result<Nat, String> {
+    let caller = caller();
+    let dev = STOPPED_FOR_UPDATE.with_borrow(|(dev, _)| *dev);
+    if caller == dev {
+        return Err(String::from("Set a dev fee owner other than the dev before confirming withdrawals"));
+    }
+
+    let withdrawal = STATE.with_borrow_mut(|s| s.confirm_dev_fee_withdrawal(withdrawal_id, caller, time()))?;
+
+    let (ledger_id, fee) = dev_fee_ledger(withdrawal.token);
+
+    let mut amount = withdrawal.qty.clone();
+    amount -= E8s::from(fee);
+
+    let ledger = ICRC1CanisterClient::new(ledger_id);
+    let result = ledger
+        .icrc1_transfer(TransferArg {
+            to: Account {
+                owner: withdrawal.to,
+                subaccount: None,
+            },
+            amount: Nat(amount.val),
+            from_subaccount: Some(SATSLINKER_DEV_FEE_SUBACCOUNT),
+            fee: Some(Nat::from(fee)),
+            created_at_time: None,
+            memo: None,
+        })
+        .await
+        .map_err(|e| format!("{:?}", e))
+        .and_then(|(r,)| r.map_err(|e| format!("{:?}", e)));
+
+    STATE.with_borrow_mut(|s| s.complete_dev_fee_withdrawal(withdrawal_id, result.clone(), caller, time()));
+
+    result
+}
+
+// Fetches the balance of every canister subaccount and compares it to what the state owes from it.
+// A recent report is returned as is, so the ledgers aren't queried on every call.
+pub async fn compile_reserves_report(icp_paid_in: E8s) -> ReservesReport {
+    let last_report = STATE.with_borrow(|s| s.get_reserves_report());
+    if !last_report.lines.is_empty() && time() < last_report.created_at + RESERVES_REPORT_MIN_INTERVAL_NS {
+        return last_report;
+    }
+
+    let this_canister_id = id();
+    let icp_can = ICRC1CanisterClient::new(ENV_VARS.icp_token_canister_id);
+    let satslink_token_can = ICRC1CanisterClient::new(ENV_VARS.satslink_token_canister_id);
+
+    let mut lines = STATE.with_borrow(|s| s.get_reserve_liabilities());
+    for line in lines.iter_mut() {
+        let ledger = match line.token {
+            ReserveToken::ICP => &icp_can,
+            ReserveToken::SATSLINK => &satslink_token_can,
+        };
+
+        let balance_call_result = ledger
+            .icrc1_balance_of(Account {
+                owner: this_canister_id,
+                subaccount: Some(line.subaccount),
+            })
+            .await;
+
+        match balance_call_result {
+            Ok((balance,)) => line.balance = Some(E8s::new(balance.0)),
+            Err(e) => log(LogLevel::Warn, "reserves", format!("Unable to fetch the {} {:?} balance: {:?}", line.name, line.token, e)),
+        }
+    }
+
+    STATE.with_borrow_mut(|s| s.store_reserves_report(lines, icp_paid_in, time()))
+}
+
+// the local replica only has the test key
+fn ecdsa_key_id() -> EcdsaKeyId {
+    let name = match ENV_VARS.mode {
+        CanisterMode::IC => ECDSA_KEY_NAME_IC,
+        CanisterMode::Dev => ECDSA_KEY_NAME_DEV,
+    };
+
+    EcdsaKeyId {
+        curve: EcdsaCurve::Secp256k1,
+        name: String::from(name),
+    }
+}
+
+pub async fn get_attestation_signer() -> Result<AttestationSigner, String> {
+    let cached = STATE.with_borrow(|s| s.get_info().ecdsa_public_key);
+
+    let public_key = match cached {
+        Some(public_key) => public_key,
+        None => {
+            let (response,) = ecdsa_public_key(EcdsaPublicKeyArgument {
+                canister_id: None,
+                derivation_path: Vec::new(),
+                key_id: ecdsa_key_id(),
+            })
+            .await
+            .map_err(|(code, msg)| format!("Unable to fetch the ECDSA public key: {:?} {}", code, msg))?;
+
+            STATE.with_borrow_mut(|s| {
+                let mut info = s.get_info();
+                info.ecdsa_public_key = Some(response.public_key.clone());
+                s.set_info(info);
+            });
+
+            response.public_key
+        }
+    };
+
+    Ok(AttestationSigner {
+        eth_address: format_eth_address(&eth_address_from_public_key(&public_key)?),
+        public_key,
+    })
+}
+
+// The expiry comes from the certified VIP tree, so the attestation and get_certified_vip_status always agree.
+// `address` is parsed once by the caller, the lookup and the signed message both use it.
+pub async fn attest_vip_status(address: EthAddress, chain_id: u64) -> Result<VipAttestation, String> {
+    let eth_address = format_eth_address(&address);
+
+    let vip_expires_at_ns = STATE.with_borrow(|s| s.get_certified_vip_expiry(Some(&eth_address), None));
+    if vip_expires_at_ns <= time() {
+        return Err(String::from("The address has no active VIP"));
+    }
+
+    let vip_expires_at = vip_expires_at_ns / 1_000_000_000;
+    if let Some(attestation) = STATE.with_borrow(|s| s.get_vip_attestation(&address, chain_id, vip_expires_at))? {
+        return Ok(attestation);
+    }
+
+    let signer = get_attestation_signer().await?;
+    let signer_address = parse_eth_address(&signer.eth_address)?;
+
+    let nonce = STATE.with_borrow_mut(|s| {
+        let mut info = s.get_info();
+        let nonce = info.generate_attestation_nonce();
+        s.set_info(info);
+
+        nonce
     });
+
+    let digest = vip_attestation_digest(&address, vip_expires_at, nonce, chain_id);
+
+    let (response,) = sign_with_ecdsa(SignWithEcdsaArgument {
+        message_hash: digest.to_vec(),
+        derivation_path: Vec::new(),
+        key_id: ecdsa_key_id(),
+    })
+    .await
+    .map_err(|(code, msg)| format!("Unable to sign the attestation: {:?} {}", code, msg))?;
+
+    let signature = to_recoverable_signature(&digest, &response.signature, &signer_address)?;
+
+    let attestation = VipAttestation {
+        eth_address,
+        vip_expires_at,
+        nonce,
+        chain_id,
+        digest: digest.to_vec(),
+        signature: signature.to_vec(),
+        signer: signer.eth_address,
+    };
+    STATE.with_borrow_mut(|s| s.store_vip_attestation(address, attestation.clone()));
+
+    Ok(attestation)
+}
+
+// Keeps the entry in the stable log buffer and prints it to the replica log.
+// Must not be called while STATE is borrowed.
+pub fn log(level: LogLevel, subsystem: &str, message: String) {
+    println!("[{:?}] {}: {}", level, subsystem, message);
+
+    STATE.with_borrow_mut(|s| s.log(level, subsystem, message, caller(), time()));
 }
 
 thread_local! {
-    pub static STOPPED_FOR_UPDATE: RefCell<(Principal, bool)> = RefCell::new((Principal::anonymous(), false));
+    pub static STOPPED_FOR_UPDATE: RefCell<(Principal, bool)> = const { RefCell::new((Principal::anonymous(), false)) };
 }
 
 pub fn is_stopped() -> bool {
@@ -427,6 +1717,23 @@ pub fn assert_caller_is_dev() {
     }
 }
 
+// the dev announces dev fee withdrawals, the owner confirms them
+pub fn assert_caller_is_dev_fee_owner() {
+    let owner = STATE.with_borrow(|s| s.get_info().get_dev_fee_owner());
+    if owner != Some(caller()) {
+        panic!("Access denied");
+    }
+}
+
+// the dev can always audit, the auditor is an extra read-only role
+pub fn assert_caller_is_auditor() {
+    let dev = STOPPED_FOR_UPDATE.with_borrow(|(dev, _)| *dev);
+    let auditor = STATE.with_borrow(|s| s.get_info().get_auditor(dev));
+    if caller() != dev && caller() != auditor {
+        panic!("Access denied");
+    }
+}
+
 pub fn assert_running() {
     if is_stopped() {
         panic!("The canister is stopped and is awaiting for an update");
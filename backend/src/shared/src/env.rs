@@ -1,8 +1,8 @@
 // This file is automatically generated by build.rs
 
-pub const CAN_SATSLINKER_CANISTER_ID: &'static str = "bkyz2-fmaaa-aaaaa-qaaaq-cai";
-pub const CAN_SATSLINK_TOKEN_CANISTER_ID: &'static str = "bd3sg-teaaa-aaaaa-qaaba-cai";
-pub const CAN_II_CANISTER_ID: &'static str = "be2us-64aaa-aaaaa-qaabq-cai";
-pub const CAN_ROOT_KEY: &'static str = "[48, 129, 130, 48, 29, 6, 13, 43, 6, 1, 4, 1, 130, 220, 124, 5, 3, 1, 2, 1, 6, 12, 43, 6, 1, 4, 1, 130, 220, 124, 5, 3, 2, 1, 3, 97, 0, 165, 152, 164, 114, 19, 28, 10, 205, 33, 211, 241, 186, 121, 191, 243, 31, 137, 63, 205, 75, 75, 37, 178, 203, 174, 84, 240, 72, 203, 118, 108, 90, 81, 136, 61, 131, 225, 14, 172, 110, 251, 137, 65, 229, 252, 47, 228, 71, 12, 230, 67, 172, 56, 175, 205, 179, 190, 96, 190, 219, 141, 48, 198, 175, 127, 239, 105, 138, 12, 11, 218, 124, 108, 78, 109, 203, 105, 167, 133, 130, 56, 151, 77, 45, 238, 4, 45, 185, 110, 115, 224, 40, 195, 15, 96, 249]";
-pub const CAN_MODE: &'static str = "dev";
-pub const CAN_IC_HOST: &'static str = "http://localhost:8080";
+pub const CAN_SATSLINKER_CANISTER_ID: &str = "bkyz2-fmaaa-aaaaa-qaaaq-cai";
+pub const CAN_SATSLINK_TOKEN_CANISTER_ID: &str = "bd3sg-teaaa-aaaaa-qaaba-cai";
+pub const CAN_II_CANISTER_ID: &str = "be2us-64aaa-aaaaa-qaabq-cai";
+pub const CAN_ROOT_KEY: &str = "[48, 129, 130, 48, 29, 6, 13, 43, 6, 1, 4, 1, 130, 220, 124, 5, 3, 1, 2, 1, 6, 12, 43, 6, 1, 4, 1, 130, 220, 124, 5, 3, 2, 1, 3, 97, 0, 165, 152, 164, 114, 19, 28, 10, 205, 33, 211, 241, 186, 121, 191, 243, 31, 137, 63, 205, 75, 75, 37, 178, 203, 174, 84, 240, 72, 203, 118, 108, 90, 81, 136, 61, 131, 225, 14, 172, 110, 251, 137, 65, 229, 252, 47, 228, 71, 12, 230, 67, 172, 56, 175, 205, 179, 190, 96, 190, 219, 141, 48, 198, 175, 127, 239, 105, 138, 12, 11, 218, 124, 108, 78, 109, 203, 105, 167, 133, 130, 56, 151, 77, 45, 238, 4, 45, 185, 110, 115, 224, 40, 195, 15, 96, 249]";
+pub const CAN_MODE: &str = "dev";
+pub const CAN_IC_HOST: &str = "http://localhost:8080";
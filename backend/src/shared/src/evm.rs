@@ -0,0 +1,209 @@
+use k256::ecdsa::{RecoveryId, Signature, VerifyingKey};
+use sha3::{Digest, Keccak256};
+
+pub type EthAddress = [u8; 20];
+
+pub const EIP712_DOMAIN_NAME: &str = "SatsLink";
+pub const EIP712_DOMAIN_VERSION: &str = "1";
+pub const EIP712_DOMAIN_TYPE: &str = "EIP712Domain(string name,string version,uint256 chainId)";
+pub const VIP_ATTESTATION_TYPE: &str = "VipAttestation(address ethAddress,uint64 vipExpiresAt,uint64 nonce,uint256 chainId)";
+
+pub fn keccak256(data: &[u8]) -> [u8; 32] {
+    Keccak256::digest(data).into()
+}
+
+pub fn parse_eth_address(text: &str) -> Result<EthAddress, String> {
+    let hex = text.strip_prefix("0x").unwrap_or(text);
+    if hex.len() != 40 {
+        return Err(String::from("Invalid ETH address length"));
+    }
+
+    let mut address = [0u8; 20];
+    for (i, byte) in address.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+            .map_err(|_| String::from("Invalid ETH address"))?;
+    }
+
+    Ok(address)
+}
+
+pub fn format_eth_address(address: &EthAddress) -> String {
+    let hex: String = address.iter().map(|byte| format!("{:02x}", byte)).collect();
+
+    format!("0x{}", hex)
+}
+
+// the form addresses are keyed by, anything that isn't an address is kept as is
+pub fn normalize_eth_address(text: &str) -> String {
+    parse_eth_address(text)
+        .map(|address| format_eth_address(&address))
+        .unwrap_or_else(|_| text.to_string())
+}
+
+// the address is the last 20 bytes of keccak256 over the uncompressed key without its 0x04 prefix
+pub fn eth_address_from_public_key(sec1_public_key: &[u8]) -> Result<EthAddress, String> {
+    let key = VerifyingKey::from_sec1_bytes(sec1_public_key)
+        .map_err(|e| format!("Invalid public key: {:?}", e))?;
+    let point = key.to_encoded_point(false);
+    let hash = keccak256(&point.as_bytes()[1..]);
+
+    let mut address = [0u8; 20];
+    address.copy_from_slice(&hash[12..]);
+
+    Ok(address)
+}
+
+fn u64_word(value: u64) -> [u8; 32] {
+    let mut word = [0u8; 32];
+    word[24..].copy_from_slice(&value.to_be_bytes());
+
+    word
+}
+
+fn address_word(address: &EthAddress) -> [u8; 32] {
+    let mut word = [0u8; 32];
+    word[12..].copy_from_slice(address);
+
+    word
+}
+
+pub fn eip712_domain_separator(chain_id: u64) -> [u8; 32] {
+    let mut encoded = Vec::with_capacity(32 * 4);
+    encoded.extend_from_slice(&keccak256(EIP712_DOMAIN_TYPE.as_bytes()));
+    encoded.extend_from_slice(&keccak256(EIP712_DOMAIN_NAME.as_bytes()));
+    encoded.extend_from_slice(&keccak256(EIP712_DOMAIN_VERSION.as_bytes()));
+    encoded.extend_from_slice(&u64_word(chain_id));
+
+    keccak256(&encoded)
+}
+
+// keccak256(0x1901 || domainSeparator || hashStruct(message)), what the contract passes to ecrecover
+pub fn vip_attestation_digest(eth_address: &EthAddress, vip_expires_at: u64, nonce: u64, chain_id: u64) -> [u8; 32] {
+    let mut encoded = Vec::with_capacity(32 * 5);
+    encoded.extend_from_slice(&keccak256(VIP_ATTESTATION_TYPE.as_bytes()));
+    encoded.extend_from_slice(&address_word(eth_address));
+    encoded.extend_from_slice(&u64_word(vip_expires_at));
+    encoded.extend_from_slice(&u64_word(nonce));
+    encoded.extend_from_slice(&u64_word(chain_id));
+    let struct_hash = keccak256(&encoded);
+
+    let mut message = Vec::with_capacity(2 + 32 * 2);
+    message.extend_from_slice(&[0x19, 0x01]);
+    message.extend_from_slice(&eip712_domain_separator(chain_id));
+    message.extend_from_slice(&struct_hash);
+
+    keccak256(&message)
+}
+
+// Threshold ECDSA returns a bare 64 byte (r, s) signature. Ethereum wants a low s and the recovery
+// id, which is found by recovering the signer for both candidates.
+pub fn to_recoverable_signature(digest: &[u8; 32], signature: &[u8], signer: &EthAddress) -> Result<[u8; 65], String> {
+    let signature = Signature::from_slice(signature)
+        .map_err(|e| format!("Invalid signature: {:?}", e))?;
+    let signature = signature.normalize_s().unwrap_or(signature);
+
+    for recovery_byte in 0..2u8 {
+        let recovery_id = RecoveryId::from_byte(recovery_byte).expect("Invalid recovery id");
+        let Ok(key) = VerifyingKey::recover_from_prehash(digest, &signature, recovery_id) else {
+            continue;
+        };
+
+        if eth_address_from_public_key(key.to_encoded_point(true).as_bytes())? == *signer {
+            let mut recoverable = [0u8; 65];
+            recoverable[..64].copy_from_slice(&signature.to_bytes());
+            recoverable[64] = 27 + recovery_byte;
+
+            return Ok(recoverable);
+        }
+    }
+
+    Err(String::from("The signature doesn't match the signer"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k256::ecdsa::{signature::hazmat::PrehashSigner, SigningKey};
+
+    fn signing_key() -> SigningKey {
+        let mut secret = [0u8; 32];
+        secret[31] = 1;
+
+        SigningKey::from_slice(&secret).unwrap()
+    }
+
+    fn signer_address(key: &SigningKey) -> EthAddress {
+        eth_address_from_public_key(key.verifying_key().to_encoded_point(false).as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn keccak256_is_not_sha3() {
+        let hash: String = keccak256(b"").iter().map(|byte| format!("{:02x}", byte)).collect();
+
+        assert_eq!(hash, "c5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470");
+    }
+
+    #[test]
+    fn address_from_public_key_matches_ethereum() {
+        // the well known address of private key 1
+        assert_eq!(format_eth_address(&signer_address(&signing_key())), "0x7e5f4552091a69125d5dfcb7b8c2659029395bdf");
+    }
+
+    #[test]
+    fn eth_address_round_trips() {
+        let text = "0x7e5f4552091a69125d5dfcb7b8c2659029395bdf";
+
+        assert_eq!(format_eth_address(&parse_eth_address(text).unwrap()), text);
+        assert!(parse_eth_address("0x7e5f").is_err());
+        assert!(parse_eth_address("0xzz5f4552091a69125d5dfcb7b8c2659029395bdf").is_err());
+    }
+
+    #[test]
+    fn mixed_case_addresses_normalize_to_one_key() {
+        let text = "0x7e5f4552091a69125d5dfcb7b8c2659029395bdf";
+
+        assert_eq!(normalize_eth_address("0x7E5F4552091A69125d5DfCb7b8C2659029395Bdf"), text);
+        assert_eq!(normalize_eth_address("7e5f4552091a69125d5dfcb7b8c2659029395bdf"), text);
+        assert_eq!(normalize_eth_address("not an address"), "not an address");
+    }
+
+    #[test]
+    fn attestation_digest_binds_every_field() {
+        let address = parse_eth_address("0x7e5f4552091a69125d5dfcb7b8c2659029395bdf").unwrap();
+        let digest = vip_attestation_digest(&address, 1_000, 1, 1);
+
+        assert_eq!(digest, vip_attestation_digest(&address, 1_000, 1, 1));
+        assert_ne!(digest, vip_attestation_digest(&[0u8; 20], 1_000, 1, 1));
+        assert_ne!(digest, vip_attestation_digest(&address, 1_001, 1, 1));
+        assert_ne!(digest, vip_attestation_digest(&address, 1_000, 2, 1));
+        assert_ne!(digest, vip_attestation_digest(&address, 1_000, 1, 5));
+        assert_ne!(eip712_domain_separator(1), eip712_domain_separator(5));
+    }
+
+    #[test]
+    fn recoverable_signature_recovers_the_signer() {
+        let key = signing_key();
+        let signer = signer_address(&key);
+        let digest = vip_attestation_digest(&signer, 1_000, 1, 1);
+        let signature: Signature = key.sign_prehash(&digest).unwrap();
+
+        let recoverable = to_recoverable_signature(&digest, &signature.to_bytes(), &signer).unwrap();
+        assert!(recoverable[64] == 27 || recoverable[64] == 28);
+
+        let signature = Signature::from_slice(&recoverable[..64]).unwrap();
+        assert!(signature.normalize_s().is_none());
+
+        let recovery_id = RecoveryId::from_byte(recoverable[64] - 27).unwrap();
+        let key = VerifyingKey::recover_from_prehash(&digest, &signature, recovery_id).unwrap();
+        assert_eq!(eth_address_from_public_key(key.to_encoded_point(false).as_bytes()).unwrap(), signer);
+    }
+
+    #[test]
+    fn recoverable_signature_rejects_another_signer() {
+        let key = signing_key();
+        let digest = vip_attestation_digest(&signer_address(&key), 1_000, 1, 1);
+        let signature: Signature = key.sign_prehash(&digest).unwrap();
+
+        assert!(to_recoverable_signature(&digest, &signature.to_bytes(), &[0u8; 20]).is_err());
+    }
+}
@@ -0,0 +1,86 @@
+use candid::CandidType;
+use serde::Deserialize;
+
+// The request/response records the HTTP gateway exchanges with `http_request`
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+    // the url without the query string and the trailing slash
+    pub fn path(&self) -> &str {
+        let path = self.url.split('?').next().unwrap_or_default();
+        if path.len() > 1 {
+            path.trim_end_matches('/')
+        } else {
+            path
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub fn new(status_code: u16, content_type: &str, body: Vec<u8>) -> Self {
+        Self {
+            status_code,
+            headers: vec![
+                (String::from("Content-Type"), String::from(content_type)),
+                (String::from("Content-Length"), body.len().to_string()),
+                (String::from("Access-Control-Allow-Origin"), String::from("*")),
+            ],
+            body,
+        }
+    }
+
+    pub fn json(status_code: u16, body: &serde_json::Value) -> Self {
+        Self::new(status_code, "application/json", body.to_string().into_bytes())
+    }
+
+    pub fn error(status_code: u16, message: &str) -> Self {
+        Self::json(status_code, &serde_json::json!({ "error": message }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get(url: &str) -> HttpRequest {
+        HttpRequest {
+            method: String::from("GET"),
+            url: String::from(url),
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn the_path_drops_the_query_string_and_the_trailing_slash() {
+        assert_eq!(get("/vip/principal/aaaaa-aa?format=json").path(), "/vip/principal/aaaaa-aa");
+        assert_eq!(get("/stats/").path(), "/stats");
+        assert_eq!(get("/").path(), "/");
+        assert_eq!(get("/?x=1").path(), "/");
+    }
+
+    #[test]
+    fn responses_declare_their_length_and_allow_any_origin() {
+        let response = HttpResponse::error(404, "Not found");
+        let header = |name: &str| response.headers.iter().find(|(key, _)| key == name).map(|(_, value)| value.clone());
+
+        assert_eq!(response.status_code, 404);
+        assert_eq!(response.body, br#"{"error":"Not found"}"#.to_vec());
+        assert_eq!(header("Content-Type"), Some(String::from("application/json")));
+        assert_eq!(header("Content-Length"), Some(response.body.len().to_string()));
+        assert_eq!(header("Access-Control-Allow-Origin"), Some(String::from("*")));
+    }
+}
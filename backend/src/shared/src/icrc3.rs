@@ -0,0 +1,214 @@
+use std::borrow::Cow;
+
+use candid::{CandidType, Int, Nat, Principal};
+use ic_e8s::c::E8s;
+use ic_certified_map::{fork, labeled, HashTree};
+use num_bigint::{BigInt, BigUint};
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+
+pub const MAX_BLOCKS_PER_REQUEST: u64 = 100;
+
+// ICRC-3 generic value, blocks are maps of these
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum Icrc3Value {
+    Blob(Vec<u8>),
+    Text(String),
+    Nat(Nat),
+    Int(Int),
+    Array(Vec<Icrc3Value>),
+    Map(Vec<(String, Icrc3Value)>),
+}
+
+impl Icrc3Value {
+    pub fn text(text: &str) -> Self {
+        Icrc3Value::Text(text.to_string())
+    }
+
+    pub fn nat<T: Into<Nat>>(nat: T) -> Self {
+        Icrc3Value::Nat(nat.into())
+    }
+
+    pub fn e8s(qty: &E8s) -> Self {
+        Icrc3Value::Nat(Nat(qty.val.clone()))
+    }
+
+    // accounts are encoded as [owner, subaccount?]
+    pub fn account(owner: &Principal) -> Self {
+        Icrc3Value::Array(vec![Icrc3Value::Blob(owner.as_slice().to_vec())])
+    }
+
+    // representation-independent hash, as required by ICRC-3
+    pub fn hash(&self) -> [u8; 32] {
+        let mut hasher = sha2::Sha256::default();
+
+        match self {
+            Icrc3Value::Blob(bytes) => hasher.update(bytes),
+            Icrc3Value::Text(text) => hasher.update(text.as_bytes()),
+            Icrc3Value::Nat(nat) => hasher.update(leb128(&nat.0)),
+            Icrc3Value::Int(int) => hasher.update(sleb128(&int.0)),
+            Icrc3Value::Array(items) => {
+                for item in items {
+                    hasher.update(item.hash());
+                }
+            }
+            Icrc3Value::Map(entries) => {
+                let mut pairs: Vec<Vec<u8>> = entries
+                    .iter()
+                    .map(|(key, value)| {
+                        let mut pair = Icrc3Value::text(key).hash().to_vec();
+                        pair.extend_from_slice(&value.hash());
+                        pair
+                    })
+                    .collect();
+                pairs.sort();
+
+                for pair in pairs {
+                    hasher.update(pair);
+                }
+            }
+        }
+
+        hasher.finalize().into()
+    }
+}
+
+fn leb128(nat: &BigUint) -> Vec<u8> {
+    let mut n = nat.clone();
+    let mut buf = Vec::new();
+
+    loop {
+        let byte = u8::try_from(&(&n & BigUint::from(0x7fu8))).unwrap();
+        n >>= 7;
+
+        if n == BigUint::from(0u8) {
+            buf.push(byte);
+            return buf;
+        }
+        buf.push(byte | 0x80);
+    }
+}
+
+fn sleb128(int: &BigInt) -> Vec<u8> {
+    let mut n = int.clone();
+    let mut buf = Vec::new();
+
+    loop {
+        let byte = u8::try_from(&(&n & BigInt::from(0x7f))).unwrap();
+        n >>= 7;
+
+        let sign_bit_clear = byte & 0x40 == 0;
+        if (n == BigInt::from(0) && sign_bit_clear) || (n == BigInt::from(-1) && !sign_bit_clear) {
+            buf.push(byte);
+            return buf;
+        }
+        buf.push(byte | 0x80);
+    }
+}
+
+// the tip tree holds the last block index and hash under the labels ICRC-3 clients look up,
+// an empty log has no tip
+pub fn tip_hash_tree(tip: Option<(u64, [u8; 32])>) -> HashTree<'static> {
+    let Some((last_block_index, last_block_hash)) = tip else {
+        return HashTree::Empty;
+    };
+
+    fork(
+        labeled(b"last_block_hash", HashTree::Leaf(Cow::Owned(last_block_hash.to_vec()))),
+        labeled(b"last_block_index", HashTree::Leaf(Cow::Owned(leb128(&BigUint::from(last_block_index))))),
+    )
+}
+
+pub fn encode_hash_tree(tree: &HashTree) -> Vec<u8> {
+    let mut serializer = serde_cbor::Serializer::new(Vec::new());
+    serializer.self_describe().expect("Unable to encode the hash tree");
+    tree.serialize(&mut serializer).expect("Unable to encode the hash tree");
+
+    serializer.into_inner()
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GetBlocksArgs {
+    pub start: Nat,
+    pub length: Nat,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct BlockWithId {
+    pub id: Nat,
+    pub block: Icrc3Value,
+}
+
+candid::define_function!(pub GetBlocksFn : (Vec<GetBlocksArgs>) -> (GetBlocksResult) query);
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ArchivedBlocks {
+    pub args: Vec<GetBlocksArgs>,
+    pub callback: GetBlocksFn,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GetBlocksResult {
+    pub log_length: Nat,
+    pub blocks: Vec<BlockWithId>,
+    pub archived_blocks: Vec<ArchivedBlocks>, // the log is never archived, always empty
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct DataCertificate {
+    pub certificate: Vec<u8>,
+    pub hash_tree: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GetArchivesArgs {
+    pub from: Option<Principal>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ArchiveInfo {
+    pub canister_id: Principal,
+    pub start: Nat,
+    pub end: Nat,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SupportedBlockType {
+    pub block_type: String,
+    pub url: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(hash: [u8; 32]) -> String {
+        hash.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    // reference values from the ICRC-3 specification
+    #[test]
+    fn hash_matches_the_icrc3_examples() {
+        assert_eq!(hex(Icrc3Value::nat(42u64).hash()), "684888c0ebb17f374298b65ee2807526c066094c701bcc7ebbe1c1095f494fc1");
+        assert_eq!(hex(Icrc3Value::Int(Int::from(-42)).hash()), "de5a6f78116eca62d7fc5ce159d23ae6b889b365a1739ad2cf36f925a140d0cc");
+        assert_eq!(hex(Icrc3Value::text("Hello, World!").hash()), "dffd6021bb2bd5b0af676290809ec3a53191dd81c7f70a4b28688a362182986f");
+        assert_eq!(hex(Icrc3Value::Blob(vec![1, 2, 3, 4]).hash()), "9f64a747e1b97f131fabb6b447296c9b6f0201e79fb3c5356e6c77e89b6a806a");
+        assert_eq!(
+            hex(Icrc3Value::Array(vec![Icrc3Value::nat(3u64), Icrc3Value::text("foo"), Icrc3Value::Blob(vec![5, 6])]).hash()),
+            "514a04011caa503990d446b7dec5d79e19c221ae607fb08b2848c67734d468d6",
+        );
+    }
+
+    #[test]
+    fn map_hash_ignores_the_entry_order() {
+        let entries = vec![
+            (String::from("btype"), Icrc3Value::text("stl_bet")),
+            (String::from("amt"), Icrc3Value::nat(42u64)),
+        ];
+        let reversed = entries.iter().rev().cloned().collect();
+        let expected = "a3afecaec30710e8dc85005629d7149ae90323df2e5577b90002b4302ce9d355";
+
+        assert_eq!(hex(Icrc3Value::Map(entries).hash()), expected);
+        assert_eq!(hex(Icrc3Value::Map(reversed).hash()), expected);
+    }
+}
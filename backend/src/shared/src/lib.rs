@@ -15,8 +15,12 @@ pub mod cmc;
 //pub mod decideid;
 mod env;
 pub mod icrc1;
+pub mod icrc3;
+pub mod evm;
+pub mod http;
 
 pub const ICP_FEE: u64 = 10_000u64;
+pub const SATSLINK_FEE: u64 = 10_000u64;
 pub const CYCLES_SATSLINKER_FEE: u128 = 10_000_000_000_u128;
 pub const MIN_ICP_STAKE_E8S_U64: u64 = 50_000_000;
 pub const MIN_STL_LOTTERY_E8S_U64: u64 = 100_000_000;
@@ -82,3 +86,9 @@ impl EnvVarsState {
         }
     }
 }
+
+impl Default for EnvVarsState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -6,6 +6,9 @@ use serde::Deserialize;
 use super::types::TCycles;
 use super::types::Timestamp;
 use super::types::Address;
+use super::types::{EarlyUnpledgeQuote, PledgeLockPeriod, PledgeLot, SwapDirection, SwapQuote, TopUpRecord, LotteryBet, LotteryDraw, BeaconRound, DevFeeToken, DevFeeWithdrawal, DevFeeAuditEntry};
+use super::types::{AccountTransfer, RedistributionCarry, RedistributionConfig, RedistributionRecord};
+use super::types::{LogEntry, LogLevel, NetworkProfile, TimerJobHealth};
 
 #[derive(CandidType, Deserialize)]
 pub struct GetVIPuserResponse {
@@ -29,6 +32,10 @@ pub struct GetTotalsResponse {
     pub total_vip_participants: u64,
     pub icp_to_cycles_exchange_rate: TCycles,
 
+    pub canister_cycles_balance: u128,
+    pub cycles_threshold: u128,
+    pub low_cycles_alert: bool,
+
     pub your_vip_shares: u64,
     pub your_vip_unclaimed_reward_e8s: E8s,
     pub your_vip_eligibility_status: bool,
@@ -80,19 +87,159 @@ pub struct LotteryRequest {
 }
 
 #[derive(CandidType, Deserialize)]
-pub struct LotteryResponse {}
+pub struct LotteryResponse {
+    pub result: Result<u64, String>, // id of the bet
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct BuyLotteryTicketsRequest {
+    pub count: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct BuyLotteryTicketsResponse {
+    pub result: Result<u64, String>, // id of the draw the tickets belong to
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetLotteryDrawsResponse {
+    pub current: Option<(u64, LotteryDraw)>,
+    pub pot: E8s,
+    pub ticket_price: E8s,
+    pub your_tickets: u64,
+    pub past: Vec<(u64, LotteryDraw)>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct VerifyLotteryDrawResponse {
+    pub draw: LotteryDraw,
+    pub beacon_round: Option<BeaconRound>,
+    pub is_valid: bool,
+    pub recomputed_winning_ticket: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetLotteryBetsResponse {
+    pub entries: Vec<(u64, LotteryBet)>,
+}
 
 #[derive(CandidType, Deserialize)]
 pub struct PledgeRequest {
     pub qty_e8s_u64: u64,
+    pub lock_period: Option<PledgeLockPeriod>, // defaults to one month
+    pub auto_compound: Option<bool>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct PledgeResponse {
+    pub result: Result<Nat, String>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetPledgeLotsResponse {
+    pub lots: Vec<(u64, PledgeLot)>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct SetPledgeAutoCompoundRequest {
+    pub lot_id: u64,
+    pub enabled: bool,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct PreviewEarlyUnpledgeRequest {
+    pub lot_id: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct PreviewEarlyUnpledgeResponse {
+    pub result: Result<EarlyUnpledgeQuote, String>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct UnpledgeEarlyRequest {
+    pub lot_id: u64,
+    pub to: Principal,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct UnpledgeEarlyResponse {
+    pub result: Result<Nat, String>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct SetEarlyUnpledgeConfigRequest {
+    pub max_penalty_bp: u64,
+    pub treasury_share_bp: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct QuoteSwapRequest {
+    pub direction: SwapDirection,
+    pub amount_in_e8s: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct QuoteSwapResponse {
+    pub result: Result<SwapQuote, String>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct SwapRequest {
+    pub direction: SwapDirection,
+    pub amount_in_e8s: u64,
+    pub min_amount_out_e8s: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct SwapResponse {
+    pub result: Result<Nat, String>,
 }
 
 #[derive(CandidType, Deserialize)]
-pub struct PledgeResponse {}
+pub struct AddLiquidityRequest {
+    pub icp_e8s: u64,
+    pub satslink_e8s: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct AddLiquidityResponse {
+    pub result: Result<E8s, String>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct RemoveLiquidityRequest {
+    pub shares: E8s,
+    pub min_icp_e8s: u64,
+    pub min_satslink_e8s: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct RemoveLiquidityResponse {
+    pub result: Result<(E8s, E8s), String>,
+}
 
+#[derive(CandidType, Deserialize)]
+pub struct ClaimLpPayoutResponse {
+    pub result: Result<Nat, String>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetSwapPoolResponse {
+    pub reserve_icp: E8s,
+    pub reserve_satslink: E8s,
+    pub lp_total_supply: E8s,
+    pub swap_fee_bp: u64,
+    pub your_lp_shares: E8s,
+    pub your_lp_payout: E8s, // SATSLINK of a removal that couldn't be sent yet
+}
+
+// SATSLINK -> ICP through the swap pool
 #[derive(CandidType, Deserialize)]
 pub struct RedeemRequest {
     pub to: Principal,
+    pub qty_e8s_u64: u64,
+    pub min_icp_out_e8s_u64: u64,
 }
 
 #[derive(CandidType, Deserialize)]
@@ -107,7 +254,69 @@ pub struct WithdrawRequest {
 }
 
 #[derive(CandidType, Deserialize)]
-pub struct WithdrawResponse {}
+pub struct WithdrawResponse {
+    pub result: Result<Nat, String>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct TopUpCanisterRequest {
+    pub canister_id: Principal,
+    pub qty_e8s_u64: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct TopUpCanisterResponse {
+    pub result: Result<u64, String>, // id of the top up record
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetTopUpsResponse {
+    pub entries: Vec<(u64, TopUpRecord)>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct SetCyclesMonitorConfigRequest {
+    pub threshold: u128,
+    pub top_up_e8s: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct AnnounceDevFeeWithdrawalRequest {
+    pub token: DevFeeToken,
+    pub qty_e8s_u64: u64,
+    pub to: Principal,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct AnnounceDevFeeWithdrawalResponse {
+    pub result: Result<u64, String>, // id of the withdrawal
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ConfirmDevFeeWithdrawalResponse {
+    pub result: Result<Nat, String>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetDevFeeWithdrawalsResponse {
+    pub owner: Option<Principal>,
+    pub pending_owner: Option<(Principal, Timestamp)>, // (owner, accepted not before)
+    pub timelock_ns: u64,
+    pub pending_timelock: Option<(u64, Timestamp)>, // (timelock_ns, effective_at)
+    pub withdrawals: Vec<(u64, DevFeeWithdrawal)>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetDevFeeAuditLogResponse {
+    pub entries: Vec<(u64, DevFeeAuditEntry)>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetRedistributionsResponse {
+    pub config: RedistributionConfig,
+    pub carry: RedistributionCarry,
+    pub records: Vec<(u64, RedistributionRecord)>,
+}
 
 #[derive(CandidType, Deserialize)]
 pub struct MigrateAccountRequest {
@@ -115,4 +324,108 @@ pub struct MigrateAccountRequest {
 }
 
 #[derive(CandidType, Deserialize)]
-pub struct MigrateAccountResponse {}
+pub struct MigrateAccountResponse {
+    pub result: Result<(), String>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct AcceptAccountTransferRequest {
+    pub from: Principal,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetAccountTransfersResponse {
+    pub entries: Vec<(Principal, AccountTransfer)>, // (source, offer)
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetCertifiedVipStatusRequest {
+    pub eth_address: Option<String>, // takes precedence over the principal
+    pub principal: Option<Principal>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetCertifiedVipStatusResponse {
+    pub expiry: Timestamp, // 0 - no VIP
+    pub certificate: Vec<u8>,
+    pub witness: Vec<u8>, // CBOR hash tree, the value is a big-endian u64 under vip_eth/<address> or vip_principal/<principal>
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetAttestationSignerResponse {
+    pub result: Result<AttestationSigner, String>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct AttestationSigner {
+    pub eth_address: String,
+    pub public_key: Vec<u8>, // SEC1 compressed secp256k1
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct AttestVipStatusRequest {
+    pub eth_address: String,
+    pub chain_id: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct AttestVipStatusResponse {
+    pub result: Result<VipAttestation, String>,
+}
+
+// EIP-712 VipAttestation(address ethAddress,uint64 vipExpiresAt,uint64 nonce,uint256 chainId)
+// under the SatsLink/1 domain, `signature` is r || s || v and can be passed to ecrecover
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct VipAttestation {
+    pub eth_address: String,
+    pub vip_expires_at: u64, // unix seconds, comparable with block.timestamp
+    pub nonce: u64,
+    pub chain_id: u64,
+    pub digest: Vec<u8>,
+    pub signature: Vec<u8>,
+    pub signer: String,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetHealthResponse {
+    pub healthy: bool, // running, rounds not paused, enough cycles and every job scheduled or running
+    pub now: Timestamp,
+    pub is_stopped: bool,
+    pub reward_rounds_paused: bool,
+    pub low_cycles_alert: bool,
+    pub cycles_balance: u128,
+    pub jobs: Vec<TimerJobHealth>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetLogsRequest {
+    pub min_level: Option<LogLevel>,
+    pub subsystem: Option<String>,
+    pub from: Option<Timestamp>,
+    pub to: Option<Timestamp>,
+    pub before_id: Option<u64>, // the `next_before_id` of the previous page, None - the newest entries
+    pub limit: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetLogsResponse {
+    pub entries: Vec<(u64, LogEntry)>, // newest first
+    pub next_before_id: Option<u64>,   // None - no older entries match
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetPosRoundScheduleResponse {
+    pub genesis_at: Option<Timestamp>, // None - no round has run yet
+    pub round_delay_ns: u64,
+    pub expected_rounds: u64,  // due by now
+    pub processed_rounds: u64, // current_pos_round
+    pub missed_rounds: u64,
+    pub next_round_at: Option<Timestamp>,
+    pub catch_up_batch: u64, // missed rounds processed per timer run
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct SatslinkerInitArgs {
+    pub network_profile: Option<NetworkProfile>, // None - the one of the build's CanisterMode, only read at install
+    pub dev_fee_owner: Option<Principal>,        // confirms dev fee withdrawals, can't be the dev
+}
@@ -1,32 +1,128 @@
-use std::collections::BTreeSet;
-use candid::{decode_one, encode_one, Principal};
-use ic_cdk::api::time;
-use ic_cdk::println;
+use std::collections::BTreeMap;
+
+use candid::{decode_one, encode_one, Nat, Principal};
+use ic_cdk::api::{canister_balance128, set_certified_data, time};
 use ic_e8s::c::{E8s, ECs};
+use ic_certified_map::{fork, labeled, AsHashTree, HashTree, RbTree};
 use ic_stable_structures::{storable::Bound, Cell, StableBTreeMap, Storable};
+use num_bigint::BigUint;
+
+use crate::{
+    icrc3::{tip_hash_tree, Icrc3Value},
+    ICP_FEE,
+};
 
 use super::{
     api::{
+        GetLogsRequest,
+        GetLogsResponse,
         GetVIPuserResponse, 
-        GetTotalsResponse
+        GetTotalsResponse,
+        VerifyLotteryDrawResponse,
+        VipAttestation,
     },
     types::{
         SatslinkerStateInfo, 
         Memory, 
-        TCycles, 
         Timestamp,
         Address,
-        TCYCLE_POS_ROUND_BASE_FEE,
-        PLEDGE_ROUND_DELAY_NS,
-        VIP_ROUND_DELAY_NS,
+        PledgeLockPeriod,
+        PledgeLot,
+        EarlyUnpledgeQuote,
+        EarlyUnpledgeReceipt,
+        SwapDirection,
+        SwapPoolInfo,
+        SwapQuote,
+        TopUpRecord,
+        TopUpRefund,
+        TopUpStatus,
+        TOP_UP_MAX_ATTEMPTS,
+        LotteryBet,
+        LotteryBetStatus,
+        LotteryDraw,
+        BeaconRound,
+        winning_idx_from_seed,
+        DevFeeToken,
+        DevFeeWithdrawal,
+        DevFeeWithdrawalStatus,
+        DevFeeAuditEvent,
+        DevFeeAuditEntry,
+        DEV_FEE_AUDIT_LOG_LIMIT,
+        RedistributionCarry,
+        RedistributionOutcome,
+        RedistributionRecord,
+        RedistributionTarget,
+        RedistributionTransfer,
+        REDISTRIBUTION_HISTORY_LIMIT,
+        ReserveLine,
+        ReserveToken,
+        ReservesReport,
+        InvariantReport,
+        InvariantViolation,
+        LogEntry,
+        LogLevel,
+        LOG_BUFFER_LIMIT,
+        MAX_LOGS_PER_REQUEST,
+        MAX_VIP_ATTESTATION_CHAINS,
+        POS_ACCOUNTS_PER_BATCH,
+        AccountTransfer,
+        ACCOUNT_TRANSFER_TTL_NS,
+        BLOCK_TYPE_BET,
+        BLOCK_TYPE_BET_RESULT,
+        BLOCK_TYPE_CLAIM,
+        BLOCK_TYPE_CLAIM_REVERT,
+        BLOCK_TYPE_PLEDGE,
+        BLOCK_TYPE_REDISTRIBUTE,
+        SATSLINKER_DEV_FEE_SUBACCOUNT,
+        SATSLINKER_LOTTERY_SUBACCOUNT,
+        SATSLINKER_PLEDGE_SUBACCOUNT,
+        SATSLINKER_REDISTRIBUTION_SUBACCOUNT,
+        SATSLINKER_SWAPPOOL_SUBACCOUNT,
+        SATSLINKER_TOPUP_SUBACCOUNT,
+        LOTTERY_DRAWS_HISTORY_LIMIT,
+        NetworkProfile,
     },
 };
 
 pub struct SatslinkerState {
+    // the canonical VIP store, only ever written through `put_vip_share` / `remove_vip_share`
     pub vip_shares: StableBTreeMap<Principal, (Address, Timestamp, E8s), Memory>,
+    // legacy single-position pledges, moved into `pledge_lots` by `migrate_legacy_pledge_shares`
     pub pledge_shares: StableBTreeMap<Principal, (E8s, Timestamp, E8s), Memory>, 
     pub info: Cell<SatslinkerStateInfo, Memory>,
+    // legacy copy of `vip_shares` keyed by address, emptied by `rebuild_vip_address_index`
     pub vip_participants: StableBTreeMap<Address, (Principal, Timestamp, E8s), Memory>,
+    pub pledge_lots: StableBTreeMap<(Principal, u64), PledgeLot, Memory>,
+    pub swap_pool: Cell<SwapPoolInfo, Memory>,
+    pub swap_lp_shares: StableBTreeMap<Principal, E8s, Memory>,
+    pub top_ups: StableBTreeMap<u64, TopUpRecord, Memory>,
+    pub lottery_bets: StableBTreeMap<u64, LotteryBet, Memory>,
+    pub lottery_draws: StableBTreeMap<u64, LotteryDraw, Memory>,
+    // (draw id, first ticket) -> (owner, tickets count)
+    pub lottery_tickets: StableBTreeMap<(u64, u64), (Principal, u64), Memory>,
+    pub beacon_rounds: StableBTreeMap<u64, BeaconRound, Memory>,
+    pub dev_fee_withdrawals: StableBTreeMap<u64, DevFeeWithdrawal, Memory>,
+    pub dev_fee_audit_log: StableBTreeMap<u64, DevFeeAuditEntry, Memory>,
+    pub redistributions: StableBTreeMap<u64, RedistributionRecord, Memory>,
+    pub reserves_report: Cell<ReservesReport, Memory>,
+    // source account -> pending offer
+    pub account_transfers: StableBTreeMap<Principal, AccountTransfer, Memory>,
+    // (address, owner) -> (), secondary index over `vip_shares`
+    pub vip_address_index: StableBTreeMap<(Address, Principal), (), Memory>,
+    pub invariant_report: Cell<InvariantReport, Memory>,
+    // append-only, every block carries the hash of the previous one
+    pub blocks: StableBTreeMap<u64, Icrc3Value, Memory>,
+    // certified VIP expiry (big-endian u64 nanoseconds), kept on the heap and rebuilt from the payments on upgrade
+    pub certified_vip_by_eth: RbTree<String, Vec<u8>>,
+    pub certified_vip_by_principal: RbTree<Principal, Vec<u8>>,
+    // (address, chain id) -> the last signed attestation, on the heap since signing again after an upgrade is fine
+    pub vip_attestations: BTreeMap<(Address, u64), VipAttestation>,
+    // ring buffer of the last LOG_BUFFER_LIMIT entries, ids keep growing
+    pub logs: StableBTreeMap<u64, LogEntry, Memory>,
+    // owner -> start of the current VIP position, positions without an entry predate it
+    pub vip_started_at: StableBTreeMap<Principal, Timestamp, Memory>,
+    // provider -> SATSLINK of a liquidity removal that couldn't be sent, still in the swap pool subaccount
+    pub swap_lp_payouts: StableBTreeMap<Principal, E8s, Memory>,
 }
 
 impl SatslinkerState {
@@ -37,108 +133,370 @@ impl SatslinkerState {
         self.set_info(info);
     }
 
-    // TODO: delete this function, once the initialization is complete
-    pub fn init_tmp_can_migrate(&mut self) {
-        let mut info = self.get_info();
-        // Only execute on the first call
-        if info.tmp_can_vip_migrate.is_none() {
-            let can_migrate_vip_set: BTreeSet<_> = self.vip_shares.iter().map(|(k, _)| k.clone()).collect();
-            info.tmp_can_vip_migrate = Some(can_migrate_vip_set);
+    pub fn append_block(&mut self, btype: &str, tx: Vec<(String, Icrc3Value)>, now: Timestamp) -> u64 {
+        let id = self.blocks.len();
+
+        let mut block = vec![
+            (String::from("btype"), Icrc3Value::text(btype)),
+            (String::from("ts"), Icrc3Value::nat(now)),
+        ];
+        if let Some((_, phash)) = self.get_block_tip() {
+            block.push((String::from("phash"), Icrc3Value::Blob(phash.to_vec())));
         }
-        
-        if info.tmp_can_pledge_migrate.is_none() {
-            let can_migrate_pledge_set: BTreeSet<_> = self.pledge_shares.iter().map(|(k, _)| k.clone()).collect();
-            info.tmp_can_pledge_migrate = Some(can_migrate_pledge_set);
+        block.push((String::from("tx"), Icrc3Value::Map(tx)));
+
+        self.blocks.insert(id, Icrc3Value::Map(block));
+        self.certify();
+
+        id
+    }
+
+    // (last block index, last block hash)
+    pub fn get_block_tip(&self) -> Option<(u64, [u8; 32])> {
+        self.blocks
+            .last_key_value()
+            .map(|(id, block)| (id, block.hash()))
+    }
+
+    // The certified root is fork(block tip, fork(vip_eth, vip_principal)), so the labels stay sorted.
+    // Certified data doesn't survive upgrades, so this is also called from post_upgrade.
+    pub fn certify(&self) {
+        let tree = fork(tip_hash_tree(self.get_block_tip()), self.vip_hash_tree(None, None));
+
+        // there is nothing to certify outside of a canister, e.g. in unit tests
+        if cfg!(target_arch = "wasm32") {
+            set_certified_data(&tree.reconstruct());
         }
+    }
 
-        self.set_info(info);
+    // the whole certified tree with everything except the block tip pruned
+    pub fn block_tip_witness(&self) -> HashTree<'_> {
+        fork(
+            tip_hash_tree(self.get_block_tip()),
+            HashTree::Pruned(self.vip_hash_tree(None, None).reconstruct()),
+        )
     }
 
-    pub fn migrate_satslinker_account(&mut self, caller: &Principal, to: Principal,) -> Result<(), String> {
-        let mut info = self.get_info();
+    // without keys both subtrees are pruned down to their root hashes
+    fn vip_hash_tree<'a>(&'a self, eth_address: Option<&'a str>, principal: Option<&'a Principal>) -> HashTree<'a> {
+        let by_eth = match eth_address {
+            Some(eth_address) => self.certified_vip_by_eth.witness(eth_address.as_bytes()),
+            None => HashTree::Pruned(self.certified_vip_by_eth.root_hash()),
+        };
+        let by_principal = match principal {
+            Some(principal) => self.certified_vip_by_principal.witness(principal.as_slice()),
+            None => HashTree::Pruned(self.certified_vip_by_principal.root_hash()),
+        };
+
+        fork(labeled(b"vip_eth", by_eth), labeled(b"vip_principal", by_principal))
+    }
+
+    // rebuilt as a whole, expired payments are dropped by the hourly cleanup
+    pub fn reset_certified_vip_expiry(&mut self, by_eth: Vec<(String, Timestamp)>, by_principal: Vec<(Principal, Timestamp)>) {
+        self.certified_vip_by_eth = RbTree::new();
+        self.certified_vip_by_principal = RbTree::new();
+
+        for (eth_address, expiry) in by_eth {
+            self.certified_vip_by_eth.insert(eth_address, expiry.to_be_bytes().to_vec());
+        }
+        for (principal, expiry) in by_principal {
+            self.certified_vip_by_principal.insert(principal, expiry.to_be_bytes().to_vec());
+        }
+
+        self.certify();
+    }
+
+    pub fn get_certified_vip_expiry(&self, eth_address: Option<&str>, principal: Option<&Principal>) -> Timestamp {
+        let value = match (eth_address, principal) {
+            (Some(eth_address), _) => self.certified_vip_by_eth.get(eth_address.as_bytes()),
+            (None, Some(principal)) => self.certified_vip_by_principal.get(principal.as_slice()),
+            (None, None) => None,
+        };
+
+        value
+            .and_then(|bytes| <[u8; 8]>::try_from(bytes.as_slice()).ok())
+            .map(u64::from_be_bytes)
+            .unwrap_or_default()
+    }
+
+    // Signing costs cycles, so an address gets one attestation per VIP expiry and chain, for a few chains at most.
+    // Returns the attestation signed earlier for the same expiry, if any.
+    pub fn get_vip_attestation(&self, address: &Address, chain_id: u64, vip_expires_at: u64) -> Result<Option<VipAttestation>, String> {
+        if let Some(attestation) = self.vip_attestations.get(&(*address, chain_id)) {
+            if attestation.vip_expires_at == vip_expires_at {
+                return Ok(Some(attestation.clone()));
+            }
+        }
 
-        if !info.can_vip_migrate(caller) || !info.can_pledge_migrate(caller) {
+        let signed_chains = self.vip_attestations
+            .range((*address, 0)..=(*address, u64::MAX))
+            .filter(|(_, attestation)| attestation.vip_expires_at == vip_expires_at)
+            .count();
+        if signed_chains >= MAX_VIP_ATTESTATION_CHAINS {
+            return Err(format!("The VIP status of the address is already attested for {} chains", signed_chains));
+        }
+
+        Ok(None)
+    }
+
+    pub fn store_vip_attestation(&mut self, address: Address, attestation: VipAttestation) {
+        self.vip_attestations.insert((address, attestation.chain_id), attestation);
+    }
+
+    // the whole certified tree with only the requested VIP entry revealed
+    pub fn vip_status_witness<'a>(&'a self, eth_address: Option<&'a str>, principal: Option<&'a Principal>) -> HashTree<'a> {
+        fork(
+            HashTree::Pruned(tip_hash_tree(self.get_block_tip()).reconstruct()),
+            self.vip_hash_tree(eth_address, principal),
+        )
+    }
+
+    pub fn get_blocks(&self, start: u64, length: u64) -> Vec<(u64, Icrc3Value)> {
+        self.blocks
+            .range(start..start.saturating_add(length))
+            .collect()
+    }
+
+    // The source offers the account, nothing moves until the destination accepts
+    pub fn initiate_account_transfer(&mut self, from: Principal, to: Principal, now: Timestamp) -> Result<AccountTransfer, String> {
+        if from == to || to == Principal::anonymous() {
+            return Err(String::from("Invalid destination account"));
+        }
+
+        if !self.has_account_positions(&from) {
+            return Err(String::from("Nothing to transfer"));
+        }
+        self.check_account_settled(&from)?;
+
+        let transfer = AccountTransfer {
+            to,
+            created_at: now,
+            expires_at: now + ACCOUNT_TRANSFER_TTL_NS,
+        };
+        // a new offer replaces the previous one
+        self.account_transfers.insert(from, transfer.clone());
+
+        Ok(transfer)
+    }
+
+    pub fn cancel_account_transfer(&mut self, from: &Principal) -> Result<(), String> {
+        self.account_transfers
+            .remove(from)
+            .map(|_| ())
+            .ok_or(String::from("No pending account transfer"))
+    }
+
+    pub fn accept_account_transfer(&mut self, caller: Principal, from: Principal, now: Timestamp) -> Result<(), String> {
+        let transfer = self.account_transfers
+            .get(&from)
+            .ok_or(String::from("No pending account transfer"))?;
+
+        if transfer.to != caller {
             return Err(String::from("Access denied"));
         }
 
-         // Handle VIP share migration
-        if let Some((address_1, share_1, reward_1)) = self.vip_shares.remove(caller) {
-            let (address, share, reward) = if let Some((address_2, share_2, reward_2)) = self.vip_shares.get(&to) {
-                (address_2.clone(), share_1 + share_2, reward_1 + reward_2)
-            } else {
-                (address_1, share_1, reward_1)
-            };
+        self.account_transfers.remove(&from);
 
-            self.vip_shares.insert(to, (address, share, reward));
-            info.note_vip_migrated(caller);
-        } 
+        if now > transfer.expires_at {
+            return Err(String::from("The account transfer has expired"));
+        }
 
-        // Handle pledge share migration
-        if let Some((share_1, tmps_1, reward_1)) = self.pledge_shares.remove(caller) {
-            let (share_2, tmps_2, reward_2) = self.pledge_shares
-                .get(&to)
-                .map(|(s, t, r)| (s.clone(), t.clone(), r.clone()))
-                .unwrap_or((E8s::zero(), 0u64, E8s::zero()));
-            let (share, tmps, reward) = (share_1 + share_2, tmps_1 + tmps_2, reward_1 + reward_2);
-            let fee = TCycles::from(TCYCLE_POS_ROUND_BASE_FEE)
-                .to_dynamic()
-                .to_decimals(8)
-                .to_const::<8>();
-
-            if share.clone() > fee {
-                self.pledge_shares.insert(to, (share.clone(), tmps, reward));
-                info.note_pledge_migrated(caller);     
-            }   
-        } 
+        // the offer stays open, so it can be accepted once the source is settled
+        if let Err(e) = self.check_account_settled(&from) {
+            self.account_transfers.insert(from, transfer);
+            return Err(e);
+        }
+
+        self.move_account(from, caller, now);
+
+        Ok(())
+    }
+
+    // pending transfers where `account` is either side
+    pub fn get_account_transfers_of(&self, account: &Principal) -> Vec<(Principal, AccountTransfer)> {
+        self.account_transfers
+            .iter()
+            .filter(|(from, transfer)| from == account || transfer.to == *account)
+            .collect()
+    }
+
+    fn has_account_positions(&self, account: &Principal) -> bool {
+        self.vip_shares.contains_key(account)
+            || !self.get_pledge_lots(account).is_empty()
+            || self.get_lp_shares(account) > E8s::zero()
+            || self.get_lp_payout(account) > E8s::zero()
+            || !self.get_open_lottery_tickets_of(account).is_empty()
+    }
+
+    // Bets, top ups and won prizes in flight are paid to the principal that made them,
+    // so an account with any of them can't be moved until they are settled
+    fn check_account_settled(&self, account: &Principal) -> Result<(), String> {
+        if self.lottery_bets
+            .iter()
+            .any(|(_, bet)| bet.player == *account && matches!(bet.status, LotteryBetStatus::Pending | LotteryBetStatus::Won { .. }))
+        {
+            return Err(String::from("Wait until your lottery bets are settled"));
+        }
+
+        if self.top_ups.iter().any(|(_, record)| record.from == *account && !record.is_final()) {
+            return Err(String::from("Wait until your top ups are settled"));
+        }
+
+        if self.lottery_draws.iter().any(|(_, draw)| draw.is_unpaid() && draw.winner == Some(*account)) {
+            return Err(String::from("Wait until your lottery prize is paid"));
+        }
 
-        self.set_info(info);
         Ok(())
+    }
+
+    // tickets of the draw that hasn't been drawn yet, keyed as in `lottery_tickets`
+    fn get_open_lottery_tickets_of(&self, owner: &Principal) -> Vec<((u64, u64), u64)> {
+        let Some((draw_id, draw)) = self.get_current_lottery_draw() else {
+            return Vec::new();
+        };
+        if draw.drawn_at.is_some() {
+            return Vec::new();
+        }
+
+        self.lottery_tickets
+            .range((draw_id, 0)..(draw_id + 1, 0))
+            .filter(|(_, (ticket_owner, _))| ticket_owner == owner)
+            .map(|(key, (_, count))| (key, count))
+            .collect()
+    }
+
+    // Moves VIP time, pledge lots and their unclaimed rewards, LP shares, owed LP payouts and the tickets
+    // of the open draw in one go. Lots keep their ids, start times and lockups, so nothing is merged
+    // and nothing can fall below the fee.
+    fn move_account(&mut self, from: Principal, to: Principal, now: Timestamp) {
+        let from_started_at = self.vip_started_at.get(&from);
+
+        if let Some((from_address, from_expiry, from_reward)) = self.remove_vip_share(&from) {
+            let now_vip = now / self.vip_unit_ns();
+            let (address, expiry, reward) = match self.vip_shares.get(&to) {
+                // the destination keeps its address and gets the remaining VIP time on top
+                Some((to_address, to_expiry, to_reward)) => (
+                    to_address,
+                    to_expiry.max(now_vip) + from_expiry.saturating_sub(now_vip),
+                    to_reward + from_reward,
+                ),
+                None => {
+                    if let Some(started_at) = from_started_at {
+                        self.vip_started_at.insert(to, started_at);
+                    }
+
+                    (from_address, from_expiry, from_reward)
+                }
+            };
+
+            self.put_vip_share(to, address, expiry, reward);
+        }
+
+        for (lot_id, lot) in self.get_pledge_lots(&from) {
+            self.pledge_lots.remove(&(from, lot_id));
+            self.pledge_lots.insert((to, lot_id), lot);
+        }
+
+        if let Some(shares) = self.swap_lp_shares.remove(&from) {
+            let to_shares = self.get_lp_shares(&to) + shares;
+            self.swap_lp_shares.insert(to, to_shares);
+        }
+
+        if let Some(owed) = self.swap_lp_payouts.remove(&from) {
+            self.owe_lp_payout(to, owed);
+        }
+
+        // tickets keep their numbers, only the owner changes
+        for (key, count) in self.get_open_lottery_tickets_of(&from) {
+            self.lottery_tickets.insert(key, (to, count));
+        }
+    }
 
+    // Every VIP write goes through here, so the address index can't drift from the store
+    pub fn put_vip_share(&mut self, owner: Principal, address: Address, expiry: Timestamp, reward: E8s) {
+        if let Some((prev_address, _, _)) = self.vip_shares.insert(owner, (address, expiry, reward)) {
+            if prev_address != address {
+                self.vip_address_index.remove(&(prev_address, owner));
+            }
+        }
+        self.vip_address_index.insert((address, owner), ());
+    }
+
+    pub fn remove_vip_share(&mut self, owner: &Principal) -> Option<(Address, Timestamp, E8s)> {
+        let removed = self.vip_shares.remove(owner);
+        if let Some((address, _, _)) = &removed {
+            self.vip_address_index.remove(&(*address, *owner));
+        }
+        self.vip_started_at.remove(owner);
+
+        removed
+    }
+
+    // One-time migration: drops the legacy `vip_participants` copy and rebuilds the index from `vip_shares`
+    pub fn rebuild_vip_address_index(&mut self, by: Principal, now: Timestamp) {
+        if self.vip_participants.is_empty() && self.vip_address_index.len() == self.vip_shares.len() {
+            return;
+        }
+
+        let legacy: Vec<_> = self.vip_participants.iter().map(|(address, _)| address).collect();
+        for address in legacy {
+            self.vip_participants.remove(&address);
+        }
+
+        let stale: Vec<_> = self.vip_address_index.iter().map(|(key, _)| key).collect();
+        for key in stale {
+            self.vip_address_index.remove(&key);
+        }
+
+        let entries: Vec<_> = self.vip_shares.iter().map(|(owner, (address, _, _))| (address, owner)).collect();
+        for key in entries {
+            self.vip_address_index.insert(key, ());
+        }
+
+        let entries = self.vip_address_index.len();
+        self.log(LogLevel::Info, "vip", format!("VIP address index rebuilt: {} entries", entries), by, now);
     }
 
-    pub fn mint_vip_share(&mut self, tmps: Timestamp, to: Principal, address: Address) {
+    pub fn mint_vip_share(&mut self, tmps: Timestamp, to: Principal, address: Address, now: Timestamp) {
         // add new share to the account
         let cur_opt = self.vip_shares.get(&to);
-        println!("mint vip share shares: {:?}", tmps);
-        
+
         let (address, share, unclaimed_reward) = if let Some((cur_address, mut cur_share, cur_unclaimed_reward)) = cur_opt {
             cur_share += &tmps;
-            (cur_address.clone(), cur_share, cur_unclaimed_reward.clone())
+            (cur_address, cur_share, cur_unclaimed_reward.clone())
         } else {
+            // rounds that were due before the position existed don't reward it, even when caught up later
+            self.vip_started_at.insert(to, now);
             (address, tmps, E8s::zero())
         };
 
-        self.vip_shares.insert(to, (address, share, unclaimed_reward.clone()));
-        self.vip_participants.insert(address, (to, share, unclaimed_reward.clone()));
-        println!("VIP shares: {:?} | unclarmed reward: {:?}", share, unclaimed_reward.clone());
+        self.log(LogLevel::Debug, "vip", format!("Minted {:?} VIP shares, now {:?} | unclaimed reward: {:?}", tmps, share, unclaimed_reward), to, now);
+        self.put_vip_share(to, address, share, unclaimed_reward);
     }
 
     pub fn claim_vip_reward(&mut self, caller: Principal) -> Option<E8s> {
-        let current_time = time() / VIP_ROUND_DELAY_NS; // Get current time
+        let current_time = time() / self.vip_unit_ns(); // Get current time
 
         if let Some((address, share, unclaimed_reward)) = self.vip_shares.get(&caller) {
             let mut info = self.get_info();
             // Check if VIP time has expired
             if current_time >= share {
-                // Only remove if user is in participant list
-                if self.vip_participants.contains_key(&address) {
-                    self.vip_participants.remove(&address);
-                }
-                self.vip_shares.remove(&caller); 
+                self.remove_vip_share(&caller);
                 // If there are unclaimed rewards
                 if unclaimed_reward > E8s::zero() {
-                    info.total_token_minted += &unclaimed_reward;
+                    info.note_minted_reward(&unclaimed_reward);
                     self.set_info(info);           
+                    self.log_claim(BLOCK_TYPE_CLAIM, caller, &unclaimed_reward, "vip");
                     return Some(unclaimed_reward);
                 } 
             } else {
                 // If VIP time hasn't expired, return original unclaimed rewards
-                info.total_token_minted += &unclaimed_reward;
+                info.note_minted_reward(&unclaimed_reward);
                 self.set_info(info);
 
                 // Reset unclaimed rewards
-                self.vip_shares.insert(caller, (address, share, E8s::zero()));
-                self.vip_participants.insert(address, (caller, share, E8s::zero()));
+                self.put_vip_share(caller, address, share, E8s::zero());
+                self.log_claim(BLOCK_TYPE_CLAIM, caller, &unclaimed_reward, "vip");
                 return Some(unclaimed_reward);
             }
         }
@@ -148,306 +506,2916 @@ impl SatslinkerState {
 
     pub fn revert_claim_vip_reward(&mut self, caller: Principal, unclaimed_reward: E8s) {
         let mut info = self.get_info();
-        info.total_token_minted -= &unclaimed_reward;
+        info.revert_minted_reward(&unclaimed_reward);
         self.set_info(info);
+        self.log_claim(BLOCK_TYPE_CLAIM_REVERT, caller, &unclaimed_reward, "vip");
 
         if let Some((address, share, reward)) = self.vip_shares.get(&caller) {
             let new_rewards = reward + unclaimed_reward;
-            self.vip_shares.insert(caller, (address, share, new_rewards.clone()));
-            // Only insert if user is not in participant list
-            if !self.vip_participants.contains_key(&address) {
-                self.vip_participants.insert(address, (caller, share, new_rewards.clone()));
-            }
+            self.put_vip_share(caller, address, share, new_rewards);
         } 
     }
 
-    pub fn mint_pledge_share(&mut self, qty: E8s, pledge_time: Timestamp, to: Principal) {
-        // Add new SATSLINK share to the account
-        let cur_opt = self.pledge_shares.get(&to);
+    // pledges made before lots were introduced become one-month lots, keeping their start time
+    pub fn migrate_legacy_pledge_shares(&mut self) {
+        let legacy_shares: Vec<_> = self.pledge_shares.iter().collect();
+        if legacy_shares.is_empty() {
+            return;
+        }
+
         let mut info = self.get_info();
-        let satslink_amount = qty.clone();
-    
-        // Update the user's SATSLINK share and unclaimed reward
-        let (satslink_share, pledge_satslink_time, unclaimed_reward) = 
-            if let Some((mut cur_satslink_share ,_, cur_unclaimed_reward)) = cur_opt
-            {
-                cur_satslink_share += qty; // Increase the user's SATSLINK share
-                (cur_satslink_share, pledge_time.clone(), cur_unclaimed_reward)
+        for (owner, (qty, started_at, unclaimed_reward)) in legacy_shares {
+            let lot_id = info.generate_pledge_lot_id();
+            self.pledge_lots.insert((owner, lot_id), PledgeLot {
+                qty,
+                started_at,
+                lock_period: PledgeLockPeriod::OneMonth,
+                unclaimed_reward,
+                auto_compound: None,
+                tickets_granted: None,
+            });
+            self.pledge_shares.remove(&owner);
+        }
+        self.set_info(info);
+    }
 
-            } else {
-                (qty.clone(), pledge_time.clone(), E8s::zero())
-            };
+    // Every pledge is a separate lot, so topping up never re-locks the balance pledged before
+    pub fn mint_pledge_lot(&mut self, qty: E8s, lock_period: PledgeLockPeriod, auto_compound: bool, now: Timestamp, to: Principal) -> u64 {
+        let mut info = self.get_info();
+        let lot_id = info.generate_pledge_lot_id();
 
+        // the tokens are already in custody at this point, so the lot is always recorded
+        self.pledge_lots.insert((to, lot_id), PledgeLot {
+            qty: qty.clone(),
+            started_at: now,
+            lock_period,
+            unclaimed_reward: E8s::zero(),
+            auto_compound: Some(auto_compound),
+            tickets_granted: Some(false),
+        });
+        // Update total pledged SATSLINK amount in SatslinkerStateInfo
+        info.total_pledge_token_supply += &qty;
+        self.set_info(info);
 
-        let fee = TCycles::from(TCYCLE_POS_ROUND_BASE_FEE)
-            .to_dynamic()
-            .to_decimals(8)
-            .to_const::<8>();
+        self.append_block(BLOCK_TYPE_PLEDGE, vec![
+            (String::from("from"), Icrc3Value::account(&to)),
+            (String::from("amt"), Icrc3Value::e8s(&qty)),
+            (String::from("lot"), Icrc3Value::nat(lot_id)),
+            (String::from("lock"), Icrc3Value::Text(format!("{:?}", lock_period))),
+        ], now);
 
-        if satslink_share >= fee {
-            // Update the user's SATSLINK shares
-            self.pledge_shares.insert(to, (satslink_share, pledge_satslink_time, unclaimed_reward));
-            // Update total pledged SATSLINK amount in SatslinkerStateInfo
-            info.total_pledge_token_supply += satslink_amount;// 调整总质押SATSLINK
-        }
-        self.set_info(info); 
+        lot_id
+    }
 
+    pub fn set_pledge_auto_compound(&mut self, caller: Principal, lot_id: u64, enabled: bool) -> Result<(), String> {
+        let mut lot = self.pledge_lots
+            .get(&(caller, lot_id))
+            .ok_or(String::from("Pledge lot not found"))?;
+
+        lot.auto_compound = Some(enabled);
+        self.pledge_lots.insert((caller, lot_id), lot);
+
+        Ok(())
     }
 
-    pub fn claim_pledge_reward(&mut self, caller: Principal) -> Option<E8s> {
-        let fee = TCycles::from(TCYCLE_POS_ROUND_BASE_FEE)
-            .to_dynamic()
-            .to_decimals(8)
-            .to_const::<8>();
-        // Get user's SATSLINK shares and unclaimed rewards
-        if let Some((satslink_share, pledge_satslink_time, unclaimed_reward)) = self.pledge_shares.get(&caller) {
-
-            if satslink_share < fee {
-                let mut info = self.get_info();
-                info.total_pledge_token_supply -= &satslink_share;
-                self.set_info(info);
-                self.pledge_shares.remove(&caller);
-            } else {
-                self.pledge_shares.insert(caller, (satslink_share, pledge_satslink_time, E8s::zero()));
-            }
+    // Lot ids are unique across accounts, but the owner changes with an account transfer.
+    // Scans every lot, only used when reverting a failed transfer.
+    pub fn find_pledge_lot(&self, lot_id: u64) -> Option<(Principal, PledgeLot)> {
+        self.pledge_lots
+            .iter()
+            .find(|((_, id), _)| *id == lot_id)
+            .map(|((owner, _), lot)| (owner, lot))
+    }
 
-            // Check if user has unclaimed rewards
-            if unclaimed_reward > E8s::zero() {
-                let mut info = self.get_info();
-                info.total_token_minted += &unclaimed_reward;
-                self.set_info(info);
-                // Return user's unclaimed rewards
-                return Some(unclaimed_reward);
-            } 
-        } 
-        None
+    pub fn get_pledge_lots(&self, owner: &Principal) -> Vec<(u64, PledgeLot)> {
+        self.pledge_lots
+            .range((*owner, 0u64)..=(*owner, u64::MAX))
+            .map(|((_, lot_id), lot)| (lot_id, lot))
+            .collect()
     }
 
-    pub fn revert_claim_pledge_reward(&mut self, caller: Principal, unclaimed_reward: E8s) {
-        let mut info = self.get_info();
-        info.total_token_minted -= &unclaimed_reward;
+    pub fn count_pledge_participants(&self) -> u64 {
+        let mut count = 0u64;
+        let mut last_owner = None;
 
-        if let Some((satslink_share, pledge_time, reward)) = self.pledge_shares.get(&caller) {
-            self.pledge_shares.insert(caller, (satslink_share, pledge_time, reward + unclaimed_reward));
-        } else {
-            self.pledge_shares.insert(caller, (E8s::zero(), 0u64, unclaimed_reward));
-            // If no staking record found, panic or execute other logic
-            panic!("No staking record found for caller: {:?}", caller);
+        // lots are ordered by owner, so each owner is a single run of keys
+        for ((owner, _), _) in self.pledge_lots.iter() {
+            if last_owner != Some(owner) {
+                count += 1;
+                last_owner = Some(owner);
+            }
         }
-        self.set_info(info);
+
+        count
     }
 
-    // dostribute rewards for vip users
-    pub fn distribute_vip_pos_rewards(&mut self) -> bool {
-        if self.vip_shares.is_empty() {
-            return true;
+    // Releases `qty` of the caller's unlocked pledge, taking from the oldest lots first.
+    // Returns the touched lots as they were, so the release can be reverted if the ledger transfer fails.
+    pub fn withdraw_pledge_lots(&mut self, caller: Principal, qty: E8s, now: Timestamp) -> Result<Vec<(u64, PledgeLot)>, String> {
+        // a lot withdrawn before the next round still gets the tickets it earned
+        self.grant_matured_pledge_tickets(now);
+
+        let pledge_period_ns = self.pledge_period_ns();
+        let unlocked_lots: Vec<_> = self
+            .get_pledge_lots(&caller)
+            .into_iter()
+            .filter(|(_, lot)| !lot.is_locked(now, pledge_period_ns) && lot.qty > E8s::zero())
+            .collect();
+
+        if unlocked_lots.is_empty() {
+            return Err(String::from("No unlocked pledge found"));
         }
-    
-        let info = self.get_info();
-        let mut cur_reward = info.current_token_reward.clone();
 
-        cur_reward *= ECs::<8>::from(500u64);
-        cur_reward /= ECs::<8>::from(1000u64);  // 50% = 500/1000
-        println!("Current VIP reward: {:?}", cur_reward);
-    
-        let current_time = ic_cdk::api::time() / VIP_ROUND_DELAY_NS;
-    
-        let mut accounts_to_remove = Vec::new();
-        let mut accounts_to_update = Vec::new();
-    
-        // **Step 1: Iterate through vip_shares and categorize**
-        for (account, (address, vip_time, unclaimed_reward)) in self.vip_shares.iter() {
-            if current_time >= vip_time && unclaimed_reward == ECs::<8>::zero() {
-                println!("Expired VIP user: {:?}", account);
-                accounts_to_remove.push(account.clone());
-            } else {
-                accounts_to_update.push((account.clone(), address.clone(), vip_time.clone(), unclaimed_reward.clone()));
-            }
+        let mut unlocked_total = E8s::zero();
+        for (_, lot) in unlocked_lots.iter() {
+            unlocked_total += &lot.qty;
         }
-    
-        // **Step 2: Remove expired accounts**
-        for account in accounts_to_remove {
-            self.vip_shares.remove(&account);
+
+        if qty > unlocked_total {
+            return Err(format!("Only {:?} of the pledge is unlocked", unlocked_total));
         }
-    
-        // Check number of valid accounts
-        let valid_account_count = accounts_to_update.len();
-        if valid_account_count == 0 {
-            return true;
+
+        // a lot is either withdrawn whole or keeps at least the minimum pledge, checked before anything changes
+        let min_pledge_share = SatslinkerStateInfo::get_min_pledge_share();
+        let mut remaining = qty.clone();
+        for (_, lot) in unlocked_lots.iter() {
+            if remaining == E8s::zero() {
+                break;
+            }
+            if lot.qty > remaining {
+                let mut left = lot.qty.clone();
+                left -= &remaining;
+                if left < min_pledge_share {
+                    return Err(format!("The withdrawal would leave less than {:?} in a pledge lot", min_pledge_share));
+                }
+                break;
+            }
+            remaining -= &lot.qty;
         }
-    
-        // **Step 3: Calculate rewards for each account**
-        let valid_account_count = valid_account_count as u128;
-        let reward_per_account = cur_reward.val / valid_account_count;
-        let new_reward = ECs::<8>::new(reward_per_account);
 
-        // **Step 4: Update rewards for valid accounts**
-        for (account, address, vip_time, unclaimed_reward) in accounts_to_update {
-            // Update account reward value
-            let updated_reward = unclaimed_reward + new_reward.clone();
-            self.vip_shares.insert(account.clone(), (address.clone(), vip_time.clone(), updated_reward.clone()));
-            println!("Account: {:?}, Expiration: {:?}, New reward: {:?}", account, vip_time, updated_reward);
+        let mut remaining = qty.clone();
+        let mut touched_lots = Vec::new();
+
+        for (lot_id, lot) in unlocked_lots {
+            if remaining == E8s::zero() {
+                break;
+            }
+
+            let taken = if lot.qty > remaining { remaining.clone() } else { lot.qty.clone() };
+            remaining -= &taken;
+
+            let mut updated_lot = lot.clone();
+            updated_lot.qty -= &taken;
+
+            if updated_lot.qty == E8s::zero() && updated_lot.unclaimed_reward == E8s::zero() {
+                self.pledge_lots.remove(&(caller, lot_id));
+            } else {
+                self.pledge_lots.insert((caller, lot_id), updated_lot);
+            }
+
+            touched_lots.push((lot_id, lot));
         }
-    
-        self.set_info(info);
-        true
-    } 
 
-    // Return true if the staking round has completed
-    pub fn distribute_pledge_rewards(&mut self) -> bool {
         let mut info = self.get_info();
-        let mut cur_reward = info.current_token_reward.clone();
-        cur_reward *= ECs::<8>::from(375u64);
-        cur_reward /= ECs::<8>::from(1000u64);  // 37.5% = 375/1000
-        ic_cdk::println!("Current pledge reward: {:?}", cur_reward);
+        info.total_pledge_token_supply -= &qty;
+        self.set_info(info);
 
-        if self.pledge_shares.len() == 0 {
-            return true;
+        Ok(touched_lots)
+    }
+
+    pub fn revert_withdraw_pledge_lots(&mut self, caller: Principal, qty: E8s, lots: Vec<(u64, PledgeLot)>) {
+        for (lot_id, lot) in lots {
+            // the lot could have moved to another account while the transfer was in flight
+            let (owner, unclaimed_reward) = match self.find_pledge_lot(lot_id) {
+                // unlocked lots don't earn, but their reward could have been claimed in the meantime
+                Some((owner, current)) => (owner, current.unclaimed_reward),
+                None => (caller, E8s::zero()),
+            };
+
+            self.pledge_lots.insert((owner, lot_id), PledgeLot { unclaimed_reward, ..lot });
         }
 
-        let current_time = ic_cdk::api::time() / VIP_ROUND_DELAY_NS;
-        let mut accounts_to_update = Vec::new();
-        let mut valid_shares_total = E8s::zero();
-
-        // First pass: Collect expired accounts and calculate total valid pledge
-        let mut expired_accounts = Vec::new();
-        for (account, (share, pledge_time, _)) in self.pledge_shares.iter() {
-            let pledge_expiration_time = pledge_time + PLEDGE_ROUND_DELAY_NS;
-            if current_time >= pledge_expiration_time {
-                expired_accounts.push(account.clone());
-            } else {
-                valid_shares_total += &share;
+        let mut info = self.get_info();
+        info.total_pledge_token_supply += &qty;
+        self.set_info(info);
+    }
+
+    pub fn claim_pledge_reward(&mut self, caller: Principal) -> Option<E8s> {
+        let mut unclaimed_reward = E8s::zero();
+
+        // Collect rewards of every lot of the caller
+        for (lot_id, mut lot) in self.get_pledge_lots(&caller) {
+            if lot.unclaimed_reward == E8s::zero() {
+                continue;
             }
-        }
 
-        // Second pass: Process rewards for non-expired accounts
-        for (account, (share, pledge_time, unclaimed_reward)) in self.pledge_shares.iter() {
-            if !expired_accounts.contains(&account) {
-                let new_reward = if valid_shares_total > E8s::zero() {
-                    &cur_reward * &share / &valid_shares_total
-                } else {
-                    E8s::zero()
-                };
-                ic_cdk::println!("Account {:?} received pledge reward: {:?}", account, new_reward);
-                let updated_reward = unclaimed_reward + &new_reward;
-                accounts_to_update.push((
-                    account.clone(),
-                    (share.clone(), pledge_time, updated_reward)
-                ));
+            unclaimed_reward += &lot.unclaimed_reward;
+            lot.unclaimed_reward = E8s::zero();
+
+            // the principal stays in custody until withdrawn, only fully withdrawn lots are dropped
+            if lot.qty == E8s::zero() {
+                self.pledge_lots.remove(&(caller, lot_id));
+            } else {
+                self.pledge_lots.insert((caller, lot_id), lot);
             }
         }
 
-        // Update rewards for non-expired accounts
-        for (account, entry) in accounts_to_update {
-            self.pledge_shares.insert(account, entry);
+        // Check if user has unclaimed rewards
+        if unclaimed_reward > E8s::zero() {
+            let mut info = self.get_info();
+            info.note_minted_reward(&unclaimed_reward);
+            self.set_info(info);
+            self.log_claim(BLOCK_TYPE_CLAIM, caller, &unclaimed_reward, "pledge");
+            // Return user's unclaimed rewards
+            return Some(unclaimed_reward);
         }
 
-        // Remove expired accounts and update total pledge amount
-        for account in expired_accounts {
-            if let Some((share, _, _)) = self.pledge_shares.remove(&account) {
-                info.total_pledge_token_supply -= &share;
-                ic_cdk::println!("Removed expired pledge account {:?}, pledge amount: {:?}", account, share);
-            }
-        }
+        None
+    }
+
+    pub fn revert_claim_pledge_reward(&mut self, caller: Principal, unclaimed_reward: E8s) {
+        let mut info = self.get_info();
+        info.revert_minted_reward(&unclaimed_reward);
+
+        // the reward is returned to the first lot, or to an empty unlocked lot if everything was withdrawn
+        let (lot_id, mut lot) = self
+            .get_pledge_lots(&caller)
+            .into_iter()
+            .next()
+            .unwrap_or_else(|| (info.generate_pledge_lot_id(), PledgeLot::default()));
+
+        self.log_claim(BLOCK_TYPE_CLAIM_REVERT, caller, &unclaimed_reward, "pledge");
+        lot.unclaimed_reward += unclaimed_reward;
+        self.pledge_lots.insert((caller, lot_id), lot);
 
-        // Update state information
         self.set_info(info);
-        true
     }
 
-    pub fn get_info(&self) -> SatslinkerStateInfo {
-        self.info.get().clone()
+    fn log_claim(&mut self, btype: &str, owner: Principal, qty: &E8s, kind: &str) {
+        self.append_block(btype, vec![
+            (String::from("to"), Icrc3Value::account(&owner)),
+            (String::from("amt"), Icrc3Value::e8s(qty)),
+            (String::from("kind"), Icrc3Value::text(kind)),
+        ], time());
     }
 
-    pub fn set_info(&mut self, info: SatslinkerStateInfo) {
-        self.info.set(info).expect("Unable to store info");
-    }
+    // The penalty decays linearly from the configured maximum to zero over the lock period
+    pub fn quote_early_unpledge(&self, caller: &Principal, lot_id: u64, now: Timestamp) -> Result<EarlyUnpledgeQuote, String> {
+        let lot = self.pledge_lots
+            .get(&(*caller, lot_id))
+            .ok_or(String::from("Pledge lot not found"))?;
 
-    pub fn get_satslinkers(&self, address: Address) -> GetVIPuserResponse {
-        let mut entry = Vec::new();
+        let info = self.get_info();
+        let pledge_period_ns = info.get_network_profile().pledge_period_ns;
 
-        for (participant_address, (principal, share, rewards)) in self.vip_participants.iter() {
-            if participant_address == address {
-                // If matching address found, add to entries
-                entry.push((participant_address.clone(),principal.clone(), share.clone(), rewards.clone(), true));
-                println!("satslinker vip shares:{:?} | {:?} | {:?} | {:?} ", principal, address, share, rewards);
-            }
+        if !lot.is_locked(now, pledge_period_ns) {
+            return Err(String::from("The lot is already unlocked, withdraw it instead"));
         }
-    
-        GetVIPuserResponse { entry }
+
+        let penalty_bp = lot.early_unpledge_penalty_bp(now, pledge_period_ns, info.get_early_unpledge_max_penalty_bp());
+
+        Ok(EarlyUnpledgeQuote::new(
+            lot_id,
+            lot.qty,
+            penalty_bp,
+            info.get_early_unpledge_treasury_share_bp(),
+            self.has_other_locked_lots(caller, lot_id, now),
+        ))
     }
 
-    pub fn get_totals(&self, caller: &Principal) -> GetTotalsResponse {
-        let info = self.get_info();
-        let fee = SatslinkerStateInfo::get_current_fee();
-        let is_satslink_enabled = info.is_satslink_enabled();
+    fn has_other_locked_lots(&self, caller: &Principal, lot_id: u64, now: Timestamp) -> bool {
+        let pledge_period_ns = self.pledge_period_ns();
 
-        let (address, share_1, unclaimed_reward_1) = self.vip_shares
-            .get(caller)
-            .map(|(a, s, r)| (a.clone(), s.clone(), r.clone()))
-            .unwrap_or_default();
-        let vip_status = self.vip_participants.contains_key(&address);
-        let (share_2, _, unclaimed_reward_2) = self.pledge_shares
-            .get(&caller)
-            .map(|(s, t, r)| (s.clone(), t, r.clone()))
-            .unwrap_or((ECs::zero(), 0u64, ECs::zero()));
-        let pledge_status = self.pledge_shares.contains_key(caller);
-        let icp_to_cycles_exchange_rate = info.get_icp_to_cycles_exchange_rate();
+        self.pledge_lots
+            .iter()
+            .any(|(key, lot)| key != (*caller, lot_id) && lot.is_locked(now, pledge_period_ns) && lot.qty > E8s::zero())
+    }
 
-        GetTotalsResponse {
-            total_pledge_token_supply: info.total_pledge_token_supply,
-            total_token_lottery: info.total_token_lottery,
-            total_token_dev: info.total_token_dev,
-            total_token_minted: info.total_token_minted,
+    // Closes a locked lot before it unlocks. The pledgers' part of the penalty is added to the
+    // principal of the other locked lots by their reward weight, so it never leaves the pledge subaccount.
+    pub fn unpledge_early(&mut self, caller: Principal, lot_id: u64, now: Timestamp) -> Result<EarlyUnpledgeReceipt, String> {
+        let mut quote = self.quote_early_unpledge(&caller, lot_id, now)?;
+        let lot = self.pledge_lots
+            .get(&(caller, lot_id))
+            .ok_or(String::from("Pledge lot not found"))?;
 
-            current_pos_round: info.current_pos_round,
-            pos_round_delay_ns: info.pos_round_delay_ns,
+        let mut credited_lots = Vec::new();
 
-            current_token_reward: info.current_token_reward,
-            current_share_fee: fee,
-            is_satslink_enabled,
+        if quote.to_pledgers > E8s::zero() {
+            let pledge_period_ns = self.pledge_period_ns();
+            let mut total_weight = E8s::zero();
+            let mut other_lots = Vec::new();
+
+            for (key, other_lot) in self.pledge_lots.iter() {
+                if key != (caller, lot_id) && other_lot.is_locked(now, pledge_period_ns) {
+                    total_weight += other_lot.reward_weight();
+                    other_lots.push((key, other_lot));
+                }
+            }
 
-            total_pledge_participants: self.pledge_shares.len(),
-            total_vip_participants: self.vip_participants.len(),
+            let mut distributed = E8s::zero();
+            for (key, mut other_lot) in other_lots {
+                let credit = E8s::new(
+                    quote.to_pledgers.val.clone() * other_lot.reward_weight().val / total_weight.val.clone(),
+                );
+                if credit == E8s::zero() {
+                    continue;
+                }
 
-            icp_to_cycles_exchange_rate: icp_to_cycles_exchange_rate,
+                other_lot.qty += &credit;
+                distributed += &credit;
+                self.pledge_lots.insert(key, other_lot);
+                credited_lots.push((key, credit));
+            }
 
-            your_vip_shares: share_1,
-            your_vip_unclaimed_reward_e8s: unclaimed_reward_1,
-            your_vip_eligibility_status: vip_status,
-            your_pledge_shares: share_2,
-            your_pledge_unclaimed_reward_e8s: unclaimed_reward_2,
-            your_pledge_eligibility_status: pledge_status,
+            // rounding dust goes to the treasury
+            let mut dust = quote.to_pledgers.clone();
+            dust -= &distributed;
+            quote.to_pledgers = distributed;
+            quote.to_treasury += dust;
+        }
+
+        // keep the emptied lot only while it still has a reward to claim
+        if lot.unclaimed_reward == E8s::zero() {
+            self.pledge_lots.remove(&(caller, lot_id));
+        } else {
+            self.pledge_lots.insert((caller, lot_id), PledgeLot { qty: E8s::zero(), ..lot.clone() });
         }
+
+        // the part credited to other pledgers stays pledged
+        let mut info = self.get_info();
+        info.total_pledge_token_supply -= &quote.payout;
+        info.total_pledge_token_supply -= &quote.to_treasury;
+        self.set_info(info);
+
+        Ok(EarlyUnpledgeReceipt {
+            quote,
+            lot,
+            credited_lots,
+        })
     }
-}
 
-impl Storable for SatslinkerStateInfo {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        match encode_one(self) {
-            Ok(bytes) => std::borrow::Cow::Owned(bytes),
-            Err(e) => {
-                ic_cdk::trap(&format!(
-                    "Failed to encode SatslinkerStateInfo: {}. State: {:?}", 
-                    e, 
-                    self
-                ));
+    pub fn revert_unpledge_early(&mut self, caller: Principal, receipt: EarlyUnpledgeReceipt) {
+        // credited lots and the closed lot could have moved to another account while the transfer was in flight
+        for ((_, other_lot_id), credit) in receipt.credited_lots {
+            if let Some((owner, mut other_lot)) = self.find_pledge_lot(other_lot_id) {
+                if other_lot.qty >= credit {
+                    other_lot.qty -= &credit;
+                    self.pledge_lots.insert((owner, other_lot_id), other_lot);
+                }
             }
         }
+
+        let lot_id = receipt.quote.lot_id;
+        let (owner, unclaimed_reward) = match self.find_pledge_lot(lot_id) {
+            Some((owner, current)) => (owner, current.unclaimed_reward),
+            None => (caller, E8s::zero()),
+        };
+        self.pledge_lots.insert((owner, lot_id), PledgeLot { unclaimed_reward, ..receipt.lot });
+
+        let mut info = self.get_info();
+        info.total_pledge_token_supply += &receipt.quote.payout;
+        info.total_pledge_token_supply += &receipt.quote.to_treasury;
+        self.set_info(info);
     }
 
-    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        match decode_one(&bytes) {
-            Ok(state) => state,
-            Err(e) => {
-                ic_cdk::println!(
-                    "Failed to decode existing state: {}. Creating new state.", 
-                    e
-                );
-                Self::default()
+    fn pledge_period_ns(&self) -> u64 {
+        self.get_info().get_network_profile().pledge_period_ns
+    }
+
+    fn vip_unit_ns(&self) -> u64 {
+        self.get_info().get_network_profile().vip_unit_ns
+    }
+
+    // VIP expiry is stored in VIP units, so the unit is fixed once there are VIP positions
+    pub fn set_network_profile(&mut self, profile: NetworkProfile, now: Timestamp) -> Result<(), String> {
+        if !self.vip_shares.is_empty() && profile.vip_unit_ns != self.vip_unit_ns() {
+            return Err(String::from("vip_unit_ns can't be changed while there are VIP positions"));
+        }
+
+        let mut info = self.get_info();
+        info.set_network_profile(profile, now)?;
+        self.set_info(info);
+
+        Ok(())
+    }
+
+    // Runs up to `max_rounds` of the rounds due by `now`, each at the time it was due.
+    // Every round touches every position, so fewer rounds fit into one run as the state grows.
+    pub fn catch_up_pos_rounds(&mut self, now: Timestamp, max_rounds: u64) -> u64 {
+        let positions = (self.vip_shares.len() + self.pledge_lots.len()).max(1);
+        let rounds = self.get_info()
+            .get_missed_pos_rounds(now)
+            .min(max_rounds)
+            .min((POS_ACCOUNTS_PER_BATCH / positions).max(1));
+
+        for _ in 0..rounds {
+            let round_at = self.get_info().get_next_pos_round_at().unwrap_or(now);
+            self.run_pos_round(round_at);
+        }
+
+        rounds
+    }
+
+    // One PoS round: 10% of the round reward accrues to the lottery pool and 2.5% to the dev pool,
+    // the VIP and pledge positions share the rest. Positions are taken as they were at `round_at`,
+    // the time the round was due, so a caught up round rewards the positions of its own time.
+    pub fn run_pos_round(&mut self, round_at: Timestamp) {
+        let mut info = self.get_info();
+
+        let mut cur_lottery_reward = info.current_token_reward.clone();
+        cur_lottery_reward *= ECs::<8>::from(100u64);
+        cur_lottery_reward /= ECs::<8>::from(1000u64);  // 10% = 100/1000
+        info.total_token_lottery += cur_lottery_reward;
+
+        let mut cur_dev_reward = info.current_token_reward.clone();
+        cur_dev_reward *= ECs::<8>::from(25u64);
+        cur_dev_reward /= ECs::<8>::from(1000u64);  // 2.5% = 25/1000
+        info.total_token_dev += cur_dev_reward;
+
+        self.set_info(info);
+
+        // both read and write the info themselves
+        self.distribute_vip_pos_rewards(round_at);
+        self.distribute_pledge_rewards(round_at);
+
+        let mut info = self.get_info();
+        info.complete_round();
+        self.set_info(info);
+    }
+
+    // dostribute rewards for vip users
+    pub fn distribute_vip_pos_rewards(&mut self, round_at: Timestamp) -> bool {
+        if self.vip_shares.is_empty() {
+            return true;
+        }
+    
+        let info = self.get_info();
+        let mut cur_reward = info.current_token_reward.clone();
+
+        cur_reward *= ECs::<8>::from(500u64);
+        cur_reward /= ECs::<8>::from(1000u64);  // 50% = 500/1000
+    
+        let current_time = round_at / info.get_network_profile().vip_unit_ns;
+    
+        let mut accounts_to_remove = Vec::new();
+        let mut accounts_to_update = Vec::new();
+    
+        // **Step 1: Iterate through vip_shares and categorize**
+        for (account, (address, vip_time, unclaimed_reward)) in self.vip_shares.iter() {
+            // bought after the round was due
+            if self.vip_started_at.get(&account).is_some_and(|started_at| started_at > round_at) {
+                continue;
+            }
+
+            if current_time >= vip_time && unclaimed_reward == ECs::<8>::zero() {
+                accounts_to_remove.push(account);
+            } else {
+                accounts_to_update.push((account, address, vip_time, unclaimed_reward.clone()));
+            }
+        }
+    
+        // **Step 2: Remove expired accounts**
+        for account in accounts_to_remove {
+            self.remove_vip_share(&account);
+        }
+    
+        // Check number of valid accounts
+        let valid_account_count = accounts_to_update.len();
+        if valid_account_count == 0 {
+            return true;
+        }
+    
+        // **Step 3: Calculate rewards for each account**
+        let valid_account_count = valid_account_count as u128;
+        let reward_per_account = cur_reward.val / valid_account_count;
+        let new_reward = ECs::<8>::new(reward_per_account);
+
+        // **Step 4: Update rewards for valid accounts**
+        for (account, address, vip_time, unclaimed_reward) in accounts_to_update {
+            // Update account reward value
+            let updated_reward = unclaimed_reward + new_reward.clone();
+            self.put_vip_share(account, address, vip_time, updated_reward);
+        }
+    
+        self.set_info(info);
+        true
+    } 
+
+    // Return true if the staking round has completed
+    pub fn distribute_pledge_rewards(&mut self, round_at: Timestamp) -> bool {
+        let mut info = self.get_info();
+        let mut cur_reward = info.current_token_reward.clone();
+        cur_reward *= ECs::<8>::from(375u64);
+        cur_reward /= ECs::<8>::from(1000u64);  // 37.5% = 375/1000
+
+        if self.pledge_lots.is_empty() {
+            return true;
+        }
+
+        let pledge_period_ns = info.get_network_profile().pledge_period_ns;
+        // pledged before the round was due and still locked at that time
+        let earns = |lot: &PledgeLot| lot.started_at <= round_at && lot.is_locked(round_at, pledge_period_ns);
+        let mut lots_to_update = Vec::new();
+        let mut total_weight = E8s::zero();
+
+        // First pass: calculate the weighted sum of lots that are still locked
+        for (_, lot) in self.pledge_lots.iter() {
+            if earns(&lot) {
+                total_weight += lot.reward_weight();
+            }
+        }
+
+        if total_weight == E8s::zero() {
+            return true;
+        }
+
+        // Second pass: split the reward by lot weight.
+        // Unlocked lots stop earning, but keep their principal until it is withdrawn.
+        for (key, mut lot) in self.pledge_lots.iter() {
+            if earns(&lot) {
+                let new_reward = &cur_reward * &lot.reward_weight() / &total_weight;
+
+                if lot.is_auto_compound() {
+                    // re-pledged into the same lot without a ledger round-trip
+                    info.note_compounded_pledge_reward(&new_reward);
+                    lot.qty += &new_reward;
+                } else {
+                    lot.unclaimed_reward += &new_reward;
+                }
+                lots_to_update.push((key, lot));
             }
         }
+
+        // Update rewards for locked lots
+        for (key, lot) in lots_to_update {
+            self.pledge_lots.insert(key, lot);
+        }
+
+        // Update state information
+        self.set_info(info);
+        true
+    }
+
+    pub fn get_swap_pool(&self) -> SwapPoolInfo {
+        self.swap_pool.get().clone()
+    }
+
+    pub fn set_swap_pool(&mut self, pool: SwapPoolInfo) {
+        self.swap_pool.set(pool).expect("Unable to store swap pool");
+    }
+
+    pub fn get_lp_shares(&self, provider: &Principal) -> E8s {
+        self.swap_lp_shares.get(provider).unwrap_or_default()
+    }
+
+    // redistributed ICP is added to the reserve without minting LP shares, so it backs the existing liquidity
+    pub fn note_swap_pool_icp_donation(&mut self, qty: E8s) {
+        let mut pool = self.get_swap_pool();
+        pool.reserve_icp += qty;
+        self.set_swap_pool(pool);
+    }
+
+    // Re-quotes with the current reserves, since they may have moved while the input was transferred
+    pub fn apply_swap(&mut self, direction: SwapDirection, amount_in: E8s, min_amount_out: E8s) -> Result<SwapQuote, String> {
+        let mut pool = self.get_swap_pool();
+        let quote = pool.quote(direction, &amount_in)?;
+
+        if quote.amount_out < min_amount_out {
+            return Err(format!(
+                "Slippage limit exceeded: {:?} would be received, at least {:?} expected",
+                quote.amount_out, min_amount_out
+            ));
+        }
+
+        let (mut reserve_in, mut reserve_out) = pool.reserves(direction);
+        reserve_in += &quote.amount_in;
+        reserve_out -= &quote.amount_out;
+        pool.set_reserves(direction, reserve_in, reserve_out);
+        self.set_swap_pool(pool);
+
+        Ok(quote)
     }
 
-    const BOUND: ic_stable_structures::storable::Bound = Bound::Unbounded;
+    pub fn revert_swap(&mut self, quote: &SwapQuote) {
+        let mut pool = self.get_swap_pool();
+
+        let (mut reserve_in, mut reserve_out) = pool.reserves(quote.direction);
+        reserve_in -= &quote.amount_in;
+        reserve_out += &quote.amount_out;
+        pool.set_reserves(quote.direction, reserve_in, reserve_out);
+        self.set_swap_pool(pool);
+    }
+
+    // Mints LP shares for the deposited amounts. Only the part matching the current pool ratio is used,
+    // returns (shares, icp used, satslink used) so the caller can refund the rest.
+    // The first deposit sets the price, so only the dev may seed an empty pool.
+    pub fn add_liquidity(&mut self, provider: Principal, dev: Principal, icp: E8s, satslink: E8s) -> Result<(E8s, E8s, E8s), String> {
+        if icp == E8s::zero() || satslink == E8s::zero() {
+            return Err(String::from("Both ICP and SATSLINK are required"));
+        }
+
+        let mut pool = self.get_swap_pool();
+
+        if pool.lp_total_supply == E8s::zero() && provider != dev {
+            return Err(String::from("Only the dev can seed the empty swap pool"));
+        }
+
+        let (shares, icp_used, satslink_used) = if pool.lp_total_supply == E8s::zero() {
+            // the first deposit sets the price, it also takes over any ICP redistributed into the empty pool
+            let shares = E8s::new((icp.val.clone() * satslink.val.clone()).sqrt());
+            (shares, icp, satslink)
+        } else {
+            if pool.reserve_icp == E8s::zero() || pool.reserve_satslink == E8s::zero() {
+                return Err(String::from("The swap pool is drained"));
+            }
+
+            let supply = pool.lp_total_supply.val.clone();
+            let shares_by_icp = icp.val.clone() * supply.clone() / pool.reserve_icp.val.clone();
+            let shares_by_satslink = satslink.val.clone() * supply.clone() / pool.reserve_satslink.val.clone();
+            let shares = shares_by_icp.min(shares_by_satslink);
+
+            // rounded up, so the existing providers are never diluted
+            let one = BigUint::from(1u64);
+            let icp_used = (shares.clone() * pool.reserve_icp.val.clone() + supply.clone() - one.clone()) / supply.clone();
+            let satslink_used = (shares.clone() * pool.reserve_satslink.val.clone() + supply.clone() - one) / supply;
+
+            (E8s::new(shares), E8s::new(icp_used), E8s::new(satslink_used))
+        };
+
+        if shares == E8s::zero() {
+            return Err(String::from("The deposit is too small"));
+        }
+
+        pool.reserve_icp += &icp_used;
+        pool.reserve_satslink += &satslink_used;
+        pool.lp_total_supply += &shares;
+        self.set_swap_pool(pool);
+
+        let provider_shares = self.get_lp_shares(&provider) + &shares;
+        self.swap_lp_shares.insert(provider, provider_shares);
+
+        Ok((shares, icp_used, satslink_used))
+    }
+
+    // Burns LP shares, returns (icp out, satslink out)
+    pub fn remove_liquidity(&mut self, provider: Principal, shares: E8s, min_icp: E8s, min_satslink: E8s) -> Result<(E8s, E8s), String> {
+        let provider_shares = self.get_lp_shares(&provider);
+
+        if shares == E8s::zero() || shares > provider_shares {
+            return Err(String::from("Insufficient LP shares"));
+        }
+
+        let mut pool = self.get_swap_pool();
+        let supply = pool.lp_total_supply.val.clone();
+
+        let icp_out = E8s::new(shares.val.clone() * pool.reserve_icp.val.clone() / supply.clone());
+        let satslink_out = E8s::new(shares.val.clone() * pool.reserve_satslink.val.clone() / supply);
+
+        if icp_out < min_icp || satslink_out < min_satslink {
+            return Err(format!(
+                "Slippage limit exceeded: {:?} ICP and {:?} SATSLINK would be received",
+                icp_out, satslink_out
+            ));
+        }
+
+        pool.reserve_icp -= &icp_out;
+        pool.reserve_satslink -= &satslink_out;
+        pool.lp_total_supply -= &shares;
+        self.set_swap_pool(pool);
+
+        let mut remaining_shares = provider_shares;
+        remaining_shares -= &shares;
+        if remaining_shares == E8s::zero() {
+            self.swap_lp_shares.remove(&provider);
+        } else {
+            self.swap_lp_shares.insert(provider, remaining_shares);
+        }
+
+        Ok((icp_out, satslink_out))
+    }
+
+    pub fn revert_remove_liquidity(&mut self, provider: Principal, shares: E8s, icp_out: E8s, satslink_out: E8s) {
+        let mut pool = self.get_swap_pool();
+        pool.reserve_icp += &icp_out;
+        pool.reserve_satslink += &satslink_out;
+        pool.lp_total_supply += &shares;
+        self.set_swap_pool(pool);
+
+        let provider_shares = self.get_lp_shares(&provider) + &shares;
+        self.swap_lp_shares.insert(provider, provider_shares);
+    }
+
+    // The SATSLINK side of a removal couldn't be sent after the ICP side was. The shares are burned and
+    // the reserves are already reduced, so the provider is owed exactly `qty` until the payout is claimed.
+    pub fn owe_lp_payout(&mut self, provider: Principal, qty: E8s) {
+        let owed = self.get_lp_payout(&provider) + qty;
+        self.swap_lp_payouts.insert(provider, owed);
+    }
+
+    pub fn get_lp_payout(&self, provider: &Principal) -> E8s {
+        self.swap_lp_payouts.get(provider).unwrap_or_default()
+    }
+
+    pub fn take_lp_payout(&mut self, provider: &Principal) -> Option<E8s> {
+        self.swap_lp_payouts.remove(provider)
+    }
+
+    pub fn create_top_up(&mut self, from: Principal, canister_id: Principal, qty_e8s: u64, now: Timestamp) -> u64 {
+        let mut info = self.get_info();
+        let id = info.generate_top_up_id();
+        self.set_info(info);
+
+        self.top_ups.insert(id, TopUpRecord {
+            from,
+            canister_id,
+            qty_e8s,
+            created_at: now,
+            status: TopUpStatus::Received,
+            attempts: 0,
+            reward: None,
+            source_subaccount: None,
+            reward_pending_since: None,
+            refund: None,
+        });
+
+        id
+    }
+
+    // tops up the canister itself, paid from the treasury and therefore not rewarded
+    pub fn create_cycles_top_up(&mut self, this_canister_id: Principal, source_subaccount: [u8; 32], qty_e8s: u64, now: Timestamp) -> u64 {
+        let mut info = self.get_info();
+        let id = info.generate_top_up_id();
+        info.pending_cycles_top_up_id = Some(id);
+        self.set_info(info);
+
+        self.top_ups.insert(id, TopUpRecord {
+            from: this_canister_id,
+            canister_id: this_canister_id,
+            qty_e8s,
+            created_at: now,
+            status: TopUpStatus::Received,
+            attempts: 0,
+            reward: Some(E8s::zero()),
+            source_subaccount: Some(source_subaccount),
+            reward_pending_since: None,
+            refund: None,
+        });
+
+        id
+    }
+
+    pub fn has_pending_cycles_top_up(&self) -> bool {
+        self.get_info()
+            .pending_cycles_top_up_id
+            .and_then(|id| self.top_ups.get(&id))
+            .map(|record| !record.is_final())
+            .unwrap_or_default()
+    }
+
+    pub fn get_top_up(&self, id: u64) -> Option<TopUpRecord> {
+        self.top_ups.get(&id)
+    }
+
+    pub fn get_top_ups_of(&self, from: &Principal) -> Vec<(u64, TopUpRecord)> {
+        self.top_ups
+            .iter()
+            .filter(|(_, record)| record.from == *from)
+            .collect()
+    }
+
+    // top ups with a step left: the CMC transfer, the notification, the reward or the refund
+    pub fn get_pending_top_up_ids(&self) -> Vec<u64> {
+        self.top_ups
+            .iter()
+            .filter(|(_, record)| !record.is_final())
+            .map(|(id, _)| id)
+            .collect()
+    }
+
+    // pending top ups whose retries ran out, nothing is scheduled for them anymore
+    pub fn get_given_up_top_up_ids(&self) -> Vec<u64> {
+        self.top_ups
+            .iter()
+            .filter(|(_, record)| !record.is_final() && record.attempts >= TOP_UP_MAX_ATTEMPTS)
+            .map(|(id, _)| id)
+            .collect()
+    }
+
+    pub fn reset_top_up_attempts(&mut self, id: u64) {
+        if let Some(mut record) = self.top_ups.get(&id) {
+            record.attempts = 0;
+            self.top_ups.insert(id, record);
+        }
+    }
+
+    // A user top up that didn't go through owes the ICP back. Before the CMC transfer it is still in the
+    // top up subaccount. After it the ICP is stuck at the CMC, so the treasury makes up for it
+    // the way a CMC refund would: less the fee to the CMC and the CMC refund fee.
+    pub fn fail_top_up(&mut self, id: u64, reason: String) {
+        if let Some(mut record) = self.top_ups.get(&id) {
+            if record.source_subaccount.is_none() {
+                record.refund = match record.status {
+                    TopUpStatus::Received => TopUpRefund::new(SATSLINKER_TOPUP_SUBACCOUNT, record.qty_e8s),
+                    _ => TopUpRefund::new(SATSLINKER_DEV_FEE_SUBACCOUNT, record.qty_e8s.saturating_sub(ICP_FEE * 2)),
+                };
+            }
+            record.status = TopUpStatus::Failed { reason };
+            self.top_ups.insert(id, record);
+        }
+    }
+
+    // the CMC returns the ICP to the source subaccount less the fee to the CMC and its refund fee
+    pub fn note_top_up_refunded_by_cmc(&mut self, id: u64, reason: String) {
+        if let Some(mut record) = self.top_ups.get(&id) {
+            if record.source_subaccount.is_none() {
+                record.refund = TopUpRefund::new(SATSLINKER_TOPUP_SUBACCOUNT, record.qty_e8s.saturating_sub(ICP_FEE * 2));
+            }
+            record.status = TopUpStatus::Refunded { reason };
+            self.top_ups.insert(id, record);
+        }
+    }
+
+    pub fn note_top_up_refund_sent(&mut self, id: u64, block_index: Nat) {
+        if let Some(mut record) = self.top_ups.get(&id) {
+            if let Some(refund) = record.refund.as_mut() {
+                refund.block_index = Some(block_index);
+            }
+            self.top_ups.insert(id, record);
+        }
+    }
+
+    fn get_unsent_top_up_refunds(&self, from_subaccount: [u8; 32]) -> u64 {
+        self.top_ups
+            .iter()
+            .filter_map(|(_, record)| record.refund)
+            .filter(|refund| refund.block_index.is_none() && refund.from_subaccount == from_subaccount)
+            .map(|refund| refund.qty_e8s)
+            .sum()
+    }
+
+    pub fn set_top_up_status(&mut self, id: u64, status: TopUpStatus) {
+        if let Some(mut record) = self.top_ups.get(&id) {
+            record.status = status;
+            self.top_ups.insert(id, record);
+        }
+    }
+
+    // Returns the attempt number
+    pub fn note_top_up_attempt(&mut self, id: u64) -> u32 {
+        if let Some(mut record) = self.top_ups.get(&id) {
+            record.attempts += 1;
+            let attempts = record.attempts;
+            self.top_ups.insert(id, record);
+
+            return attempts;
+        }
+
+        0
+    }
+
+    // The reward is priced with the current ICP/cycles rate and counted as minted before the ledger call
+    // Returns (to, reward, created_at) of the reward transfer. A transfer with an unknown outcome is retried
+    // with the same amount and creation time, so the ledger can deduplicate it.
+    pub fn prepare_top_up_reward(&mut self, id: u64, now: Timestamp) -> Option<(Principal, E8s, Timestamp)> {
+        let mut record = self.top_ups.get(&id)?;
+
+        match (record.reward.clone(), record.reward_pending_since) {
+            (Some(reward), Some(created_at)) => return Some((record.from, reward, created_at)),
+            (Some(_), None) => return None,
+            (None, _) => {}
+        }
+
+        let mut info = self.get_info();
+        let reward = record.calculate_reward(&info.get_icp_to_cycles_exchange_rate());
+        info.note_minted_reward(&reward);
+        self.set_info(info);
+
+        record.reward = Some(reward.clone());
+        record.reward_pending_since = Some(now);
+        self.top_ups.insert(id, record.clone());
+
+        Some((record.from, reward, now))
+    }
+
+    pub fn note_top_up_reward_paid(&mut self, id: u64) {
+        if let Some(mut record) = self.top_ups.get(&id) {
+            record.reward_pending_since = None;
+            self.top_ups.insert(id, record);
+        }
+    }
+
+    // only after the ledger rejected the transfer, nothing was minted
+    pub fn revert_top_up_reward(&mut self, id: u64) {
+        if let Some(mut record) = self.top_ups.get(&id) {
+            if let Some(reward) = record.reward.take() {
+                let mut info = self.get_info();
+                info.revert_minted_reward(&reward);
+                self.set_info(info);
+            }
+            record.reward_pending_since = None;
+            self.top_ups.insert(id, record);
+        }
+    }
+
+    pub fn get_info(&self) -> SatslinkerStateInfo {
+        self.info.get().clone()
+    }
+
+    pub fn set_info(&mut self, info: SatslinkerStateInfo) {
+        self.info.set(info).expect("Unable to store info");
+    }
+
+    pub fn get_satslinkers(&self, address: Address) -> GetVIPuserResponse {
+        let mut entry = Vec::new();
+
+        // the management canister principal is the smallest one, so the range starts at this address
+        let keys = self.vip_address_index
+            .range((address, Principal::management_canister())..)
+            .map(|(key, _)| key)
+            .take_while(|(participant_address, _)| *participant_address == address);
+
+        for (participant_address, principal) in keys {
+            if let Some((_, share, rewards)) = self.vip_shares.get(&principal) {
+                entry.push((participant_address, principal, share, rewards, true));
+            }
+        }
+    
+        GetVIPuserResponse { entry }
+    }
+
+    // `lottery_balance` is the lottery subaccount balance before the stake arrives,
+    // the bet is only accepted if every unsettled bet could still be paid in full
+    pub fn reserve_lottery_bet(&mut self, qty: &E8s, lottery_balance: &E8s) -> Result<(E8s, u64), String> {
+        let mut info = self.get_info();
+        let house_edge_bp = info.get_lottery_house_edge_bp();
+        let max_payout = LotteryBet::max_payout(qty, house_edge_bp);
+
+        let mut available = lottery_balance.clone();
+        available += qty;
+
+        // the draw pot and unpaid prizes are not part of the bets bankroll
+        let mut required = info.get_lottery_draw_funds();
+        required += &info.get_lottery_reserved();
+        required += &max_payout;
+
+        if available < required {
+            return Err(String::from("The lottery pool can't cover this bet"));
+        }
+
+        info.reserve_lottery_payout(&max_payout);
+        self.set_info(info);
+
+        Ok((max_payout, house_edge_bp))
+    }
+
+    pub fn release_lottery_reservation(&mut self, max_payout: &E8s) {
+        let mut info = self.get_info();
+        info.release_lottery_payout(max_payout);
+        self.set_info(info);
+    }
+
+    pub fn create_lottery_bet(&mut self, player: Principal, qty: E8s, house_edge_bp: u64, now: Timestamp) -> u64 {
+        let mut info = self.get_info();
+        let id = info.generate_lottery_bet_id();
+        self.set_info(info);
+
+        self.lottery_bets.insert(id, LotteryBet {
+            player,
+            qty: qty.clone(),
+            house_edge_bp,
+            placed_at: now,
+            status: LotteryBetStatus::Pending,
+            roll: None,
+            attempts: 0,
+        });
+
+        self.append_block(BLOCK_TYPE_BET, vec![
+            (String::from("from"), Icrc3Value::account(&player)),
+            (String::from("amt"), Icrc3Value::e8s(&qty)),
+            (String::from("bet"), Icrc3Value::nat(id)),
+            (String::from("edge_bp"), Icrc3Value::nat(house_edge_bp)),
+        ], now);
+
+        id
+    }
+
+    pub fn get_lottery_bet(&self, id: u64) -> Option<LotteryBet> {
+        self.lottery_bets.get(&id)
+    }
+
+    pub fn get_lottery_bets_of(&self, player: &Principal) -> Vec<(u64, LotteryBet)> {
+        self.lottery_bets
+            .iter()
+            .filter(|(_, bet)| bet.player == *player)
+            .collect()
+    }
+
+    pub fn get_unsettled_lottery_bet_ids(&self) -> Vec<u64> {
+        self.lottery_bets
+            .iter()
+            .filter(|(_, bet)| matches!(bet.status, LotteryBetStatus::Pending | LotteryBetStatus::Won { .. }))
+            .map(|(id, _)| id)
+            .collect()
+    }
+
+    pub fn reset_lottery_bet_attempts(&mut self, id: u64) {
+        if let Some(mut bet) = self.lottery_bets.get(&id) {
+            bet.attempts = 0;
+            self.lottery_bets.insert(id, bet);
+        }
+    }
+
+    pub fn note_lottery_bet_attempt(&mut self, id: u64) -> u32 {
+        if let Some(mut bet) = self.lottery_bets.get(&id) {
+            bet.attempts += 1;
+            let attempts = bet.attempts;
+            self.lottery_bets.insert(id, bet);
+
+            return attempts;
+        }
+
+        0
+    }
+
+    // `roll` is in basis points, lost bets free their reservation right away
+    pub fn roll_lottery_bet(&mut self, id: u64, roll: u64, now: Timestamp) -> Option<LotteryBet> {
+        let mut bet = self.lottery_bets.get(&id)?;
+        if !matches!(bet.status, LotteryBetStatus::Pending) {
+            return None;
+        }
+
+        let max_payout = LotteryBet::max_payout(&bet.qty, bet.house_edge_bp);
+
+        bet.roll = Some(roll);
+        let payout = if LotteryBet::is_win(roll) {
+            bet.status = LotteryBetStatus::Won { payout: max_payout.clone() };
+            max_payout
+        } else {
+            bet.status = LotteryBetStatus::Lost;
+            self.release_lottery_reservation(&max_payout);
+            E8s::zero()
+        };
+
+        self.lottery_bets.insert(id, bet.clone());
+        self.append_block(BLOCK_TYPE_BET_RESULT, vec![
+            (String::from("bet"), Icrc3Value::nat(id)),
+            (String::from("roll"), Icrc3Value::nat(roll)),
+            (String::from("payout"), Icrc3Value::e8s(&payout)),
+        ], now);
+
+        Some(bet)
+    }
+
+    pub fn note_lottery_bet_paid(&mut self, id: u64) {
+        if let Some(mut bet) = self.lottery_bets.get(&id) {
+            if let LotteryBetStatus::Won { payout } = bet.status {
+                self.release_lottery_reservation(&payout);
+                bet.status = LotteryBetStatus::Paid { payout };
+                self.lottery_bets.insert(id, bet);
+            }
+        }
+    }
+
+    pub fn commit_beacon_round(&mut self, secret: Vec<u8>, now: Timestamp) -> u64 {
+        let mut info = self.get_info();
+        let id = info.generate_beacon_round_id();
+        self.set_info(info);
+
+        self.beacon_rounds.insert(id, BeaconRound::commit(secret, now));
+
+        id
+    }
+
+    // returns the output, a round is only revealed once
+    pub fn reveal_beacon_round(&mut self, id: u64, entropy: Vec<u8>, now: Timestamp) -> Option<Vec<u8>> {
+        let mut round = self.beacon_rounds.get(&id)?;
+        if round.is_revealed() {
+            return None;
+        }
+
+        let output = BeaconRound::compute_output(&round.secret.clone().unwrap_or_default(), &entropy);
+        round.entropy = Some(entropy);
+        round.output = Some(output.clone());
+        round.revealed_at = Some(now);
+        self.beacon_rounds.insert(id, round);
+
+        Some(output)
+    }
+
+    pub fn get_public_beacon_round(&self, id: u64) -> Option<BeaconRound> {
+        self.beacon_rounds.get(&id).map(|round| round.to_public())
+    }
+
+    // reveals the pending seed round into `seed` and commits the next one
+    pub fn rotate_seed_beacon(&mut self, entropy: Vec<u8>, next_secret: Vec<u8>, now: Timestamp) {
+        if let Some(id) = self.get_info().seed_beacon_round {
+            if let Some(output) = self.reveal_beacon_round(id, entropy, now) {
+                let mut info = self.get_info();
+                info.mix_seed(&output);
+                self.set_info(info);
+            }
+        }
+
+        let next_id = self.commit_beacon_round(next_secret, now);
+
+        let mut info = self.get_info();
+        info.seed_beacon_round = Some(next_id);
+        self.set_info(info);
+    }
+
+    // `secret` backs the beacon commitment the winner of this draw is derived from
+    pub fn open_lottery_draw(&mut self, secret: Vec<u8>, now: Timestamp) -> u64 {
+        let beacon_round = self.commit_beacon_round(secret, now);
+
+        let mut info = self.get_info();
+        let id = info.current_lottery_draw_id.map(|it| it + 1).unwrap_or_default();
+        info.current_lottery_draw_id = Some(id);
+        let draw_interval_ns = info.get_network_profile().lottery_draw_interval_ns;
+        self.set_info(info);
+
+        self.lottery_draws.insert(id, LotteryDraw {
+            started_at: now,
+            ends_at: now + draw_interval_ns,
+            tickets_total: 0,
+            drawn_at: None,
+            winning_ticket: None,
+            winner: None,
+            prize: None,
+            paid: false,
+            beacon_round: Some(beacon_round),
+        });
+
+        id
+    }
+
+    pub fn get_current_lottery_draw(&self) -> Option<(u64, LotteryDraw)> {
+        let id = self.get_info().current_lottery_draw_id?;
+        let draw = self.lottery_draws.get(&id)?;
+
+        Some((id, draw))
+    }
+
+    // tickets are only issued into the draw that is still open
+    pub fn add_lottery_tickets(&mut self, owner: Principal, count: u64) -> Result<u64, String> {
+        if count == 0 {
+            return Err(String::from("At least one ticket is required"));
+        }
+
+        let (draw_id, mut draw) = self
+            .get_current_lottery_draw()
+            .ok_or(String::from("No lottery draw is open"))?;
+
+        self.lottery_tickets.insert((draw_id, draw.tickets_total), (owner, count));
+        draw.tickets_total += count;
+        self.lottery_draws.insert(draw_id, draw);
+
+        Ok(draw_id)
+    }
+
+    // A lot earns a ticket per pledged ticket price once it has stayed locked for its whole period,
+    // so a lot unpledged early never gets any. Lots matured after a draw closed wait for the next one.
+    pub fn grant_matured_pledge_tickets(&mut self, now: Timestamp) -> u64 {
+        match self.get_current_lottery_draw() {
+            Some((_, draw)) if now < draw.ends_at => {}
+            _ => return 0,
+        }
+
+        let pledge_period_ns = self.pledge_period_ns();
+        let matured_lots: Vec<_> = self
+            .pledge_lots
+            .iter()
+            .filter(|(_, lot)| lot.has_pending_tickets() && !lot.is_locked(now, pledge_period_ns))
+            .collect();
+
+        let price = self.get_info().get_lottery_ticket_price();
+        let mut granted = 0;
+
+        for ((owner, lot_id), mut lot) in matured_lots {
+            let tickets = u64::try_from(&(lot.qty.val.clone() / price.val.clone())).unwrap_or_default();
+            if tickets > 0 && self.add_lottery_tickets(owner, tickets).is_ok() {
+                granted += tickets;
+            }
+
+            lot.tickets_granted = Some(true);
+            self.pledge_lots.insert((owner, lot_id), lot);
+        }
+
+        granted
+    }
+
+    // Issues tickets bought for `draw_id` and only then adds their price to its pot.
+    // Fails if that draw is no longer open, the payment is then refunded by the caller.
+    pub fn sell_lottery_tickets(&mut self, owner: Principal, draw_id: u64, count: u64, qty: &E8s, now: Timestamp) -> Result<u64, String> {
+        match self.get_current_lottery_draw() {
+            Some((current_id, draw)) if current_id == draw_id && now < draw.ends_at => {}
+            _ => return Err(String::from("The lottery draw has closed")),
+        }
+
+        self.add_lottery_tickets(owner, count)?;
+        self.note_lottery_draw_funded(qty);
+
+        Ok(draw_id)
+    }
+
+    pub fn count_lottery_tickets_of(&self, draw_id: u64, owner: &Principal) -> u64 {
+        self.lottery_tickets
+            .range((draw_id, 0)..(draw_id + 1, 0))
+            .filter(|(_, (ticket_owner, _))| ticket_owner == owner)
+            .map(|(_, (_, count))| count)
+            .sum()
+    }
+
+    pub fn find_lottery_ticket_owner(&self, draw_id: u64, ticket: u64) -> Option<Principal> {
+        let ((found_draw_id, first_ticket), (owner, count)) = self
+            .lottery_tickets
+            .range((draw_id, 0)..=(draw_id, ticket))
+            .next_back()?;
+
+        if found_draw_id == draw_id && ticket < first_ticket + count {
+            Some(owner)
+        } else {
+            None
+        }
+    }
+
+    pub fn note_lottery_draw_funded(&mut self, qty: &E8s) {
+        let mut info = self.get_info();
+        info.note_lottery_draw_funded(qty);
+        self.set_info(info);
+    }
+
+    // `entropy` is fresh randomness revealing the draw's beacon round, the whole pot goes to the owner
+    // of the winning ticket. A draw without tickets rolls its pot over to the next one.
+    pub fn draw_lottery(&mut self, draw_id: u64, entropy: Vec<u8>, now: Timestamp) -> Option<LotteryDraw> {
+        let mut draw = self.lottery_draws.get(&draw_id)?;
+        if draw.drawn_at.is_some() {
+            return None;
+        }
+
+        let output = match draw.beacon_round {
+            // a round revealed earlier keeps its output, fresh entropy can't replace it
+            Some(round_id) => match self.reveal_beacon_round(round_id, entropy, now) {
+                Some(output) => output,
+                None => self.beacon_rounds.get(&round_id)?.output?,
+            },
+            // draws opened before the beacon existed have no commitment
+            None => BeaconRound::compute_output(&[], &entropy),
+        };
+
+        let mut info = self.get_info();
+        draw.drawn_at = Some(now);
+
+        if draw.tickets_total > 0 {
+            let winning_ticket = winning_idx_from_seed(&output, draw.tickets_total);
+
+            draw.winning_ticket = Some(winning_ticket);
+            draw.winner = self.find_lottery_ticket_owner(draw_id, winning_ticket);
+            draw.prize = Some(info.take_lottery_draw_pot());
+        }
+
+        self.set_info(info);
+        self.lottery_draws.insert(draw_id, draw.clone());
+
+        Some(draw)
+    }
+
+    pub fn get_unpaid_lottery_draws(&self) -> Vec<(u64, LotteryDraw)> {
+        self.lottery_draws
+            .iter()
+            .filter(|(_, draw)| draw.is_unpaid())
+            .collect()
+    }
+
+    pub fn note_lottery_draw_paid(&mut self, draw_id: u64) {
+        if let Some(mut draw) = self.lottery_draws.get(&draw_id) {
+            if !draw.is_unpaid() {
+                return;
+            }
+
+            let mut info = self.get_info();
+            info.note_lottery_prize_paid(&draw.prize.clone().unwrap_or_default());
+            self.set_info(info);
+
+            draw.paid = true;
+            self.lottery_draws.insert(draw_id, draw);
+        }
+    }
+
+    // recomputes the winner from the revealed beacon round, the same way anyone can do it off-chain
+    pub fn verify_lottery_draw(&self, draw_id: u64) -> Option<VerifyLotteryDrawResponse> {
+        let draw = self.lottery_draws.get(&draw_id)?;
+        let beacon_round = draw.beacon_round.and_then(|id| self.get_public_beacon_round(id));
+
+        let recomputed_winning_ticket = beacon_round
+            .as_ref()
+            .and_then(|round| round.output.as_ref())
+            .filter(|_| draw.tickets_total > 0)
+            .map(|output| winning_idx_from_seed(output, draw.tickets_total));
+
+        let is_valid = beacon_round.as_ref().map(|round| round.verify()).unwrap_or_default()
+            && draw.drawn_at.is_some()
+            && recomputed_winning_ticket == draw.winning_ticket;
+
+        Some(VerifyLotteryDrawResponse {
+            draw,
+            beacon_round,
+            is_valid,
+            recomputed_winning_ticket,
+        })
+    }
+
+    // newest first
+    pub fn get_past_lottery_draws(&self) -> Vec<(u64, LotteryDraw)> {
+        self.lottery_draws
+            .iter()
+            .rev()
+            .filter(|(_, draw)| draw.winner.is_some())
+            .take(LOTTERY_DRAWS_HISTORY_LIMIT)
+            .collect()
+    }
+
+    // Carried shares are owed first, the rest of the balance (including last run's dust) is split by the config.
+    // Returns None while the balance is below the configured minimum.
+    pub fn plan_redistribution(&self, balance_e8s: u64) -> Option<(Vec<(RedistributionTarget, u64)>, bool)> {
+        let info = self.get_info();
+        let config = info.get_redistribution_config();
+        if balance_e8s <= config.min_balance_e8s {
+            return None;
+        }
+
+        let mut carry = info.get_redistribution_carry();
+        let carry_reset = carry.total() > balance_e8s;
+        if carry_reset {
+            carry = RedistributionCarry::default();
+        }
+
+        let plan = config
+            .split(balance_e8s - carry.total())
+            .iter()
+            .map(|(target, qty_e8s)| (*target, qty_e8s + carry.get(*target)))
+            .collect();
+
+        Some((plan, carry_reset))
+    }
+
+    // whatever wasn't sent is carried over to the next run
+    pub fn record_redistribution(&mut self, balance_e8s: u64, carry_reset: bool, transfers: Vec<RedistributionTransfer>, now: Timestamp) -> u64 {
+        let mut carry = RedistributionCarry::default();
+        for transfer in &transfers {
+            if !matches!(transfer.outcome, RedistributionOutcome::Sent { .. }) {
+                carry.add(transfer.target, transfer.qty_e8s);
+            }
+        }
+
+        let mut info = self.get_info();
+        let id = info.generate_redistribution_id();
+        info.redistribution_carry = Some(carry);
+        self.set_info(info);
+
+        let logged_transfers = transfers
+            .iter()
+            .map(|transfer| Icrc3Value::Map(vec![
+                (String::from("target"), Icrc3Value::Text(format!("{:?}", transfer.target))),
+                (String::from("amt"), Icrc3Value::nat(transfer.qty_e8s)),
+                (String::from("outcome"), Icrc3Value::Text(format!("{:?}", transfer.outcome))),
+            ]))
+            .collect();
+        self.append_block(BLOCK_TYPE_REDISTRIBUTE, vec![
+            (String::from("amt"), Icrc3Value::nat(balance_e8s)),
+            (String::from("carry_reset"), Icrc3Value::nat(carry_reset as u64)),
+            (String::from("transfers"), Icrc3Value::Array(logged_transfers)),
+        ], now);
+
+        self.redistributions.insert(id, RedistributionRecord {
+            at: now,
+            balance_e8s,
+            carry_reset,
+            transfers,
+        });
+
+        id
+    }
+
+    // newest first
+    pub fn get_redistributions(&self) -> Vec<(u64, RedistributionRecord)> {
+        self.redistributions
+            .iter()
+            .rev()
+            .take(REDISTRIBUTION_HISTORY_LIMIT)
+            .collect()
+    }
+
+    // What the state owes from each subaccount, the balances are filled in from the ledgers
+    pub fn get_reserve_liabilities(&self) -> Vec<ReserveLine> {
+        let info = self.get_info();
+        let pool = self.get_swap_pool();
+
+        let mut pledged = info.total_pledge_token_supply.clone();
+        pledged -= &info.get_pending_compounded_pledge();
+        pledged += &info.get_early_unpledge_treasury_carry();
+
+        let mut lottery = info.get_lottery_draw_funds();
+        lottery += &info.get_lottery_reserved();
+
+        let mut dev_icp = self.get_committed_dev_fee_withdrawals(DevFeeToken::ICP);
+        dev_icp += E8s::from(self.get_unsent_top_up_refunds(SATSLINKER_DEV_FEE_SUBACCOUNT));
+        let dev_satslink = self.get_committed_dev_fee_withdrawals(DevFeeToken::SATSLINK);
+
+        // removals owed to the providers are still in the swap pool subaccount
+        let mut swappool_satslink = pool.reserve_satslink.clone();
+        for (_, owed) in self.swap_lp_payouts.iter() {
+            swappool_satslink += owed;
+        }
+
+        // user ICP waiting to be sent to the CMC or back to the user
+        let top_ups: u64 = self.top_ups
+            .iter()
+            .filter(|(_, record)| record.source_subaccount.is_none() && matches!(record.status, TopUpStatus::Received))
+            .map(|(_, record)| record.qty_e8s)
+            .sum::<u64>()
+            + self.get_unsent_top_up_refunds(SATSLINKER_TOPUP_SUBACCOUNT);
+
+        let line = |token, name: &str, subaccount, liability| ReserveLine {
+            token,
+            name: String::from(name),
+            subaccount,
+            balance: None,
+            liability,
+        };
+
+        vec![
+            line(ReserveToken::SATSLINK, "pledge", SATSLINKER_PLEDGE_SUBACCOUNT, pledged),
+            line(ReserveToken::SATSLINK, "lottery", SATSLINKER_LOTTERY_SUBACCOUNT, lottery),
+            line(ReserveToken::SATSLINK, "swappool", SATSLINKER_SWAPPOOL_SUBACCOUNT, swappool_satslink),
+            line(ReserveToken::SATSLINK, "dev", SATSLINKER_DEV_FEE_SUBACCOUNT, dev_satslink),
+            line(ReserveToken::ICP, "redistribution", SATSLINKER_REDISTRIBUTION_SUBACCOUNT, E8s::from(info.get_redistribution_carry().total())),
+            line(ReserveToken::ICP, "lottery", SATSLINKER_LOTTERY_SUBACCOUNT, E8s::zero()),
+            line(ReserveToken::ICP, "swappool", SATSLINKER_SWAPPOOL_SUBACCOUNT, pool.reserve_icp),
+            line(ReserveToken::ICP, "dev", SATSLINKER_DEV_FEE_SUBACCOUNT, dev_icp),
+            line(ReserveToken::ICP, "topup", SATSLINKER_TOPUP_SUBACCOUNT, E8s::from(top_ups)),
+        ]
+    }
+
+    pub fn store_reserves_report(&mut self, lines: Vec<ReserveLine>, icp_paid_in: E8s, now: Timestamp) -> ReservesReport {
+        let info = self.get_info();
+
+        let mut satslink_pending_mint = info.total_token_lottery.clone();
+        satslink_pending_mint += &info.total_token_dev;
+        satslink_pending_mint += &info.get_pending_compounded_pledge();
+
+        let mut pledge_unclaimed_rewards = E8s::zero();
+        for (_, lot) in self.pledge_lots.iter() {
+            pledge_unclaimed_rewards += &lot.unclaimed_reward;
+        }
+
+        let mut vip_unclaimed_rewards = E8s::zero();
+        for (_, (_, _, reward)) in self.vip_shares.iter() {
+            vip_unclaimed_rewards += &reward;
+        }
+
+        let mut report = ReservesReport {
+            created_at: now,
+            lines,
+            satslink_pending_mint,
+            pledge_unclaimed_rewards,
+            vip_unclaimed_rewards,
+            icp_paid_in,
+            icp_solvency_ratio_bp: None,
+            satslink_solvency_ratio_bp: None,
+            solvency_ratio_bp: None,
+        };
+
+        report.icp_solvency_ratio_bp = report.compute_solvency_ratio_bp(ReserveToken::ICP);
+        report.satslink_solvency_ratio_bp = report.compute_solvency_ratio_bp(ReserveToken::SATSLINK);
+        report.solvency_ratio_bp = match (report.icp_solvency_ratio_bp, report.satslink_solvency_ratio_bp) {
+            (Some(icp), Some(satslink)) => Some(icp.min(satslink)),
+            (icp, satslink) => icp.or(satslink),
+        };
+
+        self.reserves_report.set(report.clone()).expect("Unable to store reserves report");
+
+        report
+    }
+
+    pub fn get_reserves_report(&self) -> ReservesReport {
+        self.reserves_report.get().clone()
+    }
+
+    // Cross-checks the aggregates against the positions they summarize. With auto pause enabled
+    // any violation stops the reward rounds until the dev resumes them.
+    pub fn check_invariants(&mut self, now: Timestamp) -> InvariantReport {
+        let mut info = self.get_info();
+        let mut violations = Vec::new();
+
+        let min_pledge_share = SatslinkerStateInfo::get_min_pledge_share();
+        let mut pledged = E8s::zero();
+        for ((owner, lot_id), lot) in self.pledge_lots.iter() {
+            if lot.qty > E8s::zero() && lot.qty < min_pledge_share {
+                violations.push(InvariantViolation::PledgeLotDust { owner, lot_id, qty: lot.qty.clone() });
+            }
+            pledged += &lot.qty;
+        }
+        for (_, (qty, _, _)) in self.pledge_shares.iter() {
+            pledged += &qty;
+        }
+        if pledged != info.total_pledge_token_supply {
+            violations.push(InvariantViolation::PledgeSupplyMismatch {
+                recorded: info.total_pledge_token_supply.clone(),
+                actual: pledged,
+            });
+        }
+
+        let expected_minted = info.get_expected_minted();
+        if expected_minted != info.total_token_minted {
+            violations.push(InvariantViolation::MintedMismatch {
+                recorded: info.total_token_minted.clone(),
+                expected: expected_minted,
+            });
+        }
+
+        for ((address, owner), _) in self.vip_address_index.iter() {
+            match self.vip_shares.get(&owner) {
+                Some((share_address, _, _)) if share_address == address => {}
+                _ => violations.push(InvariantViolation::VipIndexWithoutShare { address, owner }),
+            }
+        }
+        for (owner, (address, _, _)) in self.vip_shares.iter() {
+            if !self.vip_address_index.contains_key(&(address, owner)) {
+                violations.push(InvariantViolation::VipShareNotIndexed { owner, address });
+            }
+        }
+        // should be empty once `rebuild_vip_address_index` has run
+        for (address, (owner, _, _)) in self.vip_participants.iter() {
+            violations.push(InvariantViolation::LegacyVipParticipant { address, owner });
+        }
+
+        if !violations.is_empty() && info.is_invariant_auto_pause() && !info.are_reward_rounds_paused() {
+            info.set_reward_rounds_paused(true);
+            self.set_info(info.clone());
+        }
+
+        let report = InvariantReport {
+            checked_at: now,
+            violations,
+            rounds_paused: info.are_reward_rounds_paused(),
+        };
+        self.invariant_report.set(report.clone()).expect("Unable to store invariant report");
+
+        report
+    }
+
+    pub fn get_invariant_report(&self) -> InvariantReport {
+        self.invariant_report.get().clone()
+    }
+
+    pub fn record_dev_fee_audit(&mut self, event: DevFeeAuditEvent, by: Principal, now: Timestamp) {
+        let mut info = self.get_info();
+        let id = info.generate_dev_fee_audit_id();
+        self.set_info(info);
+
+        self.dev_fee_audit_log.insert(id, DevFeeAuditEntry { event, by, at: now });
+    }
+
+    pub fn log(&mut self, level: LogLevel, subsystem: &str, message: String, by: Principal, now: Timestamp) {
+        self.append_log(LogEntry {
+            at: now,
+            level,
+            subsystem: String::from(subsystem),
+            caller: by,
+            message,
+        });
+    }
+
+    pub fn append_log(&mut self, entry: LogEntry) {
+        let id = self.logs.last_key_value().map(|(id, _)| id + 1).unwrap_or_default();
+        self.logs.insert(id, entry);
+
+        while self.logs.len() > LOG_BUFFER_LIMIT {
+            let Some((oldest, _)) = self.logs.first_key_value() else {
+                break;
+            };
+            self.logs.remove(&oldest);
+        }
+    }
+
+    // newest first, scans back from `before_id` until `limit` entries match
+    pub fn get_logs(&self, req: GetLogsRequest) -> GetLogsResponse {
+        let limit = req.limit.unwrap_or(MAX_LOGS_PER_REQUEST).min(MAX_LOGS_PER_REQUEST) as usize;
+        let before_id = req.before_id.unwrap_or(u64::MAX);
+
+        let mut entries: Vec<(u64, LogEntry)> = self.logs
+            .range(..before_id)
+            .rev()
+            .filter(|(_, entry)| req.min_level.is_none_or(|level| entry.level >= level))
+            .filter(|(_, entry)| req.subsystem.as_ref().is_none_or(|subsystem| entry.subsystem == *subsystem))
+            .filter(|(_, entry)| req.from.is_none_or(|from| entry.at >= from))
+            .filter(|(_, entry)| req.to.is_none_or(|to| entry.at < to))
+            .take(limit + 1)
+            .collect();
+
+        let next_before_id = if entries.len() > limit {
+            entries.truncate(limit);
+            entries.last().map(|(id, _)| *id)
+        } else {
+            None
+        };
+
+        GetLogsResponse { entries, next_before_id }
+    }
+
+    // newest first
+    pub fn get_dev_fee_audit_log(&self) -> Vec<(u64, DevFeeAuditEntry)> {
+        self.dev_fee_audit_log
+            .iter()
+            .rev()
+            .take(DEV_FEE_AUDIT_LOG_LIMIT)
+            .collect()
+    }
+
+    pub fn get_dev_fee_withdrawals(&self) -> Vec<(u64, DevFeeWithdrawal)> {
+        self.dev_fee_withdrawals.iter().collect()
+    }
+
+    // what announced and executing withdrawals will take out of the dev fee subaccount
+    pub fn get_committed_dev_fee_withdrawals(&self, token: DevFeeToken) -> E8s {
+        let mut committed = E8s::zero();
+        for (_, withdrawal) in self.dev_fee_withdrawals.iter() {
+            if withdrawal.token == token
+                && matches!(withdrawal.status, DevFeeWithdrawalStatus::Announced | DevFeeWithdrawalStatus::Executing)
+            {
+                committed += &withdrawal.qty;
+            }
+        }
+
+        committed
+    }
+
+    // `balance` is the dev fee subaccount balance of the token, it has to cover every pending withdrawal
+    pub fn announce_dev_fee_withdrawal(&mut self, token: DevFeeToken, qty: E8s, to: Principal, balance: E8s, by: Principal, now: Timestamp) -> Result<u64, String> {
+        // the ledger fee is paid out of `qty` when the withdrawal is executed
+        if qty <= E8s::from(token.ledger_fee()) {
+            return Err(String::from("The amount is too small to cover the transfer fee"));
+        }
+
+        let mut committed = self.get_committed_dev_fee_withdrawals(token);
+        committed += &qty;
+        if committed > balance {
+            return Err(format!("The dev fee balance {:?} doesn't cover the pending withdrawals", balance));
+        }
+
+        let mut info = self.get_info();
+        let id = info.generate_dev_fee_withdrawal_id();
+        let unlocks_at = now + info.get_dev_fee_timelock_ns(now);
+        self.set_info(info);
+
+        self.dev_fee_withdrawals.insert(id, DevFeeWithdrawal {
+            token,
+            qty,
+            to,
+            announced_by: by,
+            announced_at: now,
+            unlocks_at,
+            status: DevFeeWithdrawalStatus::Announced,
+        });
+        self.record_dev_fee_audit(DevFeeAuditEvent::Announced { withdrawal_id: id }, by, now);
+
+        Ok(id)
+    }
+
+    // moves an unlocked withdrawal to `Executing`, so it can't be confirmed twice while the transfer is in flight
+    pub fn confirm_dev_fee_withdrawal(&mut self, id: u64, by: Principal, now: Timestamp) -> Result<DevFeeWithdrawal, String> {
+        let mut withdrawal = self.dev_fee_withdrawals
+            .get(&id)
+            .ok_or(String::from("Withdrawal not found"))?;
+
+        if !matches!(withdrawal.status, DevFeeWithdrawalStatus::Announced) {
+            return Err(String::from("The withdrawal is not pending"));
+        }
+
+        if now < withdrawal.unlocks_at {
+            return Err(format!("The withdrawal is timelocked until {}", withdrawal.unlocks_at));
+        }
+
+        withdrawal.status = DevFeeWithdrawalStatus::Executing;
+        self.dev_fee_withdrawals.insert(id, withdrawal.clone());
+        self.record_dev_fee_audit(DevFeeAuditEvent::Confirmed { withdrawal_id: id }, by, now);
+
+        Ok(withdrawal)
+    }
+
+    pub fn complete_dev_fee_withdrawal(&mut self, id: u64, result: Result<Nat, String>, by: Principal, now: Timestamp) {
+        if let Some(mut withdrawal) = self.dev_fee_withdrawals.get(&id) {
+            let event = match result {
+                Ok(block_index) => {
+                    withdrawal.status = DevFeeWithdrawalStatus::Executed { block_index: block_index.clone() };
+                    DevFeeAuditEvent::Executed { withdrawal_id: id, block_index }
+                }
+                Err(reason) => {
+                    withdrawal.status = DevFeeWithdrawalStatus::Failed { reason: reason.clone() };
+                    DevFeeAuditEvent::Failed { withdrawal_id: id, reason }
+                }
+            };
+
+            self.dev_fee_withdrawals.insert(id, withdrawal);
+            self.record_dev_fee_audit(event, by, now);
+        }
+    }
+
+    pub fn cancel_dev_fee_withdrawal(&mut self, id: u64, by: Principal, now: Timestamp) -> Result<(), String> {
+        let mut withdrawal = self.dev_fee_withdrawals
+            .get(&id)
+            .ok_or(String::from("Withdrawal not found"))?;
+
+        if !matches!(withdrawal.status, DevFeeWithdrawalStatus::Announced) {
+            return Err(String::from("The withdrawal is not pending"));
+        }
+
+        withdrawal.status = DevFeeWithdrawalStatus::Cancelled;
+        self.dev_fee_withdrawals.insert(id, withdrawal);
+        self.record_dev_fee_audit(DevFeeAuditEvent::Cancelled { withdrawal_id: id }, by, now);
+
+        Ok(())
+    }
+
+    pub fn set_dev_fee_timelock(&mut self, timelock_ns: u64, by: Principal, now: Timestamp) -> Result<Timestamp, String> {
+        let mut info = self.get_info();
+        let effective_at = info.announce_dev_fee_timelock(timelock_ns, now)?;
+        self.set_info(info);
+
+        self.record_dev_fee_audit(DevFeeAuditEvent::TimelockChangeAnnounced { timelock_ns, effective_at }, by, now);
+
+        Ok(effective_at)
+    }
+
+    // only for the owner set at install, later changes go through `propose_dev_fee_owner`
+    pub fn set_dev_fee_owner(&mut self, owner: Principal, by: Principal, now: Timestamp) {
+        let mut info = self.get_info();
+        info.dev_fee_owner = Some(owner);
+        info.pending_dev_fee_owner = None;
+        self.set_info(info);
+
+        self.record_dev_fee_audit(DevFeeAuditEvent::OwnerChanged { owner }, by, now);
+    }
+
+    // The new owner can accept once the current timelock is over, so a handover can't skip it.
+    // Returns the time the owner can accept from.
+    pub fn propose_dev_fee_owner(&mut self, owner: Principal, dev: Principal, by: Principal, now: Timestamp) -> Result<Timestamp, String> {
+        if owner == dev {
+            return Err(String::from("The dev fee owner can't be the dev"));
+        }
+
+        let mut info = self.get_info();
+        if info.get_dev_fee_owner() != Some(by) {
+            return Err(String::from("Only the dev fee owner can hand it over"));
+        }
+
+        let effective_at = now + info.get_dev_fee_timelock_ns(now);
+        info.pending_dev_fee_owner = Some((owner, effective_at));
+        self.set_info(info);
+
+        self.record_dev_fee_audit(DevFeeAuditEvent::OwnerChangeProposed { owner, effective_at }, by, now);
+
+        Ok(effective_at)
+    }
+
+    pub fn accept_dev_fee_owner(&mut self, by: Principal, now: Timestamp) -> Result<(), String> {
+        let (owner, effective_at) = self
+            .get_info()
+            .pending_dev_fee_owner
+            .filter(|(owner, _)| *owner == by)
+            .ok_or(String::from("No dev fee ownership is proposed to the caller"))?;
+
+        if now < effective_at {
+            return Err(format!("The handover is timelocked until {}", effective_at));
+        }
+
+        self.set_dev_fee_owner(owner, by, now);
+
+        Ok(())
+    }
+
+    pub fn get_totals(&self, caller: &Principal) -> GetTotalsResponse {
+        let info = self.get_info();
+        let fee = SatslinkerStateInfo::get_current_fee();
+        let is_satslink_enabled = info.is_satslink_enabled();
+
+        let (address, share_1, unclaimed_reward_1) = self.vip_shares
+            .get(caller)
+            .map(|(a, s, r)| (a, s, r.clone()))
+            .unwrap_or_default();
+        let vip_status = self.vip_address_index.contains_key(&(address, *caller));
+        let now = time();
+        let mut share_2 = E8s::zero();
+        let mut unclaimed_reward_2 = E8s::zero();
+        let mut pledge_status = false;
+        let pledge_period_ns = info.get_network_profile().pledge_period_ns;
+        for (_, lot) in self.get_pledge_lots(caller) {
+            share_2 += &lot.qty;
+            unclaimed_reward_2 += &lot.unclaimed_reward;
+            pledge_status |= lot.is_locked(now, pledge_period_ns);
+        }
+        let icp_to_cycles_exchange_rate = info.get_icp_to_cycles_exchange_rate();
+        let cycles_threshold = info.get_cycles_threshold();
+        let low_cycles_alert = info.is_low_cycles_alert();
+
+        GetTotalsResponse {
+            total_pledge_token_supply: info.total_pledge_token_supply,
+            total_token_lottery: info.total_token_lottery,
+            total_token_dev: info.total_token_dev,
+            total_token_minted: info.total_token_minted,
+
+            current_pos_round: info.current_pos_round,
+            pos_round_delay_ns: info.pos_round_delay_ns,
+
+            current_token_reward: info.current_token_reward,
+            current_share_fee: fee,
+            is_satslink_enabled,
+
+            total_pledge_participants: self.count_pledge_participants(),
+            total_vip_participants: self.vip_shares.len(),
+
+            icp_to_cycles_exchange_rate,
+
+            canister_cycles_balance: canister_balance128(),
+            cycles_threshold,
+            low_cycles_alert,
+
+            your_vip_shares: share_1,
+            your_vip_unclaimed_reward_e8s: unclaimed_reward_1,
+            your_vip_eligibility_status: vip_status,
+            your_pledge_shares: share_2,
+            your_pledge_unclaimed_reward_e8s: unclaimed_reward_2,
+            your_pledge_eligibility_status: pledge_status,
+        }
+    }
+}
+
+impl Storable for SatslinkerStateInfo {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        match encode_one(self) {
+            Ok(bytes) => std::borrow::Cow::Owned(bytes),
+            Err(e) => {
+                ic_cdk::trap(&format!(
+                    "Failed to encode SatslinkerStateInfo: {}. State: {:?}", 
+                    e, 
+                    self
+                ));
+            }
+        }
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        match decode_one(&bytes) {
+            Ok(state) => state,
+            Err(e) => {
+                ic_cdk::println!(
+                    "Failed to decode existing state: {}. Creating new state.", 
+                    e
+                );
+                Self::default()
+            }
+        }
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = Bound::Unbounded;
+}
+
+impl Storable for PledgeLot {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        std::borrow::Cow::Owned(encode_one(self).expect("Unable to encode pledge lot"))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_one(&bytes).expect("Unable to decode pledge lot")
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = Bound::Unbounded;
+}
+
+impl Storable for SwapPoolInfo {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        std::borrow::Cow::Owned(encode_one(self).expect("Unable to encode swap pool"))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_one(&bytes).expect("Unable to decode swap pool")
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = Bound::Unbounded;
+}
+
+impl Storable for AccountTransfer {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        std::borrow::Cow::Owned(encode_one(self).expect("Unable to encode account transfer"))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_one(&bytes).expect("Unable to decode account transfer")
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = Bound::Unbounded;
+}
+
+impl Storable for Icrc3Value {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        std::borrow::Cow::Owned(encode_one(self).expect("Unable to encode block"))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_one(&bytes).expect("Unable to decode block")
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = Bound::Unbounded;
+}
+
+impl Storable for LogEntry {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        std::borrow::Cow::Owned(encode_one(self).expect("Unable to encode log entry"))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_one(&bytes).expect("Unable to decode log entry")
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = Bound::Unbounded;
+}
+
+impl Storable for InvariantReport {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        std::borrow::Cow::Owned(encode_one(self).expect("Unable to encode invariant report"))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_one(&bytes).expect("Unable to decode invariant report")
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = Bound::Unbounded;
+}
+
+impl Storable for ReservesReport {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        std::borrow::Cow::Owned(encode_one(self).expect("Unable to encode reserves report"))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_one(&bytes).expect("Unable to decode reserves report")
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = Bound::Unbounded;
+}
+
+impl Storable for RedistributionRecord {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        std::borrow::Cow::Owned(encode_one(self).expect("Unable to encode redistribution record"))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_one(&bytes).expect("Unable to decode redistribution record")
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = Bound::Unbounded;
+}
+
+impl Storable for DevFeeWithdrawal {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        std::borrow::Cow::Owned(encode_one(self).expect("Unable to encode dev fee withdrawal"))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_one(&bytes).expect("Unable to decode dev fee withdrawal")
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = Bound::Unbounded;
+}
+
+impl Storable for DevFeeAuditEntry {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        std::borrow::Cow::Owned(encode_one(self).expect("Unable to encode dev fee audit entry"))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_one(&bytes).expect("Unable to decode dev fee audit entry")
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = Bound::Unbounded;
+}
+
+impl Storable for BeaconRound {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        std::borrow::Cow::Owned(encode_one(self).expect("Unable to encode beacon round"))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_one(&bytes).expect("Unable to decode beacon round")
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = Bound::Unbounded;
+}
+
+impl Storable for LotteryDraw {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        std::borrow::Cow::Owned(encode_one(self).expect("Unable to encode lottery draw"))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_one(&bytes).expect("Unable to decode lottery draw")
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = Bound::Unbounded;
+}
+
+impl Storable for LotteryBet {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        std::borrow::Cow::Owned(encode_one(self).expect("Unable to encode lottery bet"))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_one(&bytes).expect("Unable to decode lottery bet")
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = Bound::Unbounded;
+}
+
+impl Storable for TopUpRecord {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        std::borrow::Cow::Owned(encode_one(self).expect("Unable to encode top up record"))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_one(&bytes).expect("Unable to decode top up record")
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = Bound::Unbounded;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::satslinker::types::{RedistributionConfig, BASIS_POINTS_BASE};
+    use ic_stable_structures::{
+        memory_manager::{MemoryId, MemoryManager},
+        DefaultMemoryImpl,
+    };
+
+    // Lays the state out over the same memory regions as the canister does.
+    // Building it again over the same memory is what the canister sees after an upgrade.
+    fn state_on(memory: &DefaultMemoryImpl) -> SatslinkerState {
+        let manager = MemoryManager::init(memory.clone());
+
+        SatslinkerState {
+            vip_shares: StableBTreeMap::init(manager.get(MemoryId::new(1))),
+            pledge_shares: StableBTreeMap::init(manager.get(MemoryId::new(2))),
+            info: Cell::init(manager.get(MemoryId::new(3)), SatslinkerStateInfo::default()).unwrap(),
+            vip_participants: StableBTreeMap::init(manager.get(MemoryId::new(4))),
+            pledge_lots: StableBTreeMap::init(manager.get(MemoryId::new(5))),
+            swap_pool: Cell::init(manager.get(MemoryId::new(6)), SwapPoolInfo::default()).unwrap(),
+            swap_lp_shares: StableBTreeMap::init(manager.get(MemoryId::new(7))),
+            top_ups: StableBTreeMap::init(manager.get(MemoryId::new(8))),
+            lottery_bets: StableBTreeMap::init(manager.get(MemoryId::new(9))),
+            lottery_draws: StableBTreeMap::init(manager.get(MemoryId::new(10))),
+            lottery_tickets: StableBTreeMap::init(manager.get(MemoryId::new(11))),
+            beacon_rounds: StableBTreeMap::init(manager.get(MemoryId::new(12))),
+            dev_fee_withdrawals: StableBTreeMap::init(manager.get(MemoryId::new(13))),
+            dev_fee_audit_log: StableBTreeMap::init(manager.get(MemoryId::new(14))),
+            redistributions: StableBTreeMap::init(manager.get(MemoryId::new(15))),
+            reserves_report: Cell::init(manager.get(MemoryId::new(16)), ReservesReport::default()).unwrap(),
+            account_transfers: StableBTreeMap::init(manager.get(MemoryId::new(17))),
+            vip_address_index: StableBTreeMap::init(manager.get(MemoryId::new(18))),
+            invariant_report: Cell::init(manager.get(MemoryId::new(19)), InvariantReport::default()).unwrap(),
+            blocks: StableBTreeMap::init(manager.get(MemoryId::new(20))),
+            certified_vip_by_eth: RbTree::new(),
+            certified_vip_by_principal: RbTree::new(),
+            vip_attestations: BTreeMap::new(),
+            logs: StableBTreeMap::init(manager.get(MemoryId::new(21))),
+            vip_started_at: StableBTreeMap::init(manager.get(MemoryId::new(22))),
+            swap_lp_payouts: StableBTreeMap::init(manager.get(MemoryId::new(23))),
+        }
+    }
+
+    // a freshly installed canister after `init_seed` has run
+    fn new_state() -> SatslinkerState {
+        let mut s = state_on(&DefaultMemoryImpl::default());
+        s.init(vec![0u8; 32]);
+
+        s
+    }
+
+    fn user(id: u8) -> Principal {
+        Principal::from_slice(&[id])
+    }
+
+    fn e8s(qty: u64) -> E8s {
+        E8s::from(qty)
+    }
+
+    fn pledge_period_ns(s: &SatslinkerState) -> u64 {
+        s.get_info().get_network_profile().pledge_period_ns
+    }
+
+    #[test]
+    fn withdraw_takes_only_unlocked_pledge_and_revert_restores_it() {
+        let mut s = new_state();
+        let period = pledge_period_ns(&s);
+
+        s.mint_pledge_lot(e8s(10_0000_0000), PledgeLockPeriod::OneMonth, false, 0, user(1));
+        s.mint_pledge_lot(e8s(20_0000_0000), PledgeLockPeriod::TwelveMonths, false, 0, user(1));
+
+        assert!(s.withdraw_pledge_lots(user(1), e8s(1_0000_0000), period - 1).is_err());
+        assert!(s.withdraw_pledge_lots(user(1), e8s(11_0000_0000), period).is_err());
+
+        let touched = s.withdraw_pledge_lots(user(1), e8s(4_0000_0000), period).unwrap();
+        assert_eq!(s.get_pledge_lots(&user(1))[0].1.qty, e8s(6_0000_0000));
+        assert_eq!(s.get_info().total_pledge_token_supply, e8s(26_0000_0000));
+
+        s.revert_withdraw_pledge_lots(user(1), e8s(4_0000_0000), touched);
+        assert_eq!(s.get_pledge_lots(&user(1))[0].1.qty, e8s(10_0000_0000));
+        assert_eq!(s.get_info().total_pledge_token_supply, e8s(30_0000_0000));
+    }
+
+    #[test]
+    fn withdrawing_a_whole_lot_removes_it() {
+        let mut s = new_state();
+        let period = pledge_period_ns(&s);
+
+        s.mint_pledge_lot(e8s(10_0000_0000), PledgeLockPeriod::OneMonth, false, 0, user(1));
+        s.withdraw_pledge_lots(user(1), e8s(10_0000_0000), period).unwrap();
+
+        assert!(s.get_pledge_lots(&user(1)).is_empty());
+        assert_eq!(s.get_info().total_pledge_token_supply, E8s::zero());
+    }
+
+    #[test]
+    fn pledge_rewards_follow_the_lockup_multiplier() {
+        let mut s = new_state();
+        let period = pledge_period_ns(&s);
+
+        let one_month = s.mint_pledge_lot(e8s(10_0000_0000), PledgeLockPeriod::OneMonth, false, 0, user(1));
+        let twelve_months = s.mint_pledge_lot(e8s(10_0000_0000), PledgeLockPeriod::TwelveMonths, false, 0, user(2));
+
+        s.distribute_pledge_rewards(1);
+        let short = s.get_pledge_lots(&user(1))[0].1.unclaimed_reward.clone();
+        let long = s.get_pledge_lots(&user(2))[0].1.unclaimed_reward.clone();
+        assert!(short > E8s::zero());
+        // 2x multiplier, up to one e8s of rounding
+        let twice_short = &short + &short;
+        assert!(long >= twice_short && long <= &twice_short + &E8s::new(BigUint::from(1u64)));
+
+        // the one month lot has unlocked, so the twelve month lot takes the whole round
+        s.distribute_pledge_rewards(period);
+        assert_eq!(s.find_pledge_lot(one_month).unwrap().1.unclaimed_reward, short);
+        assert!(s.find_pledge_lot(twelve_months).unwrap().1.unclaimed_reward > &long + &long);
+    }
+
+    #[test]
+    fn lots_pledged_after_the_round_was_due_earn_nothing_from_it() {
+        let mut s = new_state();
+
+        s.mint_pledge_lot(e8s(10_0000_0000), PledgeLockPeriod::OneMonth, false, 100, user(1));
+        s.distribute_pledge_rewards(99);
+
+        assert_eq!(s.get_pledge_lots(&user(1))[0].1.unclaimed_reward, E8s::zero());
+    }
+
+    #[test]
+    fn early_unpledge_credits_other_locked_lots_and_reverts_exactly() {
+        let mut s = new_state();
+        let period = pledge_period_ns(&s);
+
+        let lot_id = s.mint_pledge_lot(e8s(10_0000_0000), PledgeLockPeriod::ThreeMonths, false, 0, user(1));
+        s.mint_pledge_lot(e8s(10_0000_0000), PledgeLockPeriod::TwelveMonths, false, 0, user(2));
+
+        let receipt = s.unpledge_early(user(1), lot_id, period).unwrap();
+        let quote = receipt.quote.clone();
+        assert!(quote.penalty > E8s::zero());
+        assert_eq!(&(&quote.payout + &quote.to_pledgers) + &quote.to_treasury, e8s(10_0000_0000));
+
+        assert!(s.get_pledge_lots(&user(1)).is_empty());
+        assert_eq!(s.get_pledge_lots(&user(2))[0].1.qty, &e8s(10_0000_0000) + &quote.to_pledgers);
+        assert_eq!(s.get_info().total_pledge_token_supply, &e8s(10_0000_0000) + &quote.to_pledgers);
+
+        s.revert_unpledge_early(user(1), receipt);
+        assert_eq!(s.get_pledge_lots(&user(1))[0].1.qty, e8s(10_0000_0000));
+        assert_eq!(s.get_pledge_lots(&user(2))[0].1.qty, e8s(10_0000_0000));
+        assert_eq!(s.get_info().total_pledge_token_supply, e8s(20_0000_0000));
+    }
+
+    #[test]
+    fn unlocked_lots_are_withdrawn_not_unpledged_early() {
+        let mut s = new_state();
+        let period = pledge_period_ns(&s);
+
+        let lot_id = s.mint_pledge_lot(e8s(10_0000_0000), PledgeLockPeriod::OneMonth, false, 0, user(1));
+
+        assert!(s.unpledge_early(user(1), lot_id, period).is_err());
+        assert!(s.unpledge_early(user(2), lot_id, 0).is_err());
+        assert_eq!(s.get_info().total_pledge_token_supply, e8s(10_0000_0000));
+    }
+
+    #[test]
+    fn auto_compounding_lots_grow_their_principal_instead_of_the_reward() {
+        let mut s = new_state();
+
+        s.mint_pledge_lot(e8s(10_0000_0000), PledgeLockPeriod::OneMonth, false, 0, user(1));
+        let compounding = s.mint_pledge_lot(e8s(10_0000_0000), PledgeLockPeriod::OneMonth, false, 0, user(2));
+        assert!(s.set_pledge_auto_compound(user(1), compounding, true).is_err());
+        s.set_pledge_auto_compound(user(2), compounding, true).unwrap();
+
+        s.distribute_pledge_rewards(1);
+
+        let claimable = s.get_pledge_lots(&user(1))[0].1.unclaimed_reward.clone();
+        let (_, lot) = s.find_pledge_lot(compounding).unwrap();
+        assert_eq!(lot.unclaimed_reward, E8s::zero());
+        assert_eq!(lot.qty, &e8s(10_0000_0000) + &claimable);
+
+        let info = s.get_info();
+        assert_eq!(info.get_pending_compounded_pledge(), claimable);
+        assert_eq!(info.total_pledge_token_supply, &e8s(20_0000_0000) + &claimable);
+    }
+
+    #[test]
+    fn only_the_dev_can_seed_an_emptied_pool() {
+        let mut s = new_state();
+        let dev = user(9);
+
+        assert!(s.add_liquidity(user(1), dev, e8s(1_0000_0000), e8s(100_0000_0000)).is_err());
+
+        let (shares, _, _) = s.add_liquidity(dev, dev, e8s(1_0000_0000), e8s(100_0000_0000)).unwrap();
+        s.add_liquidity(user(1), dev, e8s(1_0000_0000), e8s(100_0000_0000)).unwrap();
+
+        // the pool is emptied between the dev check of the endpoint and the deposit
+        let user_shares = s.get_lp_shares(&user(1));
+        s.remove_liquidity(dev, shares, E8s::zero(), E8s::zero()).unwrap();
+        s.remove_liquidity(user(1), user_shares, E8s::zero(), E8s::zero()).unwrap();
+        assert_eq!(s.get_swap_pool().lp_total_supply, E8s::zero());
+
+        assert!(s.add_liquidity(user(1), dev, e8s(1_0000_0000), e8s(1_0000_0000)).is_err());
+    }
+
+    #[test]
+    fn failed_icp_side_restores_the_exact_shares_and_reserves() {
+        let mut s = new_state();
+        let dev = user(9);
+
+        s.add_liquidity(dev, dev, e8s(3_0000_0000), e8s(300_0000_0000)).unwrap();
+        s.add_liquidity(user(1), dev, e8s(1_0000_0000), e8s(100_0000_0000)).unwrap();
+        let pool_before = s.get_swap_pool();
+        let shares = s.get_lp_shares(&user(1));
+
+        let (icp_out, satslink_out) = s.remove_liquidity(user(1), shares.clone(), E8s::zero(), E8s::zero()).unwrap();
+        assert_eq!(s.get_lp_shares(&user(1)), E8s::zero());
+
+        s.revert_remove_liquidity(user(1), shares.clone(), icp_out, satslink_out);
+
+        let pool = s.get_swap_pool();
+        assert_eq!(s.get_lp_shares(&user(1)), shares);
+        assert_eq!(pool.reserve_icp, pool_before.reserve_icp);
+        assert_eq!(pool.reserve_satslink, pool_before.reserve_satslink);
+        assert_eq!(pool.lp_total_supply, pool_before.lp_total_supply);
+    }
+
+    #[test]
+    fn failed_satslink_side_is_owed_as_is() {
+        let mut s = new_state();
+        let dev = user(9);
+
+        s.add_liquidity(dev, dev, e8s(3_0000_0000), e8s(300_0000_0000)).unwrap();
+        s.add_liquidity(user(1), dev, e8s(1_0000_0000), e8s(100_0000_0000)).unwrap();
+        let shares = s.get_lp_shares(&user(1));
+
+        let (_, satslink_out) = s.remove_liquidity(user(1), shares, E8s::zero(), E8s::zero()).unwrap();
+        assert_eq!(satslink_out, e8s(100_0000_0000));
+
+        // the ICP side went out, the SATSLINK one didn't
+        s.owe_lp_payout(user(1), satslink_out.clone());
+        assert_eq!(s.get_lp_payout(&user(1)), satslink_out);
+
+        let swappool_satslink = s
+            .get_reserve_liabilities()
+            .into_iter()
+            .find(|line| line.token == ReserveToken::SATSLINK && line.name == "swappool")
+            .unwrap()
+            .liability;
+        assert_eq!(swappool_satslink, &s.get_swap_pool().reserve_satslink + &satslink_out);
+
+        assert_eq!(s.take_lp_payout(&user(1)), Some(satslink_out));
+        assert_eq!(s.get_lp_payout(&user(1)), E8s::zero());
+    }
+
+    #[test]
+    fn pending_top_ups_survive_an_upgrade_and_are_swept_once_given_up() {
+        let memory = DefaultMemoryImpl::default();
+        let mut s = state_on(&memory);
+
+        let received = s.create_top_up(user(1), user(7), 1_0000_0000, 0);
+        let sent = s.create_top_up(user(1), user(7), 1_0000_0000, 0);
+        s.set_top_up_status(sent, TopUpStatus::SentToCmc { block_index: 5 });
+        let done = s.create_top_up(user(1), user(7), 1_0000_0000, 0);
+        s.set_top_up_status(done, TopUpStatus::Completed { cycles: Nat::from(1u64) });
+        s.prepare_top_up_reward(done, 0);
+        s.note_top_up_reward_paid(done);
+
+        for _ in 0..TOP_UP_MAX_ATTEMPTS {
+            s.note_top_up_attempt(sent);
+        }
+        drop(s);
+
+        let mut s = state_on(&memory);
+        assert_eq!(s.get_pending_top_up_ids(), vec![received, sent]);
+        assert_eq!(s.get_given_up_top_up_ids(), vec![sent]);
+
+        s.reset_top_up_attempts(sent);
+        assert!(s.get_given_up_top_up_ids().is_empty());
+    }
+
+    #[test]
+    fn failed_user_top_ups_are_refunded_from_where_the_icp_is() {
+        let mut s = new_state();
+
+        // the CMC transfer was rejected, the ICP is still in the top up subaccount
+        let before_cmc = s.create_top_up(user(1), user(7), 1_0000_0000, 0);
+        s.fail_top_up(before_cmc, String::from("rejected"));
+
+        // the CMC can't be notified anymore, the treasury makes up for the ICP stuck there
+        let after_cmc = s.create_top_up(user(1), user(7), 1_0000_0000, 0);
+        s.set_top_up_status(after_cmc, TopUpStatus::SentToCmc { block_index: 5 });
+        s.fail_top_up(after_cmc, String::from("Transaction too old"));
+
+        let refunded_by_cmc = s.create_top_up(user(1), user(7), 1_0000_0000, 0);
+        s.set_top_up_status(refunded_by_cmc, TopUpStatus::SentToCmc { block_index: 6 });
+        s.note_top_up_refunded_by_cmc(refunded_by_cmc, String::from("bad canister"));
+
+        // the canister's own top ups are paid by the treasury, there is nobody to refund
+        let own = s.create_cycles_top_up(user(9), SATSLINKER_DEV_FEE_SUBACCOUNT, 1_0000_0000, 0);
+        s.fail_top_up(own, String::from("rejected"));
+
+        let refund = |s: &SatslinkerState, id| s.get_top_up(id).unwrap().refund.unwrap();
+        assert_eq!(refund(&s, before_cmc).from_subaccount, SATSLINKER_TOPUP_SUBACCOUNT);
+        assert_eq!(refund(&s, before_cmc).qty_e8s, 1_0000_0000);
+        assert_eq!(refund(&s, after_cmc).from_subaccount, SATSLINKER_DEV_FEE_SUBACCOUNT);
+        assert_eq!(refund(&s, after_cmc).qty_e8s, 1_0000_0000 - ICP_FEE * 2);
+        assert_eq!(refund(&s, refunded_by_cmc).from_subaccount, SATSLINKER_TOPUP_SUBACCOUNT);
+        assert!(s.get_top_up(own).unwrap().refund.is_none());
+        assert_eq!(s.get_pending_top_up_ids(), vec![before_cmc, after_cmc, refunded_by_cmc]);
+
+        let liability = |s: &SatslinkerState, token, name: &str| s
+            .get_reserve_liabilities()
+            .into_iter()
+            .find(|line| line.token == token && line.name == name)
+            .unwrap()
+            .liability;
+        assert_eq!(liability(&s, ReserveToken::ICP, "topup"), E8s::from(2_0000_0000 - ICP_FEE * 2));
+        assert_eq!(liability(&s, ReserveToken::ICP, "dev"), E8s::from(1_0000_0000 - ICP_FEE * 2));
+
+        for id in [before_cmc, after_cmc, refunded_by_cmc] {
+            s.note_top_up_refund_sent(id, Nat::from(id));
+        }
+        assert!(s.get_pending_top_up_ids().is_empty());
+        assert_eq!(liability(&s, ReserveToken::ICP, "topup"), E8s::zero());
+    }
+
+    #[test]
+    fn own_cycles_top_up_is_pending_until_it_completes_or_fails() {
+        let mut s = new_state();
+        assert!(!s.has_pending_cycles_top_up());
+
+        let id = s.create_cycles_top_up(user(9), SATSLINKER_DEV_FEE_SUBACCOUNT, 1_0000_0000, 0);
+        assert!(s.has_pending_cycles_top_up());
+
+        s.set_top_up_status(id, TopUpStatus::SentToCmc { block_index: 1 });
+        assert!(s.has_pending_cycles_top_up());
+
+        // not rewarded, so it is done once the CMC has minted the cycles
+        s.set_top_up_status(id, TopUpStatus::Completed { cycles: Nat::from(1u64) });
+        assert!(!s.has_pending_cycles_top_up());
+        assert_eq!(s.prepare_top_up_reward(id, 0), None);
+
+        let id = s.create_cycles_top_up(user(9), SATSLINKER_DEV_FEE_SUBACCOUNT, 1_0000_0000, 0);
+        s.fail_top_up(id, String::from("rejected"));
+        assert!(!s.has_pending_cycles_top_up());
+    }
+
+    #[test]
+    fn unsettled_bets_survive_an_upgrade_and_settle_afterwards() {
+        let memory = DefaultMemoryImpl::default();
+        let mut s = state_on(&memory);
+        s.init(vec![0u8; 32]);
+
+        let qty = e8s(1_0000_0000);
+        let (max_payout, edge) = s.reserve_lottery_bet(&qty, &e8s(100_0000_0000)).unwrap();
+        let pending = s.create_lottery_bet(user(1), qty.clone(), edge, 0);
+        s.reserve_lottery_bet(&qty, &e8s(100_0000_0000)).unwrap();
+        let won = s.create_lottery_bet(user(2), qty.clone(), edge, 0);
+        s.roll_lottery_bet(won, 0, 1);
+        drop(s);
+
+        // the retry timers are gone, the bets and their reservations are not
+        let mut s = state_on(&memory);
+        assert_eq!(s.get_unsettled_lottery_bet_ids(), vec![pending, won]);
+        assert_eq!(s.get_info().get_lottery_reserved(), &max_payout + &max_payout);
+
+        let rolled = s.roll_lottery_bet(pending, BASIS_POINTS_BASE - 1, 2).unwrap();
+        assert!(matches!(rolled.status, LotteryBetStatus::Lost));
+        s.note_lottery_bet_paid(won);
+
+        assert!(s.get_unsettled_lottery_bet_ids().is_empty());
+        assert_eq!(s.get_info().get_lottery_reserved(), E8s::zero());
+        assert!(matches!(s.get_lottery_bet(won).unwrap().status, LotteryBetStatus::Paid { .. }));
+    }
+
+    #[test]
+    fn pledge_tickets_are_granted_at_maturity_and_never_on_early_unpledge() {
+        let mut s = new_state();
+        let period = pledge_period_ns(&s);
+        let draw_id = s.open_lottery_draw(vec![1u8; 32], period - 1);
+
+        s.mint_pledge_lot(e8s(10_0000_0000), PledgeLockPeriod::OneMonth, false, 0, user(1));
+        let lot_id = s.mint_pledge_lot(e8s(10_0000_0000), PledgeLockPeriod::ThreeMonths, false, 0, user(2));
+
+        assert_eq!(s.grant_matured_pledge_tickets(period - 1), 0);
+        assert_eq!(s.count_lottery_tickets_of(draw_id, &user(1)), 0);
+
+        s.unpledge_early(user(2), lot_id, period).unwrap();
+        assert_eq!(s.grant_matured_pledge_tickets(period), 10);
+        assert_eq!(s.grant_matured_pledge_tickets(period + 1), 0);
+
+        assert_eq!(s.count_lottery_tickets_of(draw_id, &user(1)), 10);
+        assert_eq!(s.count_lottery_tickets_of(draw_id, &user(2)), 0);
+    }
+
+    #[test]
+    fn withdrawing_a_matured_lot_grants_its_tickets_first() {
+        let mut s = new_state();
+        let period = pledge_period_ns(&s);
+        let draw_id = s.open_lottery_draw(vec![1u8; 32], period);
+
+        s.mint_pledge_lot(e8s(3_0000_0000), PledgeLockPeriod::OneMonth, false, 0, user(1));
+        s.withdraw_pledge_lots(user(1), e8s(3_0000_0000), period).unwrap();
+
+        assert!(s.get_pledge_lots(&user(1)).is_empty());
+        assert_eq!(s.count_lottery_tickets_of(draw_id, &user(1)), 3);
+    }
+
+    #[test]
+    fn sold_tickets_only_fund_the_draw_they_were_bought_for() {
+        let mut s = new_state();
+        let draw_id = s.open_lottery_draw(vec![1u8; 32], 0);
+        let ends_at = s.get_current_lottery_draw().unwrap().1.ends_at;
+
+        s.sell_lottery_tickets(user(1), draw_id, 2, &e8s(2_0000_0000), 0).unwrap();
+        assert_eq!(s.count_lottery_tickets_of(draw_id, &user(1)), 2);
+        assert_eq!(s.get_info().get_lottery_draw_pot(), e8s(2_0000_0000));
+
+        assert!(s.sell_lottery_tickets(user(1), draw_id, 1, &e8s(1_0000_0000), ends_at).is_err());
+
+        s.draw_lottery(draw_id, vec![2u8; 32], ends_at).unwrap();
+        s.open_lottery_draw(vec![3u8; 32], ends_at);
+        assert!(s.sell_lottery_tickets(user(1), draw_id, 1, &e8s(1_0000_0000), ends_at).is_err());
+
+        assert_eq!(s.count_lottery_tickets_of(draw_id, &user(1)), 2);
+        assert_eq!(s.get_info().get_lottery_draw_pot(), E8s::zero());
+    }
+
+    #[test]
+    fn a_draw_uses_the_seed_its_round_was_already_revealed_with() {
+        let mut s = new_state();
+        let draw_id = s.open_lottery_draw(vec![1u8; 32], 0);
+        s.sell_lottery_tickets(user(1), draw_id, 3, &e8s(3_0000_0000), 0).unwrap();
+        s.sell_lottery_tickets(user(2), draw_id, 4, &e8s(4_0000_0000), 0).unwrap();
+
+        let round_id = s.get_current_lottery_draw().unwrap().1.beacon_round.unwrap();
+        let output = s.reveal_beacon_round(round_id, vec![2u8; 32], 1).unwrap();
+
+        let draw = s.draw_lottery(draw_id, vec![3u8; 32], 2).unwrap();
+        assert_eq!(draw.winning_ticket, Some(winning_idx_from_seed(&output, 7)));
+        assert_eq!(draw.prize, Some(e8s(7_0000_0000)));
+        assert!(s.verify_lottery_draw(draw_id).unwrap().is_valid);
+        assert!(s.draw_lottery(draw_id, vec![4u8; 32], 3).is_none());
+    }
+
+    #[test]
+    fn the_seed_beacon_publishes_a_commitment_and_mixes_its_output_on_reveal() {
+        let mut s = new_state();
+        s.rotate_seed_beacon(vec![9u8; 32], vec![1u8; 32], 0);
+
+        let first_id = s.get_info().seed_beacon_round.unwrap();
+        let committed = s.get_public_beacon_round(first_id).unwrap();
+        assert!(committed.secret.is_none());
+        assert_eq!(committed.commitment, BeaconRound::compute_commitment(&[1u8; 32]));
+
+        let seed_before = s.get_info().seed.clone();
+        s.rotate_seed_beacon(vec![2u8; 32], vec![3u8; 32], 1);
+
+        let revealed = s.get_public_beacon_round(first_id).unwrap();
+        assert!(revealed.verify());
+        assert_eq!(revealed.output, Some(BeaconRound::compute_output(&[1u8; 32], &[2u8; 32])));
+
+        let mut expected = s.get_info();
+        expected.seed = seed_before;
+        expected.mix_seed(revealed.output.as_ref().unwrap());
+        assert_eq!(s.get_info().seed, expected.seed);
+        assert_ne!(s.get_info().seed_beacon_round, Some(first_id));
+
+        // a round can't be revealed again with other entropy
+        assert!(s.reveal_beacon_round(first_id, vec![4u8; 32], 2).is_none());
+        assert_eq!(s.get_public_beacon_round(first_id).unwrap().output, revealed.output);
+    }
+
+    #[test]
+    fn dev_fee_withdrawals_wait_out_the_timelock_and_cover_the_fee() {
+        let mut s = new_state();
+        let dev = user(1);
+        let timelock = s.get_info().get_dev_fee_timelock_ns(0);
+
+        let fee_only = e8s(crate::SATSLINK_FEE);
+        assert!(s.announce_dev_fee_withdrawal(DevFeeToken::SATSLINK, fee_only, user(9), e8s(10_0000_0000), dev, 0).is_err());
+
+        let id = s.announce_dev_fee_withdrawal(DevFeeToken::SATSLINK, e8s(6_0000_0000), user(9), e8s(10_0000_0000), dev, 0).unwrap();
+        assert!(s.announce_dev_fee_withdrawal(DevFeeToken::SATSLINK, e8s(5_0000_0000), user(9), e8s(10_0000_0000), dev, 0).is_err());
+
+        assert!(s.confirm_dev_fee_withdrawal(id, user(2), timelock - 1).is_err());
+        s.confirm_dev_fee_withdrawal(id, user(2), timelock).unwrap();
+        assert!(s.confirm_dev_fee_withdrawal(id, user(2), timelock).is_err());
+        assert_eq!(s.get_committed_dev_fee_withdrawals(DevFeeToken::SATSLINK), e8s(6_0000_0000));
+
+        s.complete_dev_fee_withdrawal(id, Err(String::from("ledger down")), user(2), timelock);
+        assert_eq!(s.get_committed_dev_fee_withdrawals(DevFeeToken::SATSLINK), E8s::zero());
+        assert!(matches!(s.get_dev_fee_audit_log()[0].1.event, DevFeeAuditEvent::Failed { withdrawal_id, .. } if withdrawal_id == id));
+    }
+
+    #[test]
+    fn dev_fee_ownership_is_handed_over_only_after_the_timelock_and_never_to_the_dev() {
+        let mut s = new_state();
+        let (dev, owner, next_owner) = (user(1), user(2), user(3));
+        let timelock = s.get_info().get_dev_fee_timelock_ns(0);
+
+        assert_eq!(s.get_info().get_dev_fee_owner(), None);
+        assert!(s.propose_dev_fee_owner(next_owner, dev, dev, 0).is_err());
+
+        s.set_dev_fee_owner(owner, dev, 0);
+        assert!(s.propose_dev_fee_owner(dev, dev, owner, 0).is_err());
+
+        let effective_at = s.propose_dev_fee_owner(next_owner, dev, owner, 0).unwrap();
+        assert_eq!(effective_at, timelock);
+        assert!(s.accept_dev_fee_owner(dev, effective_at).is_err());
+        assert!(s.accept_dev_fee_owner(next_owner, effective_at - 1).is_err());
+        assert_eq!(s.get_info().get_dev_fee_owner(), Some(owner));
+
+        s.accept_dev_fee_owner(next_owner, effective_at).unwrap();
+        assert_eq!(s.get_info().get_dev_fee_owner(), Some(next_owner));
+        assert_eq!(s.get_info().pending_dev_fee_owner, None);
+        assert!(matches!(s.get_dev_fee_audit_log()[0].1.event, DevFeeAuditEvent::OwnerChanged { owner } if owner == next_owner));
+    }
+
+    #[test]
+    fn unsent_redistribution_shares_are_carried_and_owed_first() {
+        let mut s = new_state();
+        let mut info = s.get_info();
+        info.set_redistribution_config(RedistributionConfig {
+            swappool_share_e8s: 5000_0000,
+            lottery_share_e8s: 3000_0000,
+            dev_share_e8s: 2000_0000,
+            min_balance_e8s: 1_000,
+        })
+        .unwrap();
+        s.set_info(info);
+
+        assert!(s.plan_redistribution(1_000).is_none());
+
+        let (plan, carry_reset) = s.plan_redistribution(10_001).unwrap();
+        assert!(!carry_reset);
+        assert_eq!(plan.iter().map(|(_, qty)| qty).sum::<u64>(), 10_001);
+        assert_eq!(plan[2], (RedistributionTarget::Dev, 2_001));
+
+        let outcomes = [
+            RedistributionOutcome::Sent { block_index: Nat::from(1u64) },
+            RedistributionOutcome::Carried,
+            RedistributionOutcome::Failed { reason: String::from("ledger down") },
+        ];
+        let transfers = plan
+            .iter()
+            .zip(outcomes)
+            .map(|((target, qty_e8s), outcome)| RedistributionTransfer { target: *target, qty_e8s: *qty_e8s, outcome })
+            .collect();
+        s.record_redistribution(10_001, carry_reset, transfers, 0);
+
+        let carry = s.get_info().get_redistribution_carry();
+        assert_eq!((carry.swappool_e8s, carry.lottery_e8s, carry.dev_e8s), (0, 3_000, 2_001));
+        assert_eq!(s.get_redistributions().len(), 1);
+
+        // the carried shares are still in the subaccount, only what came on top of them is split
+        let (plan, carry_reset) = s.plan_redistribution(5_001 + 10_000).unwrap();
+        assert!(!carry_reset);
+        assert_eq!(plan, vec![
+            (RedistributionTarget::SwapPool, 5_000),
+            (RedistributionTarget::Lottery, 3_000 + 3_000),
+            (RedistributionTarget::Dev, 2_001 + 2_000),
+        ]);
+
+        // a balance that can't cover the carry drops it instead of owing more than there is
+        let (plan, carry_reset) = s.plan_redistribution(4_000).unwrap();
+        assert!(carry_reset);
+        assert_eq!(plan.iter().map(|(_, qty)| qty).sum::<u64>(), 4_000);
+    }
+
+    #[test]
+    fn reserves_report_compares_every_subaccount_to_what_the_state_owes_from_it() {
+        let mut s = new_state();
+        s.mint_pledge_lot(e8s(10_0000_0000), PledgeLockPeriod::OneMonth, false, 0, user(1));
+        let draw_id = s.open_lottery_draw(vec![1u8; 32], 0);
+        s.sell_lottery_tickets(user(2), draw_id, 2, &e8s(2_0000_0000), 0).unwrap();
+        s.owe_lp_payout(user(3), e8s(1_0000_0000));
+
+        let mut lines = s.get_reserve_liabilities();
+        let liability = |lines: &[ReserveLine], token, name: &str| {
+            lines.iter().find(|line| line.token == token && line.name == name).unwrap().liability.clone()
+        };
+        assert_eq!(liability(&lines, ReserveToken::SATSLINK, "pledge"), e8s(10_0000_0000));
+        assert_eq!(liability(&lines, ReserveToken::SATSLINK, "lottery"), e8s(2_0000_0000));
+        assert_eq!(liability(&lines, ReserveToken::SATSLINK, "swappool"), e8s(1_0000_0000));
+        assert_eq!(liability(&lines, ReserveToken::ICP, "swappool"), E8s::zero());
+
+        // the pledge subaccount holds twice what is pledged, every other one exactly what it owes
+        for line in lines.iter_mut() {
+            let mut balance = line.liability.clone();
+            if line.name == "pledge" {
+                balance += &line.liability;
+            }
+            line.balance = Some(balance);
+        }
+
+        let report = s.store_reserves_report(lines.clone(), E8s::zero(), 7);
+        assert_eq!(report.satslink_solvency_ratio_bp, Some(BASIS_POINTS_BASE * 23 / 13));
+        assert_eq!(report.icp_solvency_ratio_bp, None);
+        assert_eq!(report.solvency_ratio_bp, report.satslink_solvency_ratio_bp);
+        assert_eq!(s.get_reserves_report().created_at, 7);
+
+        // a failed ledger call leaves the ratio unknown rather than understated
+        lines[0].balance = None;
+        assert_eq!(s.store_reserves_report(lines, E8s::zero(), 8).satslink_solvency_ratio_bp, None);
+    }
+
+    #[test]
+    fn an_accepted_account_transfer_moves_every_position() {
+        let mut s = new_state();
+        let (from, to) = (user(1), user(2));
+        let draw_id = s.open_lottery_draw(vec![1u8; 32], 0);
+
+        s.put_vip_share(from, [7u8; 20], 100, e8s(5));
+        let lot_id = s.mint_pledge_lot(e8s(10_0000_0000), PledgeLockPeriod::OneMonth, false, 0, from);
+        let (shares, _, _) = s.add_liquidity(from, from, e8s(1_0000_0000), e8s(1_0000_0000)).unwrap();
+        s.owe_lp_payout(from, e8s(3_0000));
+        s.owe_lp_payout(to, e8s(1_0000));
+        s.sell_lottery_tickets(user(3), draw_id, 1, &e8s(1_0000_0000), 0).unwrap();
+        s.sell_lottery_tickets(from, draw_id, 2, &e8s(2_0000_0000), 0).unwrap();
+
+        s.initiate_account_transfer(from, to, 0).unwrap();
+        assert!(s.accept_account_transfer(user(3), from, 0).is_err());
+        s.accept_account_transfer(to, from, 0).unwrap();
+
+        assert!(!s.has_account_positions(&from));
+        assert_eq!(s.vip_shares.get(&to), Some(([7u8; 20], 100, e8s(5))));
+        assert_eq!(s.get_pledge_lots(&to)[0].0, lot_id);
+        assert_eq!(s.get_lp_shares(&to), shares);
+        assert_eq!(s.get_lp_payout(&to), e8s(4_0000));
+        assert_eq!(s.count_lottery_tickets_of(draw_id, &to), 2);
+        assert_eq!(s.find_lottery_ticket_owner(draw_id, 1), Some(to));
+        assert_eq!(s.find_lottery_ticket_owner(draw_id, 0), Some(user(3)));
+    }
+
+    #[test]
+    fn an_account_with_unsettled_top_ups_is_not_moved_until_they_settle() {
+        let mut s = new_state();
+        let (from, to) = (user(1), user(2));
+
+        s.mint_pledge_lot(e8s(10_0000_0000), PledgeLockPeriod::OneMonth, false, 0, from);
+        s.initiate_account_transfer(from, to, 0).unwrap();
+
+        let id = s.create_top_up(from, user(7), 1_0000_0000, 0);
+        s.fail_top_up(id, String::from("CMC rejected"));
+        assert!(s.accept_account_transfer(to, from, 0).is_err());
+        assert!(s.get_pledge_lots(&to).is_empty());
+
+        // the refund is paid to the principal that made the top up, then the offer can be accepted
+        s.note_top_up_refund_sent(id, Nat::from(1u64));
+        s.accept_account_transfer(to, from, 0).unwrap();
+        assert_eq!(s.get_pledge_lots(&to).len(), 1);
+    }
+
+    #[test]
+    fn the_vip_address_index_follows_every_write_to_the_vip_store() {
+        let mut s = new_state();
+        let (a, b) = ([1u8; 20], [2u8; 20]);
+
+        s.put_vip_share(user(1), a, 10, E8s::zero());
+        s.put_vip_share(user(2), a, 20, E8s::zero());
+        assert!(s.vip_address_index.contains_key(&(a, user(1))));
+        assert!(s.vip_address_index.contains_key(&(a, user(2))));
+
+        // re-binding a principal to another address drops its old index entry
+        s.put_vip_share(user(1), b, 30, e8s(1));
+        assert!(!s.vip_address_index.contains_key(&(a, user(1))));
+        assert!(s.vip_address_index.contains_key(&(b, user(1))));
+        assert_eq!(s.vip_shares.get(&user(1)), Some((b, 30, e8s(1))));
+
+        assert_eq!(s.remove_vip_share(&user(2)), Some((a, 20, E8s::zero())));
+        assert!(!s.vip_address_index.contains_key(&(a, user(2))));
+        assert_eq!(s.vip_address_index.len(), s.vip_shares.len());
+        assert!(s.remove_vip_share(&user(2)).is_none());
+    }
+
+    #[test]
+    fn withdrawals_never_leave_a_lot_below_the_minimum_pledge() {
+        let mut s = new_state();
+        let period = pledge_period_ns(&s);
+        let min = SatslinkerStateInfo::get_min_pledge_share();
+
+        s.mint_pledge_lot(e8s(10_0000_0000), PledgeLockPeriod::OneMonth, false, 0, user(1));
+        let mut dusty = e8s(10_0000_0000);
+        dusty -= &min;
+        dusty += e8s(1);
+
+        assert!(s.withdraw_pledge_lots(user(1), dusty, period).is_err());
+        assert_eq!(s.get_pledge_lots(&user(1))[0].1.qty, e8s(10_0000_0000));
+
+        let mut keeps_min = e8s(10_0000_0000);
+        keeps_min -= &min;
+        s.withdraw_pledge_lots(user(1), keeps_min, period).unwrap();
+        assert_eq!(s.get_pledge_lots(&user(1))[0].1.qty, min);
+        assert!(s.check_invariants(period).violations.is_empty());
+    }
+
+    #[test]
+    fn invariant_violations_pause_reward_rounds_only_with_auto_pause() {
+        let mut s = new_state();
+        let mut dust = SatslinkerStateInfo::get_min_pledge_share();
+        dust -= e8s(1);
+        s.mint_pledge_lot(dust.clone(), PledgeLockPeriod::OneMonth, false, 0, user(1));
+
+        let report = s.check_invariants(1);
+        assert!(matches!(report.violations[..], [InvariantViolation::PledgeLotDust { lot_id: 0, .. }]));
+        assert_eq!(report.rounds_paused, s.get_info().is_invariant_auto_pause());
+        assert_eq!(s.get_invariant_report().checked_at, 1);
+
+        let mut info = s.get_info();
+        info.total_pledge_token_supply += e8s(1);
+        s.set_info(info);
+        assert!(s
+            .check_invariants(2)
+            .violations
+            .iter()
+            .any(|violation| matches!(violation, InvariantViolation::PledgeSupplyMismatch { .. })));
+    }
+
+    #[test]
+    fn blocks_are_hash_chained_and_survive_an_upgrade() {
+        let memory = DefaultMemoryImpl::default();
+        let mut s = state_on(&memory);
+
+        let first = s.append_block(BLOCK_TYPE_PLEDGE, vec![(String::from("amt"), Icrc3Value::nat(1u64))], 10);
+        s.mint_pledge_lot(e8s(10_0000_0000), PledgeLockPeriod::OneMonth, false, 20, user(1));
+        drop(s);
+
+        let s = state_on(&memory);
+        let blocks = s.get_blocks(first, 10);
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(s.get_block_tip(), Some((first + 1, blocks[1].1.hash())));
+
+        let field = |block: &Icrc3Value, name: &str| match block {
+            Icrc3Value::Map(entries) => entries.iter().find(|(key, _)| key == name).map(|(_, value)| value.clone()),
+            _ => None,
+        };
+        assert_eq!(field(&blocks[0].1, "phash"), None);
+        assert_eq!(field(&blocks[1].1, "phash"), Some(Icrc3Value::Blob(blocks[0].1.hash().to_vec())));
+        assert_eq!(field(&blocks[1].1, "btype"), Some(Icrc3Value::text(BLOCK_TYPE_PLEDGE)));
+        assert_eq!(field(&blocks[1].1, "ts"), Some(Icrc3Value::nat(20u64)));
+    }
+
+    #[test]
+    fn vip_status_witnesses_reconstruct_the_certified_root() {
+        let mut s = new_state();
+        s.append_block(BLOCK_TYPE_PLEDGE, vec![], 1);
+
+        let eth = String::from("0x00000000000000000000000000000000000000aa");
+        s.reset_certified_vip_expiry(vec![(eth.clone(), 500)], vec![(user(1), 700)]);
+
+        assert_eq!(s.get_certified_vip_expiry(Some(&eth), None), 500);
+        assert_eq!(s.get_certified_vip_expiry(None, Some(&user(1))), 700);
+        assert_eq!(s.get_certified_vip_expiry(None, Some(&user(2))), 0);
+
+        let root = fork(tip_hash_tree(s.get_block_tip()), s.vip_hash_tree(None, None)).reconstruct();
+        assert_eq!(s.vip_status_witness(Some(&eth), None).reconstruct(), root);
+        assert_eq!(s.vip_status_witness(None, Some(&user(2))).reconstruct(), root);
+        assert_eq!(s.block_tip_witness().reconstruct(), root);
+
+        // a rebuild drops what is no longer listed
+        s.reset_certified_vip_expiry(vec![], vec![(user(1), 800)]);
+        assert_eq!(s.get_certified_vip_expiry(Some(&eth), None), 0);
+        assert_eq!(s.get_certified_vip_expiry(None, Some(&user(1))), 800);
+        assert_ne!(fork(tip_hash_tree(s.get_block_tip()), s.vip_hash_tree(None, None)).reconstruct(), root);
+    }
+
+    #[test]
+    fn vip_attestations_are_signed_once_per_expiry_and_for_a_few_chains_at_most() {
+        let mut s = new_state();
+        let address = [5u8; 20];
+        let attestation = |chain_id: u64, vip_expires_at: u64| VipAttestation {
+            eth_address: String::new(),
+            vip_expires_at,
+            nonce: chain_id,
+            chain_id,
+            digest: Vec::new(),
+            signature: Vec::new(),
+            signer: String::new(),
+        };
+
+        assert!(s.get_vip_attestation(&address, 1, 100).unwrap().is_none());
+        s.store_vip_attestation(address, attestation(1, 100));
+        assert_eq!(s.get_vip_attestation(&address, 1, 100).unwrap().unwrap().nonce, 1);
+
+        for chain_id in 2..=MAX_VIP_ATTESTATION_CHAINS as u64 {
+            assert!(s.get_vip_attestation(&address, chain_id, 100).unwrap().is_none());
+            s.store_vip_attestation(address, attestation(chain_id, 100));
+        }
+        assert!(s.get_vip_attestation(&address, 99, 100).is_err());
+        assert!(s.get_vip_attestation(&[6u8; 20], 99, 100).unwrap().is_none());
+
+        // a longer VIP is worth a new signature
+        assert!(s.get_vip_attestation(&address, 1, 200).unwrap().is_none());
+    }
+
+    #[test]
+    fn rebuilding_the_vip_address_index_goes_to_the_log_buffer() {
+        let memory = DefaultMemoryImpl::default();
+        let mut s = state_on(&memory);
+        s.init(vec![0u8; 32]);
+        let a = [1u8; 20];
+
+        // a store written before the index existed
+        s.vip_shares.insert(user(1), (a, 10, E8s::zero()));
+        s.vip_participants.insert(a, (user(1), 10, E8s::zero()));
+
+        let mut s = state_on(&memory);
+        s.rebuild_vip_address_index(user(9), 7);
+        assert!(s.vip_participants.is_empty());
+        assert!(s.vip_address_index.contains_key(&(a, user(1))));
+
+        let vip_logs = |s: &SatslinkerState| {
+            s.get_logs(GetLogsRequest {
+                min_level: None,
+                subsystem: Some(String::from("vip")),
+                from: None,
+                to: None,
+                before_id: None,
+                limit: None,
+            })
+            .entries
+        };
+        let entries = vip_logs(&s);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].1.level, LogLevel::Info);
+        assert_eq!((entries[0].1.caller, entries[0].1.at), (user(9), 7));
+        assert_eq!(entries[0].1.message, "VIP address index rebuilt: 1 entries");
+
+        // already migrated, nothing to log after the next upgrade
+        s.rebuild_vip_address_index(user(9), 8);
+        assert_eq!(vip_logs(&s).len(), 1);
+
+        s.mint_vip_share(5, user(2), a, 9);
+        let entries = vip_logs(&s);
+        assert_eq!(entries.len(), 2);
+        assert_eq!((entries[0].1.level, entries[0].1.caller, entries[0].1.at), (LogLevel::Debug, user(2), 9));
+    }
+
+    #[test]
+    fn missed_rounds_are_caught_up_in_batches_at_the_times_they_were_due() {
+        let mut s = new_state();
+        let delay = s.get_info().pos_round_delay_ns;
+        let mut info = s.get_info();
+        info.init_pos_genesis(0);
+        s.set_info(info);
+
+        s.mint_pledge_lot(e8s(10_0000_0000), PledgeLockPeriod::OneMonth, false, 0, user(1));
+        // bought after the 20th round was due, the rounds before don't reward it
+        s.mint_vip_share(1_000_000_000, user(2), [2u8; 20], 20 * delay + 1);
+
+        let now = 25 * delay + 1;
+        assert_eq!(s.get_info().get_missed_pos_rounds(now), 25);
+
+        assert_eq!(s.catch_up_pos_rounds(now, 10), 10);
+        assert_eq!(s.catch_up_pos_rounds(now, 10), 10);
+        assert_eq!(s.get_info().current_pos_round, 20);
+        assert_eq!(s.get_info().get_missed_pos_rounds(now), 5);
+        assert_eq!(s.vip_shares.get(&user(2)).unwrap().2, E8s::zero());
+
+        assert_eq!(s.catch_up_pos_rounds(now, 10), 5);
+        assert!(s.vip_shares.get(&user(2)).unwrap().2 > E8s::zero());
+        assert!(s.get_pledge_lots(&user(1))[0].1.unclaimed_reward > E8s::zero());
+
+        // on schedule again, nothing runs until the next round is due
+        assert_eq!(s.catch_up_pos_rounds(now, 10), 0);
+        assert_eq!(s.get_info().get_expected_pos_rounds(now), s.get_info().current_pos_round);
+        assert_eq!(s.get_info().get_next_pos_round_at(), Some(26 * delay));
+        assert_eq!(s.catch_up_pos_rounds(26 * delay, 10), 1);
+    }
 }
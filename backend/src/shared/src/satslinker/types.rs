@@ -1,6 +1,4 @@
-use std::collections::BTreeSet;
-
-use candid::{CandidType, Principal};
+use candid::{CandidType, Nat, Principal};
 use ic_e8s::c::{E8s, ECs};
 use ic_stable_structures::{memory_manager::VirtualMemory, DefaultMemoryImpl};
 use serde::Deserialize;
@@ -8,7 +6,7 @@ use sha2::Digest;
 use num_bigint::BigUint;
 
 // use crate
-use crate::{cmc::XdrData, ONE_MINUTE_NS};
+use crate::{cmc::XdrData, CanisterMode, CYCLES_SATSLINKER_FEE, ENV_VARS, ICP_FEE, MIN_ICP_STAKE_E8S_U64, MIN_STL_LOTTERY_E8S_U64, SATSLINK_FEE, ONE_DAY_NS, ONE_HOUR_NS, ONE_MINUTE_NS, ONE_MONTH_NS, ONE_WEEK_NS};
 pub type Address = [u8; 20];
 pub type Timestamp = u64;
 pub type TCycles = ECs<12>;
@@ -16,33 +14,892 @@ pub type Memory = VirtualMemory<DefaultMemoryImpl>;
 
 pub const TCYCLE_POS_ROUND_BASE_FEE: u64 = 25_000_000_000_u64;
 pub const POS_ROUND_START_REWARD_E8S: u64 = 1024_0000_0000_u64;
-pub const POS_ROUND_END_REWARD_E8S: u64 = 0_0014_0000_u64;
+pub const POS_ROUND_END_REWARD_E8S: u64 = 14_0000_u64;
 pub const POS_ROUNDS_PER_HALVING: u64 = 5040;
-pub const POS_ACCOUNTS_PER_BATCH: u64 = 300;
+pub const POS_ACCOUNTS_PER_BATCH: u64 = 300;     // VIP positions and pledge lots rewarded per timer run, at least one round runs
+pub const POS_ROUND_CATCH_UP_BATCH: u64 = 10;  // missed rounds processed per timer run
 pub const UPDATE_SEED_DOMAIN: &[u8] = b"stl-satslink-update-seed";
+pub const BEACON_DOMAIN: &[u8] = b"stl-satslink-beacon";
 
 pub const SATSLINKER_REDISTRIBUTION_SUBACCOUNT: [u8; 32] = [0u8; 32];
 pub const SATSLINKER_LOTTERY_SUBACCOUNT: [u8; 32] = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1,];
 pub const SATSLINKER_DEV_FEE_SUBACCOUNT: [u8; 32] = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2,];
 pub const SATSLINKER_SWAPPOOL_SUBACCOUNT: [u8; 32] = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 3,];
+pub const SATSLINKER_PLEDGE_SUBACCOUNT: [u8; 32] = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 4,];
+pub const SATSLINKER_TOPUP_SUBACCOUNT: [u8; 32] = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 5,];
+
+pub const CMC_TOP_UP_MEMO: u64 = 0x50555054;                               // "TPUP"
+pub const TOP_UP_REWARD_MEMO: u32 = 0x54525744;                            // "TRWD", followed by the top up id
+pub const TOP_UP_MAX_ATTEMPTS: u32 = 10;
+pub const TOP_UP_RETRY_DELAY_NS: u64 = 1_000_000_000 * 10;                 // retry a pending top up every 10 seconds
+pub const TOP_UP_REFUND_MEMO: u32 = 0x54524644;                            // "TRFD", followed by the top up id
+pub const TOP_UP_SWEEP_INTERVAL_NS: u64 = ONE_HOUR_NS;                     // restart the top ups that ran out of attempts every hour
+
+pub const CYCLES_MONITOR_INTERVAL_NS: u64 = ONE_MINUTE_NS * 10;            // check own cycles balance every 10 minutes
+pub const DEFAULT_CYCLES_THRESHOLD: u128 = 5_000_000_000_000;              // top up below 5T cycles
+pub const DEFAULT_CYCLES_TOP_UP_E8S: u64 = 1_0000_0000;                    // burn 1 ICP per top up
+
+pub const DEFAULT_DEV_FEE_TIMELOCK_NS: u64 = ONE_DAY_NS * 2;                // announced dev fee withdrawals wait 2 days
+pub const MIN_DEV_FEE_TIMELOCK_NS: u64 = ONE_DAY_NS;                        // the timelock can't be lowered below 1 day
+pub const DEV_FEE_AUDIT_LOG_LIMIT: usize = 200;
 
 pub const REDISTRIBUTION_LOTTERY_SHARE_E8S: u64 = 1000_0000;    // 10%
 pub const REDISTRIBUTION_SWAPPOOL_SHARE_E8S: u64 = 6000_0000;  // 60%
 pub const REDISTRIBUTION_DEV_SHARE_E8S: u64 = 3000_0000;      // 30%
+pub const REDISTRIBUTION_MIN_BALANCE_E8S: u64 = 1_0000_0000;  // redistribute once more than 1 ICP is collected
+pub const REDISTRIBUTION_HISTORY_LIMIT: usize = 100;
+pub const ACCOUNT_TRANSFER_TTL_NS: u64 = ONE_DAY_NS;                      // the destination has a day to accept
+pub const RESERVES_REPORT_MIN_INTERVAL_NS: u64 = ONE_MINUTE_NS * 10;     // a fresh reserves report at most every 10 minutes
+pub const RESERVES_REPORT_INTERVAL_NS: u64 = ONE_HOUR_NS;                  // the timer refreshes the reserves report every hour
+pub const INVARIANT_CHECK_INTERVAL_NS: u64 = ONE_HOUR_NS;                 // check the accounting invariants every hour
+pub const TIMER_SUPERVISOR_INTERVAL_NS: u64 = ONE_MINUTE_NS * 5;          // look for dead timer chains every 5 minutes
+pub const TIMER_JOB_GRACE_NS: u64 = ONE_MINUTE_NS * 30;                   // a job late for longer is re-armed
+pub const LOG_BUFFER_LIMIT: u64 = 10_000;                                 // the oldest entries are dropped past this
+pub const MAX_LOGS_PER_REQUEST: u64 = 200;
+
+pub const ECDSA_KEY_NAME_DEV: &str = "dfx_test_key";                   // the local replica's test key
+pub const ECDSA_KEY_NAME_IC: &str = "key_1";
+pub const MAX_VIP_ATTESTATION_CHAINS: usize = 4;                           // signed attestations per address and VIP expiry
+
+// ICRC-3 block types of the canister activity log
+pub const BLOCK_TYPE_PAY: &str = "stl_pay";
+pub const BLOCK_TYPE_PLEDGE: &str = "stl_pledge";
+pub const BLOCK_TYPE_CLAIM: &str = "stl_claim";
+pub const BLOCK_TYPE_CLAIM_REVERT: &str = "stl_claim_revert";
+pub const BLOCK_TYPE_BET: &str = "stl_bet";
+pub const BLOCK_TYPE_BET_RESULT: &str = "stl_bet_result";
+pub const BLOCK_TYPE_REDISTRIBUTE: &str = "stl_redistribute";
+pub const BLOCK_TYPES: [&str; 7] = [
+    BLOCK_TYPE_PAY,
+    BLOCK_TYPE_PLEDGE,
+    BLOCK_TYPE_CLAIM,
+    BLOCK_TYPE_CLAIM_REVERT,
+    BLOCK_TYPE_BET,
+    BLOCK_TYPE_BET_RESULT,
+    BLOCK_TYPE_REDISTRIBUTE,
+];
+
+pub const MIN_TIMER_INTERVAL_NS: u64 = 1_000_000_000;                     // no network profile timer fires more often than once a second
+
+pub const PLEDGE_MULTIPLIER_BASE_BP: u64 = 10_000;                          // 1x reward weight, in basis points
+pub const BASIS_POINTS_BASE: u64 = 10_000;                                 // 100%
+pub const DEFAULT_EARLY_UNPLEDGE_MAX_PENALTY_BP: u64 = 2_000;              // 20% when exiting right after pledging
+pub const DEFAULT_EARLY_UNPLEDGE_TREASURY_SHARE_BP: u64 = 5_000;           // 50% of the penalty goes to the treasury
+pub const DEFAULT_SWAP_FEE_BP: u64 = 30;                                   // 0.3% of the input, stays in the pool
+pub const DEFAULT_LOTTERY_HOUSE_EDGE_BP: u64 = 500;                        // a won bet pays 2x minus 5%
+pub const LOTTERY_WIN_CHANCE_BP: u64 = 5_000;                              // 50% of rolls win
+pub const LOTTERY_MAX_ATTEMPTS: u32 = 10;
+pub const LOTTERY_RETRY_DELAY_NS: u64 = 1_000_000_000 * 10;                // retry an unsettled bet every 10 seconds
+pub const LOTTERY_BET_MEMO: u32 = 0x4c424554;                             // "LBET", followed by the bet id
+pub const LOTTERY_PRIZE_MEMO: u32 = 0x4c50525a;                           // "LPRZ", followed by the draw id
+pub const DEFAULT_LOTTERY_TICKET_PRICE_E8S: u64 = 1_0000_0000;             // 1 SATSLINK per draw ticket
+pub const LOTTERY_DRAWS_HISTORY_LIMIT: usize = 100;
+
+// Timings that differ between a local replica and mainnet, stored in the state info.
+// Defaults to the profile of the build's CanisterMode, can be supplied at install or upgrade.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct NetworkProfile {
+    pub pos_round_delay_ns: u64,          // one PoS round
+    pub vip_unit_ns: u64,                 // VIP expiry is stored in these units, can't be changed later
+    pub price_update_interval_ns: u64,    // ICP/Cycles exchange rate refresh
+    pub redistribution_interval_ns: u64,  // ICP redistribution
+    pub pledge_period_ns: u64,            // a "month" of a pledge lockup
+    pub lottery_draw_interval_ns: u64,
+    pub beacon_interval_ns: u64,          // seed beacon reveal and re-commit
+}
+
+impl NetworkProfile {
+    pub fn mainnet() -> Self {
+        Self {
+            pos_round_delay_ns: ONE_MINUTE_NS * 2,          // Generate 1 block every 2 minutes
+            vip_unit_ns: 1_000_000_000,                     // VIP expiry in seconds
+            price_update_interval_ns: ONE_MINUTE_NS * 10,   // Update ICP/Cycles exchange rate every 10 minutes
+            redistribution_interval_ns: ONE_HOUR_NS * 3,    // Redistribute ICP every 3 hours
+            pledge_period_ns: ONE_MONTH_NS,                 // 1 month pledge cycle
+            lottery_draw_interval_ns: ONE_WEEK_NS,          // 1 lottery draw every week
+            beacon_interval_ns: ONE_MINUTE_NS * 10,         // Reveal and re-commit the seed beacon every 10 minutes
+        }
+    }
+
+    pub fn local() -> Self {
+        Self {
+            pos_round_delay_ns: ONE_MINUTE_NS / 10,         // Generate 1 block every 6 seconds
+            vip_unit_ns: 1_000_000_000,                     // VIP expiry in seconds
+            price_update_interval_ns: ONE_MINUTE_NS,        // Update ICP/Cycles exchange rate every minute
+            redistribution_interval_ns: ONE_MINUTE_NS,      // Redistribute ICP every minute
+            pledge_period_ns: ONE_MINUTE_NS * 1000,         // 1000 minutes pledge cycle
+            lottery_draw_interval_ns: ONE_HOUR_NS,          // 1 lottery draw every hour
+            beacon_interval_ns: ONE_MINUTE_NS,              // Reveal and re-commit the seed beacon every minute
+        }
+    }
+
+    pub fn for_mode(mode: &CanisterMode) -> Self {
+        match mode {
+            CanisterMode::IC => Self::mainnet(),
+            CanisterMode::Dev => Self::local(),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let intervals = [
+            ("pos_round_delay_ns", self.pos_round_delay_ns),
+            ("price_update_interval_ns", self.price_update_interval_ns),
+            ("redistribution_interval_ns", self.redistribution_interval_ns),
+            ("lottery_draw_interval_ns", self.lottery_draw_interval_ns),
+            ("beacon_interval_ns", self.beacon_interval_ns),
+        ];
+        for (name, value) in intervals {
+            if value < MIN_TIMER_INTERVAL_NS {
+                return Err(format!("{} should be at least {} ns", name, MIN_TIMER_INTERVAL_NS));
+            }
+        }
+
+        if self.vip_unit_ns == 0 {
+            return Err(String::from("vip_unit_ns can't be zero"));
+        }
+        if self.pledge_period_ns < self.pos_round_delay_ns {
+            return Err(String::from("pledge_period_ns should last at least one PoS round"));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PledgeLockPeriod {
+    #[default]
+    OneMonth,
+    ThreeMonths,
+    SixMonths,
+    TwelveMonths,
+}
+
+impl PledgeLockPeriod {
+    // a "month" is one pledge period of the network profile
+    pub fn duration_ns(&self, pledge_period_ns: u64) -> u64 {
+        match self {
+            PledgeLockPeriod::OneMonth => pledge_period_ns,
+            PledgeLockPeriod::ThreeMonths => pledge_period_ns * 3,
+            PledgeLockPeriod::SixMonths => pledge_period_ns * 6,
+            PledgeLockPeriod::TwelveMonths => pledge_period_ns * 12,
+        }
+    }
+
+    // longer lockups weigh more when the pledge reward is split
+    pub fn multiplier_bp(&self) -> u64 {
+        match self {
+            PledgeLockPeriod::OneMonth => 10_000,     // 1x
+            PledgeLockPeriod::ThreeMonths => 12_500,  // 1.25x
+            PledgeLockPeriod::SixMonths => 15_000,    // 1.5x
+            PledgeLockPeriod::TwelveMonths => 20_000, // 2x
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Default, Debug)]
+pub struct PledgeLot {
+    pub qty: E8s,
+    pub started_at: Timestamp,
+    pub lock_period: PledgeLockPeriod,
+    pub unclaimed_reward: E8s,
+    pub auto_compound: Option<bool>,
+    // None for lots that got their draw tickets when pledged, before tickets waited for the lock to end
+    pub tickets_granted: Option<bool>,
+}
+
+impl PledgeLot {
+    pub fn is_auto_compound(&self) -> bool {
+        self.auto_compound.unwrap_or_default()
+    }
+
+    pub fn has_pending_tickets(&self) -> bool {
+        self.tickets_granted == Some(false)
+    }
+
+    pub fn unlocks_at(&self, pledge_period_ns: u64) -> Timestamp {
+        self.started_at + self.lock_period.duration_ns(pledge_period_ns)
+    }
+
+    pub fn is_locked(&self, now: Timestamp, pledge_period_ns: u64) -> bool {
+        now < self.unlocks_at(pledge_period_ns)
+    }
+
+    pub fn reward_weight(&self) -> E8s {
+        E8s::new(
+            self.qty.val.clone() * BigUint::from(self.lock_period.multiplier_bp())
+                / BigUint::from(PLEDGE_MULTIPLIER_BASE_BP),
+        )
+    }
+
+    // decays linearly from the maximum to zero over the lock period
+    pub fn early_unpledge_penalty_bp(&self, now: Timestamp, pledge_period_ns: u64, max_penalty_bp: u64) -> u64 {
+        let remaining_lock_ns = self.unlocks_at(pledge_period_ns).saturating_sub(now) as u128;
+        let lock_duration_ns = self.lock_period.duration_ns(pledge_period_ns) as u128;
+        if lock_duration_ns == 0 {
+            return 0;
+        }
+
+        (max_penalty_bp as u128 * remaining_lock_ns.min(lock_duration_ns) / lock_duration_ns) as u64
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct EarlyUnpledgeQuote {
+    pub lot_id: u64,
+    pub qty: E8s,
+    pub penalty_bp: u64,
+    pub penalty: E8s,
+    pub to_pledgers: E8s,
+    pub to_treasury: E8s,
+    pub payout: E8s,
+}
+
+impl EarlyUnpledgeQuote {
+    // if nobody else is locked, the whole penalty goes to the treasury
+    pub fn new(lot_id: u64, qty: E8s, penalty_bp: u64, treasury_share_bp: u64, has_other_locked_lots: bool) -> Self {
+        let bp_base = BigUint::from(BASIS_POINTS_BASE);
+        let penalty = E8s::new(qty.val.clone() * BigUint::from(penalty_bp) / bp_base.clone());
+
+        let to_pledgers = if has_other_locked_lots {
+            let pledgers_share_bp = BASIS_POINTS_BASE - treasury_share_bp;
+            E8s::new(penalty.val.clone() * BigUint::from(pledgers_share_bp) / bp_base)
+        } else {
+            E8s::zero()
+        };
+
+        let mut to_treasury = penalty.clone();
+        to_treasury -= &to_pledgers;
 
-// pub const VIP_ROUND_DELAY_NS: u64 = 1_000_000_000; // VIP rounds in minutes
-// pub const POS_ROUND_DELAY_NS: u64 = ONE_MINUTE_NS * 2;                  // Generate 1 block every 2 minutes
-// pub const ICPSWAP_PRICE_UPDATE_INTERVAL_NS: u64 = ONE_MINUTE_NS * 10;   // Update ICP/Cycles exchange rate every 10 minutes
-// pub const ICP_REDISTRIBUTION_INTERVAL_NS: u64 = ONE_HOUR_NS * 3;        // Redistribute ICP every 3 hours
-// pub const PLEDGE_ROUND_DELAY_NS: u64 = ONE_MONTH_NS;                    // 1 month pledge cycle
+        let mut payout = qty.clone();
+        payout -= &penalty;
+
+        Self {
+            lot_id,
+            qty,
+            penalty_bp,
+            penalty,
+            to_pledgers,
+            to_treasury,
+            payout,
+        }
+    }
+}
+
+// What an early unpledge changed, so it can be reverted if the payout transfer fails
+#[derive(Clone, Debug)]
+pub struct EarlyUnpledgeReceipt {
+    pub quote: EarlyUnpledgeQuote,
+    pub lot: PledgeLot,
+    pub credited_lots: Vec<((Principal, u64), E8s)>,
+}
 
-// Test time constants
-pub const VIP_ROUND_DELAY_NS: u64 = 1_000_000_000;                        // VIP rounds in minutes
-pub const POS_ROUND_DELAY_NS: u64 = ONE_MINUTE_NS / 10;                   // Generate 1 block every 6 seconds
-pub const ICPSWAP_PRICE_UPDATE_INTERVAL_NS: u64 = ONE_MINUTE_NS * 1;      // Update ICP/Cycles exchange rate every 1 minute
-pub const ICP_REDISTRIBUTION_INTERVAL_NS: u64 = ONE_MINUTE_NS * 1;        // Redistribute ICP every 1 minute
-pub const PLEDGE_ROUND_DELAY_NS: u64 = ONE_MINUTE_NS * 1000;               // 1000 minutes pledge cycle
 
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SwapDirection {
+    IcpToSatslink,
+    SatslinkToIcp,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SwapQuote {
+    pub direction: SwapDirection,
+    pub amount_in: E8s,
+    pub fee: E8s,
+    pub amount_out: E8s,
+}
+
+// SATSLINK/ICP constant-product pool, both reserves are held on the swappool subaccount
+#[derive(CandidType, Deserialize, Clone, Default, Debug)]
+pub struct SwapPoolInfo {
+    pub reserve_icp: E8s,
+    pub reserve_satslink: E8s,
+    pub lp_total_supply: E8s,
+    pub swap_fee_bp: Option<u64>,
+}
+
+impl SwapPoolInfo {
+    pub fn get_swap_fee_bp(&self) -> u64 {
+        self.swap_fee_bp.unwrap_or(DEFAULT_SWAP_FEE_BP)
+    }
+
+    pub fn set_swap_fee_bp(&mut self, swap_fee_bp: u64) -> Result<(), String> {
+        if swap_fee_bp >= BASIS_POINTS_BASE {
+            return Err(format!("The swap fee should be less than {}", BASIS_POINTS_BASE));
+        }
+
+        self.swap_fee_bp = Some(swap_fee_bp);
+
+        Ok(())
+    }
+
+    // (reserve in, reserve out) for the given direction
+    pub fn reserves(&self, direction: SwapDirection) -> (E8s, E8s) {
+        match direction {
+            SwapDirection::IcpToSatslink => (self.reserve_icp.clone(), self.reserve_satslink.clone()),
+            SwapDirection::SatslinkToIcp => (self.reserve_satslink.clone(), self.reserve_icp.clone()),
+        }
+    }
+
+    pub fn set_reserves(&mut self, direction: SwapDirection, reserve_in: E8s, reserve_out: E8s) {
+        match direction {
+            SwapDirection::IcpToSatslink => {
+                self.reserve_icp = reserve_in;
+                self.reserve_satslink = reserve_out;
+            }
+            SwapDirection::SatslinkToIcp => {
+                self.reserve_satslink = reserve_in;
+                self.reserve_icp = reserve_out;
+            }
+        }
+    }
+
+    // x * y = k, the fee is taken from the input and stays in the pool
+    pub fn quote(&self, direction: SwapDirection, amount_in: &E8s) -> Result<SwapQuote, String> {
+        let (reserve_in, reserve_out) = self.reserves(direction);
+
+        if reserve_in == E8s::zero() || reserve_out == E8s::zero() {
+            return Err(String::from("The swap pool has no liquidity"));
+        }
+
+        if *amount_in == E8s::zero() {
+            return Err(String::from("Nothing to swap"));
+        }
+
+        let fee = E8s::new(
+            amount_in.val.clone() * BigUint::from(self.get_swap_fee_bp()) / BigUint::from(BASIS_POINTS_BASE),
+        );
+        let mut amount_in_after_fee = amount_in.clone();
+        amount_in_after_fee -= &fee;
+
+        let amount_out = E8s::new(
+            amount_in_after_fee.val.clone() * reserve_out.val / (reserve_in.val + amount_in_after_fee.val),
+        );
+
+        Ok(SwapQuote {
+            direction,
+            amount_in: amount_in.clone(),
+            fee,
+            amount_out,
+        })
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum TopUpStatus {
+    Received,
+    SentToCmc { block_index: u64 },
+    Completed { cycles: Nat },
+    Refunded { reason: String },
+    Failed { reason: String },
+}
+
+// ICP sent by `from` to be burned into cycles for `canister_id`
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TopUpRecord {
+    pub from: Principal,
+    pub canister_id: Principal,
+    pub qty_e8s: u64,
+    pub created_at: Timestamp,
+    pub status: TopUpStatus,
+    pub attempts: u32,
+    pub reward: Option<E8s>,
+    pub source_subaccount: Option<[u8; 32]>, // None - user ICP held in the top up subaccount
+    pub reward_pending_since: Option<Timestamp>, // creation time of the reward transfer until it is confirmed
+    pub refund: Option<TopUpRefund>, // ICP owed back to the user of a refunded or failed top up
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TopUpRefund {
+    pub from_subaccount: [u8; 32],
+    pub qty_e8s: u64, // leaves the subaccount, the ledger fee included
+    pub block_index: Option<Nat>, // set once sent
+}
+
+impl TopUpRefund {
+    // nothing is owed if the amount can't cover the fee
+    pub fn new(from_subaccount: [u8; 32], qty_e8s: u64) -> Option<Self> {
+        if qty_e8s <= ICP_FEE {
+            return None;
+        }
+
+        Some(Self {
+            from_subaccount,
+            qty_e8s,
+            block_index: None,
+        })
+    }
+}
+
+impl TopUpRecord {
+    // one SATSLINK for every CYCLES_SATSLINKER_FEE cycles the ICP buys at the current rate
+    pub fn calculate_reward(&self, icp_to_cycles_exchange_rate: &TCycles) -> E8s {
+        E8s::new(
+            BigUint::from(self.qty_e8s) * icp_to_cycles_exchange_rate.val.clone()
+                / BigUint::from(CYCLES_SATSLINKER_FEE),
+        )
+    }
+
+    pub fn is_final(&self) -> bool {
+        match self.status {
+            TopUpStatus::Completed { .. } => self.reward.is_some() && self.reward_pending_since.is_none(),
+            TopUpStatus::Refunded { .. } | TopUpStatus::Failed { .. } => {
+                self.refund.as_ref().is_none_or(|refund| refund.block_index.is_some())
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum LotteryBetStatus {
+    Pending,
+    Won { payout: E8s },
+    Paid { payout: E8s },
+    Lost,
+}
+
+// SATSLINK put at stake by `player`, the outcome is rolled only after the stake is in the lottery subaccount
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct LotteryBet {
+    pub player: Principal,
+    pub qty: E8s,
+    pub house_edge_bp: u64,
+    pub placed_at: Timestamp,
+    pub status: LotteryBetStatus,
+    pub roll: Option<u64>,
+    pub attempts: u32,
+}
+
+impl LotteryBet {
+    pub fn max_payout(qty: &E8s, house_edge_bp: u64) -> E8s {
+        E8s::new(
+            qty.val.clone() * BigUint::from(2u64) * BigUint::from(BASIS_POINTS_BASE - house_edge_bp)
+                / BigUint::from(BASIS_POINTS_BASE),
+        )
+    }
+
+    pub fn is_win(roll: u64) -> bool {
+        roll < LOTTERY_WIN_CHANCE_BP
+    }
+}
+
+// A scheduled draw, tickets are numbered 0..tickets_total in the order they were issued
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct LotteryDraw {
+    pub started_at: Timestamp,
+    pub ends_at: Timestamp,
+    pub tickets_total: u64,
+    pub drawn_at: Option<Timestamp>,
+    pub winning_ticket: Option<u64>,
+    pub winner: Option<Principal>,
+    pub prize: Option<E8s>,
+    pub paid: bool,
+    pub beacon_round: Option<u64>, // committed when the draw opens, revealed when it is drawn
+}
+
+impl LotteryDraw {
+    pub fn is_unpaid(&self) -> bool {
+        self.winner.is_some() && !self.paid
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RedistributionTarget {
+    SwapPool,
+    Lottery,
+    Dev,
+}
+
+impl RedistributionTarget {
+    pub fn subaccount(&self) -> [u8; 32] {
+        match self {
+            RedistributionTarget::SwapPool => SATSLINKER_SWAPPOOL_SUBACCOUNT,
+            RedistributionTarget::Lottery => SATSLINKER_LOTTERY_SUBACCOUNT,
+            RedistributionTarget::Dev => SATSLINKER_DEV_FEE_SUBACCOUNT,
+        }
+    }
+}
+
+// Shares are fractions of 1_0000_0000 (100%)
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct RedistributionConfig {
+    pub swappool_share_e8s: u64,
+    pub lottery_share_e8s: u64,
+    pub dev_share_e8s: u64,
+    pub min_balance_e8s: u64,
+}
+
+impl Default for RedistributionConfig {
+    fn default() -> Self {
+        Self {
+            swappool_share_e8s: REDISTRIBUTION_SWAPPOOL_SHARE_E8S,
+            lottery_share_e8s: REDISTRIBUTION_LOTTERY_SHARE_E8S,
+            dev_share_e8s: REDISTRIBUTION_DEV_SHARE_E8S,
+            min_balance_e8s: REDISTRIBUTION_MIN_BALANCE_E8S,
+        }
+    }
+}
+
+impl RedistributionConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        let total = self.swappool_share_e8s as u128 + self.lottery_share_e8s as u128 + self.dev_share_e8s as u128;
+        if total != 1_0000_0000 {
+            return Err(String::from("Shares should add up to 1_0000_0000 (100%)"));
+        }
+
+        Ok(())
+    }
+
+    // the last share takes the rounding remainder, so nothing is left unassigned
+    pub fn split(&self, qty_e8s: u64) -> [(RedistributionTarget, u64); 3] {
+        let to_swappool = (qty_e8s as u128 * self.swappool_share_e8s as u128 / 1_0000_0000) as u64;
+        let to_lottery = (qty_e8s as u128 * self.lottery_share_e8s as u128 / 1_0000_0000) as u64;
+        let to_dev = qty_e8s - to_swappool - to_lottery;
+
+        [
+            (RedistributionTarget::SwapPool, to_swappool),
+            (RedistributionTarget::Lottery, to_lottery),
+            (RedistributionTarget::Dev, to_dev),
+        ]
+    }
+}
+
+// Shares that were too small to cover the fee or failed to transfer, owed on the next run
+#[derive(CandidType, Deserialize, Clone, Default, Debug)]
+pub struct RedistributionCarry {
+    pub swappool_e8s: u64,
+    pub lottery_e8s: u64,
+    pub dev_e8s: u64,
+}
+
+impl RedistributionCarry {
+    pub fn get(&self, target: RedistributionTarget) -> u64 {
+        match target {
+            RedistributionTarget::SwapPool => self.swappool_e8s,
+            RedistributionTarget::Lottery => self.lottery_e8s,
+            RedistributionTarget::Dev => self.dev_e8s,
+        }
+    }
+
+    pub fn add(&mut self, target: RedistributionTarget, qty_e8s: u64) {
+        match target {
+            RedistributionTarget::SwapPool => self.swappool_e8s += qty_e8s,
+            RedistributionTarget::Lottery => self.lottery_e8s += qty_e8s,
+            RedistributionTarget::Dev => self.dev_e8s += qty_e8s,
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.swappool_e8s + self.lottery_e8s + self.dev_e8s
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum RedistributionOutcome {
+    Sent { block_index: Nat },
+    Carried,
+    Failed { reason: String },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct RedistributionTransfer {
+    pub target: RedistributionTarget,
+    pub qty_e8s: u64,
+    pub outcome: RedistributionOutcome,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct RedistributionRecord {
+    pub at: Timestamp,
+    pub balance_e8s: u64,
+    pub carry_reset: bool, // the subaccount held less than the carried shares, they were split anew
+    pub transfers: Vec<RedistributionTransfer>,
+}
+
+// An offer to move everything the source account holds to `to`
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AccountTransfer {
+    pub to: Principal,
+    pub created_at: Timestamp,
+    pub expires_at: Timestamp,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReserveToken {
+    ICP,
+    SATSLINK,
+}
+
+// The ledger balance of one canister subaccount against what the state says it owes from it
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ReserveLine {
+    pub token: ReserveToken,
+    pub name: String,
+    pub subaccount: [u8; 32],
+    pub balance: Option<E8s>, // None - the ledger call failed
+    pub liability: E8s,
+}
+
+#[derive(CandidType, Deserialize, Clone, Default, Debug)]
+pub struct ReservesReport {
+    pub created_at: Timestamp,
+    pub lines: Vec<ReserveLine>,
+    pub satslink_pending_mint: E8s,       // counted as minted, not on the ledger yet
+    pub pledge_unclaimed_rewards: E8s,    // minted on claim
+    pub vip_unclaimed_rewards: E8s,       // minted on claim
+    pub icp_paid_in: E8s,                 // ICP received through `pay`
+    pub icp_solvency_ratio_bp: Option<u64>,
+    pub satslink_solvency_ratio_bp: Option<u64>,
+    pub solvency_ratio_bp: Option<u64>,   // the lower of the two
+}
+
+impl ReservesReport {
+    pub fn compute_solvency_ratio_bp(&self, token: ReserveToken) -> Option<u64> {
+        let mut balance = E8s::zero();
+        let mut liability = E8s::zero();
+
+        for line in self.lines.iter().filter(|line| line.token == token) {
+            balance += line.balance.as_ref()?;
+            liability += &line.liability;
+        }
+
+        // None if nothing is owed or a balance is missing
+        if liability == E8s::zero() {
+            return None;
+        }
+
+        u64::try_from(&(balance.val * BigUint::from(BASIS_POINTS_BASE) / liability.val)).ok()
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum InvariantViolation {
+    PledgeSupplyMismatch { recorded: E8s, actual: E8s },   // total_pledge_token_supply vs the sum of pledge positions
+    MintedMismatch { recorded: E8s, expected: E8s },       // total_token_minted vs claimed rewards plus pool transfers
+    VipIndexWithoutShare { address: Address, owner: Principal },
+    VipShareNotIndexed { owner: Principal, address: Address },
+    LegacyVipParticipant { address: Address, owner: Principal },
+    PledgeLotDust { owner: Principal, lot_id: u64, qty: E8s },
+}
+
+#[derive(CandidType, Deserialize, Clone, Default, Debug)]
+pub struct InvariantReport {
+    pub checked_at: Timestamp,
+    pub violations: Vec<InvariantViolation>,
+    pub rounds_paused: bool,
+}
+
+// the self re-arming timer chains watched by the supervisor
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TimerJob {
+    PosRound,
+    CyclesExchangeRate,
+    IcpRedistribution,
+    CleanExpiredPayments,
+    CyclesMonitor,
+    RandomnessBeacon,
+    LotteryDraw,
+    InvariantCheck,
+    ReservesReport,
+    TopUpSweep,
+}
+
+impl TimerJob {
+    pub const ALL: [TimerJob; 10] = [
+        TimerJob::PosRound,
+        TimerJob::CyclesExchangeRate,
+        TimerJob::IcpRedistribution,
+        TimerJob::CleanExpiredPayments,
+        TimerJob::CyclesMonitor,
+        TimerJob::RandomnessBeacon,
+        TimerJob::LotteryDraw,
+        TimerJob::InvariantCheck,
+        TimerJob::ReservesReport,
+        TimerJob::TopUpSweep,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            TimerJob::PosRound => "lottery_and_pos_and_pledge",
+            TimerJob::CyclesExchangeRate => "fetch_cycles_icp_exchange_rate",
+            TimerJob::IcpRedistribution => "redistribute_icps",
+            TimerJob::CleanExpiredPayments => "clean_expired_payments",
+            TimerJob::CyclesMonitor => "monitor_cycles",
+            TimerJob::RandomnessBeacon => "rotate_randomness_beacon",
+            TimerJob::LotteryDraw => "run_lottery_draw",
+            TimerJob::InvariantCheck => "check_invariants",
+            TimerJob::ReservesReport => "refresh_reserves_report",
+            TimerJob::TopUpSweep => "sweep_top_ups",
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimerJobState {
+    NotArmed,
+    Scheduled,
+    Running, // a run is in flight, however long it awaits
+    Stalled, // the last run was released without finishing, it trapped
+    Overdue, // didn't start within the grace period after its deadline
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TimerJobHealth {
+    pub job: TimerJob,
+    pub state: TimerJobState,
+    pub last_started_at: Option<Timestamp>,
+    pub last_finished_at: Option<Timestamp>,
+    pub last_error: Option<(Timestamp, String)>,
+    pub next_run_at: Option<Timestamp>,
+    pub runs: u64,
+    pub rearms: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+// `subsystem` is one of: pos, pledge, lottery, top_up, redistribution, swap, payments, vip, timers, invariants, reserves
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct LogEntry {
+    pub at: Timestamp,
+    pub level: LogLevel,
+    pub subsystem: String,
+    pub caller: Principal, // the canister itself for timer jobs
+    pub message: String,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DevFeeToken {
+    ICP,
+    SATSLINK,
+}
+
+impl DevFeeToken {
+    pub fn ledger_fee(&self) -> u64 {
+        match self {
+            DevFeeToken::ICP => ICP_FEE,
+            DevFeeToken::SATSLINK => SATSLINK_FEE,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum DevFeeWithdrawalStatus {
+    Announced,
+    Executing,
+    Executed { block_index: Nat },
+    Failed { reason: String },
+    Cancelled,
+}
+
+// A withdrawal from the dev fee subaccount, announced by the dev and executed by the owner after `unlocks_at`
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct DevFeeWithdrawal {
+    pub token: DevFeeToken,
+    pub qty: E8s,
+    pub to: Principal,
+    pub announced_by: Principal,
+    pub announced_at: Timestamp,
+    pub unlocks_at: Timestamp,
+    pub status: DevFeeWithdrawalStatus,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum DevFeeAuditEvent {
+    Announced { withdrawal_id: u64 },
+    Confirmed { withdrawal_id: u64 },
+    Executed { withdrawal_id: u64, block_index: Nat },
+    Failed { withdrawal_id: u64, reason: String },
+    Cancelled { withdrawal_id: u64 },
+    OwnerChanged { owner: Principal },
+    TimelockChangeAnnounced { timelock_ns: u64, effective_at: Timestamp },
+    OwnerChangeProposed { owner: Principal, effective_at: Timestamp },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct DevFeeAuditEntry {
+    pub event: DevFeeAuditEvent,
+    pub by: Principal,
+    pub at: Timestamp,
+}
+
+// Commit/reveal randomness: `commitment` is published before the outcome is used, `secret` and the
+// fresh `entropy` are published after, so anyone can recompute `output` and every index derived from it
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct BeaconRound {
+    pub commitment: Vec<u8>, // sha256(BEACON_DOMAIN, secret)
+    pub committed_at: Timestamp,
+    pub secret: Option<Vec<u8>>,
+    pub entropy: Option<Vec<u8>>,
+    pub output: Option<Vec<u8>>, // sha256(BEACON_DOMAIN, secret, entropy)
+    pub revealed_at: Option<Timestamp>,
+}
+
+impl BeaconRound {
+    pub fn commit(secret: Vec<u8>, now: Timestamp) -> Self {
+        Self {
+            commitment: Self::compute_commitment(&secret),
+            committed_at: now,
+            secret: Some(secret),
+            entropy: None,
+            output: None,
+            revealed_at: None,
+        }
+    }
+
+    pub fn compute_commitment(secret: &[u8]) -> Vec<u8> {
+        let mut hasher = sha2::Sha256::default();
+        hasher.update(BEACON_DOMAIN);
+        hasher.update(secret);
+
+        hasher.finalize().to_vec()
+    }
+
+    pub fn compute_output(secret: &[u8], entropy: &[u8]) -> Vec<u8> {
+        let mut hasher = sha2::Sha256::default();
+        hasher.update(BEACON_DOMAIN);
+        hasher.update(secret);
+        hasher.update(entropy);
+
+        hasher.finalize().to_vec()
+    }
+
+    pub fn is_revealed(&self) -> bool {
+        self.output.is_some()
+    }
+
+    // the secret stays private until the round is revealed
+    pub fn to_public(&self) -> Self {
+        let mut round = self.clone();
+        if !round.is_revealed() {
+            round.secret = None;
+        }
+
+        round
+    }
+
+    pub fn verify(&self) -> bool {
+        match (&self.secret, &self.entropy, &self.output) {
+            (Some(secret), Some(entropy), Some(output)) => {
+                Self::compute_commitment(secret) == self.commitment
+                    && Self::compute_output(secret, entropy) == *output
+            }
+            _ => false,
+        }
+    }
+}
+
+pub fn winning_idx_from_seed(seed: &[u8], total_options: u64) -> u64 {
+    let mut rng_buf = [0u8; 8];
+    rng_buf.copy_from_slice(&seed[0..8]);
+
+    u64::from_le_bytes(rng_buf) % total_options
+}
 
 #[derive(CandidType, Deserialize, Clone, Default, Debug)]
 pub struct SatslinkerStateInfo {
@@ -56,16 +913,52 @@ pub struct SatslinkerStateInfo {
 
     pub seed: Vec<u8>,
     pub satslink_enabled: Option<bool>,
-    pub tmp_can_vip_migrate: Option<BTreeSet<Principal>>,
-    pub tmp_can_pledge_migrate: Option<BTreeSet<Principal>>,
     pub icp_to_cycles_exchange_rate: Option<TCycles>,
+    pub next_pledge_lot_id: Option<u64>,
+    pub early_unpledge_max_penalty_bp: Option<u64>,
+    pub early_unpledge_treasury_share_bp: Option<u64>,
+    pub pending_compounded_pledge: Option<E8s>, // compounded rewards, not yet minted to the pledge subaccount
+    pub early_unpledge_treasury_carry: Option<E8s>, // penalties owed to the treasury, still in the pledge subaccount
+    pub next_top_up_id: Option<u64>,
+    pub cycles_threshold: Option<u128>,
+    pub cycles_top_up_e8s: Option<u64>,
+    pub low_cycles_alert: Option<bool>,
+    pub pending_cycles_top_up_id: Option<u64>,
+    pub next_lottery_bet_id: Option<u64>,
+    pub lottery_house_edge_bp: Option<u64>,
+    pub lottery_reserved: Option<E8s>, // max payouts of the bets that are not settled yet
+    pub current_lottery_draw_id: Option<u64>,
+    pub lottery_ticket_price_e8s: Option<u64>,
+    pub lottery_draw_pot: Option<E8s>,      // goes to the winner of the current draw
+    pub lottery_unpaid_prizes: Option<E8s>, // won in past draws, not transferred yet
+    pub next_beacon_round_id: Option<u64>,
+    pub seed_beacon_round: Option<u64>, // committed round that is mixed into `seed` on reveal
+    pub next_dev_fee_withdrawal_id: Option<u64>,
+    pub next_dev_fee_audit_id: Option<u64>,
+    pub dev_fee_timelock_ns: Option<u64>,
+    pub dev_fee_owner: Option<Principal>, // set by the install args, withdrawals can't be confirmed before
+    pub pending_dev_fee_timelock: Option<(u64, Timestamp)>, // (timelock_ns, effective_at)
+    pub pending_dev_fee_owner: Option<(Principal, Timestamp)>, // (owner, accepted not before)
+    pub redistribution_config: Option<RedistributionConfig>,
+    pub redistribution_carry: Option<RedistributionCarry>,
+    pub next_redistribution_id: Option<u64>,
+    pub minted_baseline: Option<E8s>,        // minted before the breakdown below was tracked
+    pub total_rewards_claimed: Option<E8s>,  // claimed, compounded and top up rewards
+    pub total_pool_transfers: Option<E8s>,   // minted to the lottery and dev fee subaccounts
+    pub auditor: Option<Principal>,          // may run the invariant checks besides the dev
+    pub invariant_auto_pause: Option<bool>,
+    pub reward_rounds_paused: Option<bool>,
+    pub ecdsa_public_key: Option<Vec<u8>>, // SEC1 compressed, fetched once from the management canister
+    pub next_attestation_nonce: Option<u64>,
+    pub pos_genesis_at: Option<Timestamp>, // round N is due at pos_genesis_at + N * pos_round_delay_ns
+    pub network_profile: Option<NetworkProfile>,
 }
 
 impl SatslinkerStateInfo {
     pub fn init(&mut self, seed: Vec<u8>) {
         self.seed = seed;
         self.current_token_reward = E8s::from(POS_ROUND_START_REWARD_E8S);
-        self.pos_round_delay_ns = POS_ROUND_DELAY_NS;
+        self.pos_round_delay_ns = self.get_network_profile().pos_round_delay_ns;
     }
 
     pub fn get_icp_to_cycles_exchange_rate(&self) -> TCycles {
@@ -99,7 +992,7 @@ impl SatslinkerStateInfo {
         self.update_seed();
 
         // each 5040 blocks we half the reward, until it reaches 0.0014 SATSLINK per block
-        if (self.current_pos_round % POS_ROUNDS_PER_HALVING) == 0 {
+        if self.current_pos_round.is_multiple_of(POS_ROUNDS_PER_HALVING) {
             let end_reward = E8s::from(POS_ROUND_END_REWARD_E8S);
 
             if self.current_token_reward > end_reward {
@@ -113,11 +1006,37 @@ impl SatslinkerStateInfo {
         }
     }
 
-    pub fn current_winning_idx(&self, total_options: u64) -> u64 {
-        let mut rng_buf = [0u8; 8];
-        rng_buf.copy_from_slice(&self.seed[0..8]);
+    // States from before the time-based schedule start it so that every processed round is on time
+    pub fn init_pos_genesis(&mut self, now: Timestamp) {
+        if self.pos_genesis_at.is_none() {
+            self.pos_genesis_at = Some(now.saturating_sub(self.current_pos_round * self.pos_round_delay_ns));
+        }
+    }
+
+    pub fn get_expected_pos_rounds(&self, now: Timestamp) -> u64 {
+        match self.pos_genesis_at {
+            Some(genesis_at) if self.pos_round_delay_ns > 0 => now.saturating_sub(genesis_at) / self.pos_round_delay_ns,
+            _ => self.current_pos_round,
+        }
+    }
+
+    pub fn get_missed_pos_rounds(&self, now: Timestamp) -> u64 {
+        self.get_expected_pos_rounds(now).saturating_sub(self.current_pos_round)
+    }
+
+    pub fn get_next_pos_round_at(&self) -> Option<Timestamp> {
+        self.pos_genesis_at
+            .map(|genesis_at| genesis_at + (self.current_pos_round + 1) * self.pos_round_delay_ns)
+    }
+
+    // drops the missed rounds, the schedule continues from now
+    pub fn skip_missed_pos_rounds(&mut self, now: Timestamp) {
+        self.pos_genesis_at = None;
+        self.init_pos_genesis(now);
+    }
 
-        u64::from_le_bytes(rng_buf) % total_options
+    pub fn current_winning_idx(&self, total_options: u64) -> u64 {
+        winning_idx_from_seed(&self.seed, total_options)
     }
 
     pub fn update_seed(&mut self) {
@@ -128,13 +1047,65 @@ impl SatslinkerStateInfo {
         self.seed = hasher.finalize().to_vec();
     }
 
+    // fresh entropy (raw_rand) makes the next winning index unpredictable from the stored seed
+    pub fn mix_seed(&mut self, entropy: &[u8]) {
+        let mut hasher = sha2::Sha256::default();
+        hasher.update(UPDATE_SEED_DOMAIN);
+        hasher.update(&self.seed);
+        hasher.update(entropy);
+
+        self.seed = hasher.finalize().to_vec();
+    }
+
     // pub fn note_pledged_satslink(&mut self, qty: E8s) {
     //     self.total_pledge_token_supply += qty;
     // }
 
-    // pub fn note_minted_reward(&mut self, qty: E8s) {
-    //     self.total_token_minted += qty;
-    // }
+    pub fn note_minted_reward(&mut self, qty: &E8s) {
+        self.total_token_minted += qty;
+        self.total_rewards_claimed = Some(self.get_total_rewards_claimed() + qty);
+    }
+
+    pub fn revert_minted_reward(&mut self, qty: &E8s) {
+        self.total_token_minted -= qty;
+
+        let mut claimed = self.get_total_rewards_claimed();
+        claimed -= qty;
+        self.total_rewards_claimed = Some(claimed);
+    }
+
+    pub fn note_minted_to_pool(&mut self, qty: &E8s) {
+        self.total_token_minted += qty;
+        self.total_pool_transfers = Some(self.get_total_pool_transfers() + qty);
+    }
+
+    pub fn get_total_rewards_claimed(&self) -> E8s {
+        self.total_rewards_claimed.clone().unwrap_or_default()
+    }
+
+    pub fn get_total_pool_transfers(&self) -> E8s {
+        self.total_pool_transfers.clone().unwrap_or_default()
+    }
+
+    // whatever was minted before the breakdown existed can't be attributed, so it becomes the baseline
+    pub fn init_minted_breakdown(&mut self) {
+        if self.minted_baseline.is_some() {
+            return;
+        }
+
+        let mut baseline = self.total_token_minted.clone();
+        baseline -= &self.get_total_rewards_claimed();
+        baseline -= &self.get_total_pool_transfers();
+        self.minted_baseline = Some(baseline);
+    }
+
+    pub fn get_expected_minted(&self) -> E8s {
+        let mut expected = self.minted_baseline.clone().unwrap_or_default();
+        expected += &self.get_total_rewards_claimed();
+        expected += &self.get_total_pool_transfers();
+
+        expected
+    }
 
     // pub fn note_satslink_token_lottery(&mut self, qty: E8s) {
     //     self.total_token_lottery += qty;
@@ -152,33 +1123,596 @@ impl SatslinkerStateInfo {
     //     return self.total_token_dev.clone();
     // }
 
-    pub fn can_vip_migrate(&self, caller: &Principal) -> bool {
-        self.tmp_can_vip_migrate
-            .as_ref()
-            .map(|it| it.contains(caller))
-            .unwrap_or_default()
+    pub fn generate_pledge_lot_id(&mut self) -> u64 {
+        let id = self.next_pledge_lot_id.unwrap_or_default();
+        self.next_pledge_lot_id = Some(id + 1);
+
+        id
+    }
+
+    // the reward is counted as minted right away, the ledger mint into custody happens once per round
+    pub fn note_compounded_pledge_reward(&mut self, qty: &E8s) {
+        self.total_pledge_token_supply += qty;
+        self.note_minted_reward(qty);
+        self.pending_compounded_pledge = Some(self.get_pending_compounded_pledge() + qty);
+    }
+
+    pub fn get_pending_compounded_pledge(&self) -> E8s {
+        self.pending_compounded_pledge.clone().unwrap_or_default()
+    }
+
+    pub fn note_compounded_pledge_minted(&mut self, qty: &E8s) {
+        let mut pending = self.get_pending_compounded_pledge();
+        pending -= qty;
+        self.pending_compounded_pledge = Some(pending);
+    }
+
+    pub fn get_early_unpledge_treasury_carry(&self) -> E8s {
+        self.early_unpledge_treasury_carry.clone().unwrap_or_default()
+    }
+
+    pub fn add_early_unpledge_treasury_carry(&mut self, qty: &E8s) {
+        self.early_unpledge_treasury_carry = Some(self.get_early_unpledge_treasury_carry() + qty);
+    }
+
+    // the whole carry is moved in one transfer, a failed one puts it back
+    pub fn take_early_unpledge_treasury_carry(&mut self) -> E8s {
+        self.early_unpledge_treasury_carry.take().unwrap_or_default()
+    }
+
+    pub fn get_early_unpledge_max_penalty_bp(&self) -> u64 {
+        self.early_unpledge_max_penalty_bp
+            .unwrap_or(DEFAULT_EARLY_UNPLEDGE_MAX_PENALTY_BP)
+    }
+
+    pub fn get_early_unpledge_treasury_share_bp(&self) -> u64 {
+        self.early_unpledge_treasury_share_bp
+            .unwrap_or(DEFAULT_EARLY_UNPLEDGE_TREASURY_SHARE_BP)
+    }
+
+    pub fn set_early_unpledge_config(&mut self, max_penalty_bp: u64, treasury_share_bp: u64) -> Result<(), String> {
+        if max_penalty_bp > BASIS_POINTS_BASE || treasury_share_bp > BASIS_POINTS_BASE {
+            return Err(format!("Basis points can't exceed {}", BASIS_POINTS_BASE));
+        }
+
+        self.early_unpledge_max_penalty_bp = Some(max_penalty_bp);
+        self.early_unpledge_treasury_share_bp = Some(treasury_share_bp);
+
+        Ok(())
+    }
+
+    pub fn generate_top_up_id(&mut self) -> u64 {
+        let id = self.next_top_up_id.unwrap_or_default();
+        self.next_top_up_id = Some(id + 1);
+
+        id
+    }
+
+    pub fn get_cycles_threshold(&self) -> u128 {
+        self.cycles_threshold.unwrap_or(DEFAULT_CYCLES_THRESHOLD)
+    }
+
+    pub fn get_cycles_top_up_e8s(&self) -> u64 {
+        self.cycles_top_up_e8s.unwrap_or(DEFAULT_CYCLES_TOP_UP_E8S)
+    }
+
+    pub fn set_cycles_monitor_config(&mut self, threshold: u128, top_up_e8s: u64) -> Result<(), String> {
+        if top_up_e8s < MIN_ICP_STAKE_E8S_U64 {
+            return Err(format!("The top up should be at least {} e8s", MIN_ICP_STAKE_E8S_U64));
+        }
+
+        self.cycles_threshold = Some(threshold);
+        self.cycles_top_up_e8s = Some(top_up_e8s);
+
+        Ok(())
+    }
+
+    pub fn is_low_cycles_alert(&self) -> bool {
+        self.low_cycles_alert.unwrap_or_default()
+    }
+
+    pub fn set_low_cycles_alert(&mut self, alert: bool) {
+        self.low_cycles_alert = Some(alert);
+    }
+
+    pub fn generate_lottery_bet_id(&mut self) -> u64 {
+        let id = self.next_lottery_bet_id.unwrap_or_default();
+        self.next_lottery_bet_id = Some(id + 1);
+
+        id
+    }
+
+    pub fn get_lottery_house_edge_bp(&self) -> u64 {
+        self.lottery_house_edge_bp.unwrap_or(DEFAULT_LOTTERY_HOUSE_EDGE_BP)
+    }
+
+    pub fn set_lottery_house_edge_bp(&mut self, house_edge_bp: u64) -> Result<(), String> {
+        if house_edge_bp > BASIS_POINTS_BASE {
+            return Err(format!("Basis points can't exceed {}", BASIS_POINTS_BASE));
+        }
+
+        self.lottery_house_edge_bp = Some(house_edge_bp);
+
+        Ok(())
+    }
+
+    pub fn get_lottery_reserved(&self) -> E8s {
+        self.lottery_reserved.clone().unwrap_or_default()
+    }
+
+    pub fn reserve_lottery_payout(&mut self, qty: &E8s) {
+        let mut reserved = self.get_lottery_reserved();
+        reserved += qty;
+        self.lottery_reserved = Some(reserved);
+    }
+
+    pub fn release_lottery_payout(&mut self, qty: &E8s) {
+        let mut reserved = self.get_lottery_reserved();
+        reserved -= qty;
+        self.lottery_reserved = Some(reserved);
+    }
+
+    pub fn get_network_profile(&self) -> NetworkProfile {
+        self.network_profile
+            .clone()
+            .unwrap_or_else(|| NetworkProfile::for_mode(&ENV_VARS.mode))
+    }
+
+    // `pos_round_delay_ns` follows the profile. On a new round delay the schedule is rebased so that the rounds
+    // already due stay due and the time into the current round is kept in proportion, the same delay leaves it as is.
+    pub fn set_network_profile(&mut self, profile: NetworkProfile, now: Timestamp) -> Result<(), String> {
+        profile.validate()?;
+
+        if self.pos_genesis_at.is_some() && profile.pos_round_delay_ns != self.pos_round_delay_ns {
+            let expected = self.get_expected_pos_rounds(now);
+            let into_round = match self.pos_genesis_at {
+                Some(genesis_at) if self.pos_round_delay_ns > 0 => {
+                    let elapsed = now.saturating_sub(genesis_at) % self.pos_round_delay_ns;
+                    (elapsed as u128 * profile.pos_round_delay_ns as u128 / self.pos_round_delay_ns as u128) as u64
+                }
+                _ => 0,
+            };
+
+            self.pos_genesis_at = Some(now.saturating_sub(expected * profile.pos_round_delay_ns + into_round));
+        }
+
+        self.pos_round_delay_ns = profile.pos_round_delay_ns;
+        self.network_profile = Some(profile);
+
+        Ok(())
+    }
+
+    pub fn get_redistribution_config(&self) -> RedistributionConfig {
+        self.redistribution_config.clone().unwrap_or_default()
+    }
+
+    pub fn set_redistribution_config(&mut self, config: RedistributionConfig) -> Result<(), String> {
+        config.validate()?;
+        self.redistribution_config = Some(config);
+
+        Ok(())
+    }
+
+    pub fn get_redistribution_carry(&self) -> RedistributionCarry {
+        self.redistribution_carry.clone().unwrap_or_default()
+    }
+
+    pub fn generate_redistribution_id(&mut self) -> u64 {
+        let id = self.next_redistribution_id.unwrap_or_default();
+        self.next_redistribution_id = Some(id + 1);
+
+        id
     }
 
-    pub fn note_vip_migrated(&mut self, caller: &Principal) {
-        if let Some(can_migrate) = &mut self.tmp_can_vip_migrate {
-            can_migrate.remove(caller);
+    pub fn generate_attestation_nonce(&mut self) -> u64 {
+        let nonce = self.next_attestation_nonce.unwrap_or_default();
+        self.next_attestation_nonce = Some(nonce + 1);
+
+        nonce
+    }
+
+    pub fn get_auditor(&self, dev: Principal) -> Principal {
+        self.auditor.unwrap_or(dev)
+    }
+
+    pub fn is_invariant_auto_pause(&self) -> bool {
+        self.invariant_auto_pause.unwrap_or_default()
+    }
+
+    pub fn are_reward_rounds_paused(&self) -> bool {
+        self.reward_rounds_paused.unwrap_or_default()
+    }
+
+    pub fn set_reward_rounds_paused(&mut self, paused: bool) {
+        self.reward_rounds_paused = Some(paused);
+    }
+
+    pub fn generate_dev_fee_withdrawal_id(&mut self) -> u64 {
+        let id = self.next_dev_fee_withdrawal_id.unwrap_or_default();
+        self.next_dev_fee_withdrawal_id = Some(id + 1);
+
+        id
+    }
+
+    pub fn generate_dev_fee_audit_id(&mut self) -> u64 {
+        let id = self.next_dev_fee_audit_id.unwrap_or_default();
+        self.next_dev_fee_audit_id = Some(id + 1);
+
+        id
+    }
+
+    // a pending change applies once its own timelock is over, values set before the minimum existed are raised to it
+    pub fn get_dev_fee_timelock_ns(&self, now: Timestamp) -> u64 {
+        let timelock_ns = match self.pending_dev_fee_timelock {
+            Some((timelock_ns, effective_at)) if now >= effective_at => timelock_ns,
+            _ => self.dev_fee_timelock_ns.unwrap_or(DEFAULT_DEV_FEE_TIMELOCK_NS),
+        };
+
+        timelock_ns.max(MIN_DEV_FEE_TIMELOCK_NS)
+    }
+
+    // A timelock change waits out the current timelock, so it can't be used to skip it.
+    // Returns the time the new value applies from.
+    pub fn announce_dev_fee_timelock(&mut self, timelock_ns: u64, now: Timestamp) -> Result<Timestamp, String> {
+        if timelock_ns < MIN_DEV_FEE_TIMELOCK_NS {
+            return Err(format!("The timelock should be at least {} ns", MIN_DEV_FEE_TIMELOCK_NS));
         }
+
+        let current_ns = self.get_dev_fee_timelock_ns(now);
+        let effective_at = now + current_ns;
+
+        self.dev_fee_timelock_ns = Some(current_ns);
+        self.pending_dev_fee_timelock = Some((timelock_ns, effective_at));
+
+        Ok(effective_at)
     }
 
-    pub fn can_pledge_migrate(&self, caller: &Principal) -> bool {
-        self.tmp_can_pledge_migrate
-            .as_ref()
-            .map(|it| it.contains(caller))
-            .unwrap_or_default()
+    pub fn get_dev_fee_owner(&self) -> Option<Principal> {
+        self.dev_fee_owner
     }
 
-    pub fn note_pledge_migrated(&mut self, caller: &Principal) {
-        if let Some(can_migrate) = &mut self.tmp_can_pledge_migrate {
-            can_migrate.remove(caller);
+    pub fn generate_beacon_round_id(&mut self) -> u64 {
+        let id = self.next_beacon_round_id.unwrap_or_default();
+        self.next_beacon_round_id = Some(id + 1);
+
+        id
+    }
+
+    pub fn get_lottery_ticket_price(&self) -> E8s {
+        E8s::from(self.lottery_ticket_price_e8s.unwrap_or(DEFAULT_LOTTERY_TICKET_PRICE_E8S))
+    }
+
+    pub fn set_lottery_ticket_price(&mut self, price_e8s: u64) -> Result<(), String> {
+        if price_e8s < MIN_STL_LOTTERY_E8S_U64 {
+            return Err(format!("The ticket price should be at least {} e8s", MIN_STL_LOTTERY_E8S_U64));
         }
+
+        self.lottery_ticket_price_e8s = Some(price_e8s);
+
+        Ok(())
+    }
+
+    pub fn get_lottery_draw_pot(&self) -> E8s {
+        self.lottery_draw_pot.clone().unwrap_or_default()
+    }
+
+    pub fn note_lottery_draw_funded(&mut self, qty: &E8s) {
+        let mut pot = self.get_lottery_draw_pot();
+        pot += qty;
+        self.lottery_draw_pot = Some(pot);
+    }
+
+    pub fn get_lottery_unpaid_prizes(&self) -> E8s {
+        self.lottery_unpaid_prizes.clone().unwrap_or_default()
+    }
+
+    // the draw pot becomes the prize of the winner
+    pub fn take_lottery_draw_pot(&mut self) -> E8s {
+        let pot = self.get_lottery_draw_pot();
+
+        let mut unpaid = self.get_lottery_unpaid_prizes();
+        unpaid += &pot;
+        self.lottery_unpaid_prizes = Some(unpaid);
+        self.lottery_draw_pot = Some(E8s::zero());
+
+        pot
+    }
+
+    pub fn note_lottery_prize_paid(&mut self, prize: &E8s) {
+        let mut unpaid = self.get_lottery_unpaid_prizes();
+        unpaid -= prize;
+        self.lottery_unpaid_prizes = Some(unpaid);
+    }
+
+    // SATSLINK in the lottery subaccount that belongs to draws, not to the bets bankroll
+    pub fn get_lottery_draw_funds(&self) -> E8s {
+        let mut funds = self.get_lottery_draw_pot();
+        funds += &self.get_lottery_unpaid_prizes();
+
+        funds
     }
 
     pub fn get_current_fee() -> TCycles {
         TCycles::from(TCYCLE_POS_ROUND_BASE_FEE)
     }
+
+    // the smallest pledge share an account may hold, same as the round fee expressed in SATSLINK
+    pub fn get_min_pledge_share() -> E8s {
+        Self::get_current_fee()
+            .to_dynamic()
+            .to_decimals(8)
+            .to_const::<8>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PERIOD_NS: u64 = 1_000;
+
+    fn lot(qty_e8s: u64, lock_period: PledgeLockPeriod) -> PledgeLot {
+        PledgeLot {
+            qty: E8s::from(qty_e8s),
+            started_at: 10_000,
+            lock_period,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn early_unpledge_penalty_decays_over_the_lock() {
+        let lot = lot(100_0000_0000, PledgeLockPeriod::ThreeMonths);
+
+        assert_eq!(lot.early_unpledge_penalty_bp(10_000, PERIOD_NS, 2_000), 2_000);
+        assert_eq!(lot.early_unpledge_penalty_bp(11_500, PERIOD_NS, 2_000), 1_000);
+        assert_eq!(lot.early_unpledge_penalty_bp(12_999, PERIOD_NS, 2_000), 0);
+        assert_eq!(lot.early_unpledge_penalty_bp(13_000, PERIOD_NS, 2_000), 0);
+    }
+
+    #[test]
+    fn early_unpledge_quote_splits_the_penalty() {
+        let quote = EarlyUnpledgeQuote::new(1, E8s::from(100_0000_0000_u64), 1_000, 5_000, true);
+
+        assert_eq!(quote.penalty, E8s::from(10_0000_0000_u64));
+        assert_eq!(quote.to_pledgers, E8s::from(5_0000_0000_u64));
+        assert_eq!(quote.to_treasury, E8s::from(5_0000_0000_u64));
+        assert_eq!(quote.payout, E8s::from(90_0000_0000_u64));
+    }
+
+    #[test]
+    fn early_unpledge_quote_sends_everything_to_the_treasury_when_alone() {
+        let quote = EarlyUnpledgeQuote::new(1, E8s::from(100_0000_0000_u64), 1_000, 5_000, false);
+
+        assert_eq!(quote.to_pledgers, E8s::zero());
+        assert_eq!(quote.to_treasury, quote.penalty);
+        assert_eq!(quote.payout, E8s::from(90_0000_0000_u64));
+    }
+
+    fn pool(reserve_icp_e8s: u64, reserve_satslink_e8s: u64) -> SwapPoolInfo {
+        SwapPoolInfo {
+            reserve_icp: E8s::from(reserve_icp_e8s),
+            reserve_satslink: E8s::from(reserve_satslink_e8s),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn swap_quote_takes_the_fee_from_the_input() {
+        let pool = pool(1000_0000_0000_u64, 2000_0000_0000_u64);
+        let quote = pool.quote(SwapDirection::IcpToSatslink, &E8s::from(10_0000_0000_u64)).unwrap();
+
+        assert_eq!(quote.fee, E8s::from(300_0000_u64));
+        assert_eq!(quote.amount_out, E8s::from(19_7431_6068_u64));
+    }
+
+    #[test]
+    fn swap_quote_never_lowers_the_pool_invariant() {
+        let pool = pool(1000_0000_0000_u64, 2000_0000_0000_u64);
+        let amount_in = E8s::from(50_0000_0000_u64);
+        let quote = pool.quote(SwapDirection::SatslinkToIcp, &amount_in).unwrap();
+
+        let k_before = pool.reserve_icp.val.clone() * pool.reserve_satslink.val.clone();
+        let k_after = (pool.reserve_icp.val.clone() - quote.amount_out.val.clone())
+            * (pool.reserve_satslink.val.clone() + amount_in.val.clone());
+        assert!(k_after >= k_before);
+        assert!(quote.amount_out < E8s::from(25_0000_0000_u64));
+    }
+
+    #[test]
+    fn swap_quote_rejects_an_empty_pool_or_input() {
+        assert!(pool(0, 2000_0000_0000_u64).quote(SwapDirection::IcpToSatslink, &E8s::from(1_0000_0000_u64)).is_err());
+        assert!(pool(1000_0000_0000_u64, 2000_0000_0000_u64).quote(SwapDirection::IcpToSatslink, &E8s::zero()).is_err());
+    }
+
+    #[test]
+    fn lottery_max_payout_is_double_minus_the_house_edge() {
+        assert_eq!(LotteryBet::max_payout(&E8s::from(10_0000_0000_u64), 500), E8s::from(19_0000_0000_u64));
+        assert_eq!(LotteryBet::max_payout(&E8s::from(10_0000_0000_u64), 0), E8s::from(20_0000_0000_u64));
+    }
+
+    #[test]
+    fn lottery_half_of_the_rolls_win() {
+        assert!(LotteryBet::is_win(0));
+        assert!(LotteryBet::is_win(LOTTERY_WIN_CHANCE_BP - 1));
+        assert!(!LotteryBet::is_win(LOTTERY_WIN_CHANCE_BP));
+        assert!(!LotteryBet::is_win(BASIS_POINTS_BASE - 1));
+    }
+
+    fn revealed_round(secret: &[u8], entropy: &[u8]) -> BeaconRound {
+        let mut round = BeaconRound::commit(secret.to_vec(), 1);
+        round.entropy = Some(entropy.to_vec());
+        round.output = Some(BeaconRound::compute_output(secret, entropy));
+        round.revealed_at = Some(2);
+
+        round
+    }
+
+    #[test]
+    fn beacon_round_verifies_once_revealed() {
+        assert!(revealed_round(b"secret", b"entropy").verify());
+        assert!(!BeaconRound::commit(b"secret".to_vec(), 1).verify());
+    }
+
+    #[test]
+    fn beacon_round_rejects_a_swapped_secret_or_output() {
+        let mut round = revealed_round(b"secret", b"entropy");
+        round.secret = Some(b"other".to_vec());
+        assert!(!round.verify());
+
+        let mut round = revealed_round(b"secret", b"entropy");
+        round.output = Some(BeaconRound::compute_output(b"secret", b"other"));
+        assert!(!round.verify());
+    }
+
+    #[test]
+    fn redistribution_split_follows_the_shares() {
+        let split = RedistributionConfig::default().split(10_0000_0000);
+
+        assert_eq!(split, [
+            (RedistributionTarget::SwapPool, 6_0000_0000),
+            (RedistributionTarget::Lottery, 1_0000_0000),
+            (RedistributionTarget::Dev, 3_0000_0000),
+        ]);
+    }
+
+    #[test]
+    fn redistribution_split_leaves_nothing_unassigned() {
+        let config = RedistributionConfig {
+            swappool_share_e8s: 3333_3333,
+            lottery_share_e8s: 3333_3333,
+            dev_share_e8s: 3333_3334,
+            min_balance_e8s: 0,
+        };
+        assert!(config.validate().is_ok());
+
+        let split = config.split(1_0000_0001);
+        assert_eq!(split.iter().map(|(_, qty)| qty).sum::<u64>(), 1_0000_0001);
+        assert_eq!(split[2], (RedistributionTarget::Dev, 3333_3335));
+    }
+
+    #[test]
+    fn redistribution_config_rejects_shares_not_adding_up() {
+        let config = RedistributionConfig {
+            dev_share_e8s: 2000_0000,
+            ..Default::default()
+        };
+
+        assert!(config.validate().is_err());
+    }
+
+    fn info_at_round(current_pos_round: u64, pos_round_delay_ns: u64, pos_genesis_at: Option<Timestamp>) -> SatslinkerStateInfo {
+        SatslinkerStateInfo {
+            current_pos_round,
+            pos_round_delay_ns,
+            pos_genesis_at,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn expected_pos_rounds_follow_the_time_since_genesis() {
+        let info = info_at_round(3, 100, Some(1_000));
+
+        assert_eq!(info.get_expected_pos_rounds(1_000), 0);
+        assert_eq!(info.get_expected_pos_rounds(1_599), 5);
+        assert_eq!(info.get_missed_pos_rounds(1_599), 2);
+        assert_eq!(info.get_missed_pos_rounds(1_200), 0);
+        assert_eq!(info.get_next_pos_round_at(), Some(1_400));
+    }
+
+    #[test]
+    fn expected_pos_rounds_without_genesis_are_the_current_ones() {
+        let mut info = info_at_round(3, 100, None);
+        assert_eq!(info.get_expected_pos_rounds(10_000), 3);
+        assert_eq!(info.get_next_pos_round_at(), None);
+
+        // an older state starts the schedule so that its rounds are on time
+        info.init_pos_genesis(10_000);
+        assert_eq!(info.get_missed_pos_rounds(10_000), 0);
+        assert_eq!(info.get_next_pos_round_at(), Some(10_100));
+    }
+
+    #[test]
+    fn network_profiles_are_valid() {
+        assert!(NetworkProfile::mainnet().validate().is_ok());
+        assert!(NetworkProfile::local().validate().is_ok());
+    }
+
+    #[test]
+    fn network_profile_rejects_unusable_timings() {
+        let too_fast = NetworkProfile {
+            beacon_interval_ns: MIN_TIMER_INTERVAL_NS - 1,
+            ..NetworkProfile::local()
+        };
+        let no_vip_unit = NetworkProfile {
+            vip_unit_ns: 0,
+            ..NetworkProfile::local()
+        };
+        let short_pledge = NetworkProfile {
+            pledge_period_ns: NetworkProfile::local().pos_round_delay_ns - 1,
+            ..NetworkProfile::local()
+        };
+
+        assert!(too_fast.validate().is_err());
+        assert!(no_vip_unit.validate().is_err());
+        assert!(short_pledge.validate().is_err());
+    }
+
+    #[test]
+    fn set_network_profile_keeps_the_due_rounds_due() {
+        let profile = NetworkProfile::local();
+        let delay_ns = profile.pos_round_delay_ns;
+        let now = 1_000 * delay_ns;
+        let mut info = info_at_round(990, delay_ns, Some(0));
+
+        let faster = NetworkProfile {
+            pos_round_delay_ns: delay_ns / 2,
+            ..profile
+        };
+        info.set_network_profile(faster.clone(), now).unwrap();
+
+        assert_eq!(info.pos_round_delay_ns, faster.pos_round_delay_ns);
+        assert_eq!(info.get_expected_pos_rounds(now), 1_000);
+        assert_eq!(info.get_missed_pos_rounds(now), 10);
+        assert_eq!(info.get_expected_pos_rounds(now + faster.pos_round_delay_ns), 1_001);
+        assert_eq!(info.get_network_profile(), faster);
+    }
+
+    #[test]
+    fn set_network_profile_keeps_the_partial_round() {
+        let profile = NetworkProfile::local();
+        let delay_ns = profile.pos_round_delay_ns;
+        let genesis_at = 1_000 * delay_ns;
+        let now = genesis_at + 10 * delay_ns + delay_ns / 2;
+        let mut info = info_at_round(10, delay_ns, Some(genesis_at));
+
+        // the same delay, e.g. the stored profile written again, leaves the schedule as it was
+        info.set_network_profile(profile.clone(), now).unwrap();
+        assert_eq!(info.pos_genesis_at, Some(genesis_at));
+        assert_eq!(info.get_next_pos_round_at(), Some(genesis_at + 11 * delay_ns));
+
+        // half way into the round stays half way into it
+        let slower = NetworkProfile {
+            pos_round_delay_ns: delay_ns * 2,
+            ..profile
+        };
+        info.set_network_profile(slower, now).unwrap();
+        assert_eq!(info.get_missed_pos_rounds(now), 0);
+        assert_eq!(info.get_next_pos_round_at(), Some(now + delay_ns));
+    }
+
+    #[test]
+    fn set_network_profile_rejects_an_invalid_profile() {
+        let mut info = info_at_round(10, 100, Some(0));
+        let invalid = NetworkProfile {
+            vip_unit_ns: 0,
+            ..NetworkProfile::local()
+        };
+
+        assert!(info.set_network_profile(invalid, 5_000).is_err());
+        assert_eq!(info.pos_round_delay_ns, 100);
+        assert_eq!(info.pos_genesis_at, Some(0));
+        assert!(info.network_profile.is_none());
+    }
 }